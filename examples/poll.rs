@@ -1,4 +1,4 @@
-use allegedly::{ExportPage, poll_upstream};
+use allegedly::{ClientConf, ExportPage, OpFilter, PageParsing, poll_upstream};
 
 #[tokio::main]
 async fn main() {
@@ -11,27 +11,47 @@ async fn main() {
     // self-rate-limit (plc.directory's limit interval is 600ms)
     let throttle = std::time::Duration::from_millis(300);
 
+    // let upstream operators know who's polling
+    let client = ClientConf {
+        contact: Some("you@example.com".to_string()),
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+
+    // in this example we're alerting when changes are found for one specific
+    // identity. filters can also match op types, handles, PDS endpoints, etc.
+    let filter = OpFilter {
+        dids: Some(["did:plc:hdhoaan3xa3jiuq4fg4mefid".to_string()].into()),
+        ..Default::default()
+    };
+
     // pages are sent out of the poller via a tokio mpsc channel
     let (tx, mut rx) = tokio::sync::mpsc::channel(1);
 
     // spawn a tokio task to run the poller
-    tokio::task::spawn(poll_upstream(after, upstream, throttle, tx));
+    tokio::task::spawn(poll_upstream(
+        client,
+        after,
+        upstream,
+        throttle,
+        PageParsing::Inline,
+        tx,
+    ));
 
     // receive pages of plc ops from the poller
-    while let Some(ExportPage { ops }) = rx.recv().await {
-        println!("received {} plc ops", ops.len());
+    while let Some(mut page) = rx.recv().await {
+        println!("received {} plc ops", page.ops.len());
 
+        filter.apply(&mut page);
+        let ExportPage { ops } = page;
         for op in ops {
-            // in this example we're alerting when changes are found for one
-            // specific identity
-            if op.did == "did:plc:hdhoaan3xa3jiuq4fg4mefid" {
-                println!(
-                    "Update found for {}! cid={}\n -> operation: {}",
-                    op.did,
-                    op.cid,
-                    op.operation.get()
-                );
-            }
+            println!(
+                "Update found for {}! cid={}\n -> operation: {}",
+                op.did,
+                op.cid,
+                op.operation.get()
+            );
         }
     }
 }
@@ -1,4 +1,6 @@
-use crate::{BundleSource, Dt, ExportPage, Week, week_to_pages};
+use crate::{BundleError, BundleSource, Dt, ExportPage, Week, channel_stream, week_to_pages};
+use futures::{Stream, StreamExt};
+use std::ops::Bound;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::{
@@ -8,21 +10,41 @@ use tokio::{
 
 const FIRST_WEEK: Week = Week::from_n(1668643200);
 
+/// Fetch weekly bundles with several workers, as a stream of pages
+///
+/// Each worker is its own task so that decompressing and parsing happens in
+/// parallel. Pages arrive in no particular order. Ends with an error if any
+/// worker failed.
+pub fn backfill_stream(
+    source: impl BundleSource + Send + 'static,
+    source_workers: usize,
+    until: Option<Dt>,
+) -> impl Stream<Item = Result<ExportPage, BundleError>> {
+    let (tx, rx) = mpsc::channel(source_workers.max(1) * 2);
+    let workers = tokio::task::spawn(backfill(source, tx, source_workers, until));
+    let outcome = futures::stream::once(async move {
+        match workers.await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(e) => Some(Err(e.into())),
+        }
+    })
+    .filter_map(futures::future::ready);
+    channel_stream(rx).chain(outcome)
+}
+
 pub async fn backfill(
     source: impl BundleSource + Send + 'static,
     dest: mpsc::Sender<ExportPage>,
     source_workers: usize,
     until: Option<Dt>,
-) -> anyhow::Result<&'static str> {
+) -> Result<&'static str, BundleError> {
     // queue up the week bundles that should be available
-    let weeks = Arc::new(Mutex::new(
-        until
-            .map(|u| Week::range(FIRST_WEEK..u.into()))
-            .unwrap_or(Week::range(FIRST_WEEK..)),
-    ));
+    let end = until.map_or(Bound::Unbounded, |u| Bound::Excluded(u.into()));
+    let weeks = Arc::new(Mutex::new(Week::range(FIRST_WEEK, end)));
     weeks.lock().await.reverse();
 
-    let mut workers: JoinSet<anyhow::Result<()>> = JoinSet::new();
+    let mut workers: JoinSet<Result<(), BundleError>> = JoinSet::new();
 
     let t_step = Instant::now();
     log::info!(
@@ -1,8 +1,23 @@
-use allegedly::{Dt, bin::GlobalArgs, bin_init, pages_to_stdout, pages_to_weeks, poll_upstream};
-use clap::{CommandFactory, Parser, Subcommand};
-use std::{path::PathBuf, time::Duration, time::Instant};
+use allegedly::{
+    CatchUpTarget, ClientIp, DidHistory, DiffKind, Dt, Fixture, FixtureFaults, ForwardedHeader,
+    NoteSigner, NoteVerifier, OpDiff, OpSource, RateLimitConf, ScatterConf, ScatterRule, Tlog,
+    WebhookConf, WitnessConf,
+    bin::{FilterArgs, GlobalArgs, OutputArgs, check_config, command_with_config},
+    bin_init, caught_up, collect_did_ops, diff_pages, filter_pages, load_witnessed,
+    pages_to_output, pages_to_tlog, pages_to_webhooks, pages_to_weeks, parse_ip_net, parse_quota,
+    poll_upstream, read_cursor, serve_fixture, serve_scatter, serve_witness, source_to_pages,
+    take_ops, witness,
+};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use futures::TryFutureExt;
+use governor::Quota;
+use ipnet::IpNet;
+use reqwest::Url;
+use std::sync::{Arc, RwLock};
+use std::{net::SocketAddr, path::PathBuf, time::Duration, time::Instant};
 use tokio::fs::create_dir_all;
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 
 mod backfill;
 mod mirror;
@@ -59,17 +74,259 @@ enum Commands {
         /// Begin tailing from a specific timestamp for replay or wait-until
         #[arg(short, long)]
         after: Option<Dt>,
+        /// Stop before the first op at or after this timestamp
+        #[arg(long)]
+        until: Option<Dt>,
+        /// Stop after this many ops
+        #[arg(long)]
+        count: Option<usize>,
+        /// Stop once there's nothing newer upstream
+        #[arg(long, action)]
+        exit_when_caught_up: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Poll an upstream PLC server and POST matching ops to webhooks
+    ///
+    /// Each request is a json body like `{"cursor": ..., "ops": [...]}`. A url
+    /// that keeps failing is dropped while the others carry on, and the saved
+    /// cursor stays where it stopped. Exits once every url has failed.
+    Webhook {
+        /// Where to POST batches of ops (repeatable)
+        #[arg(
+            long = "url",
+            required = true,
+            value_delimiter = ',',
+            env = "ALLEGEDLY_WEBHOOK_URL"
+        )]
+        urls: Vec<Url>,
+        /// Sign each body with HMAC-SHA256, in an `x-allegedly-signature` header
+        #[arg(long, env = "ALLEGEDLY_WEBHOOK_SECRET")]
+        secret: Option<String>,
+        /// Send a batch once this many ops match
+        #[arg(long, default_value = "100")]
+        batch_size: usize,
+        /// ...or once the oldest op in it has waited this long
+        #[arg(long, default_value = "5000")]
+        batch_wait_ms: u64,
+        /// Keep the delivery cursor in this file, and resume from it
+        #[arg(long, env = "ALLEGEDLY_WEBHOOK_CURSOR")]
+        cursor_file: Option<PathBuf>,
+        /// Where to start without a saved cursor (default: now)
+        #[arg(short, long)]
+        after: Option<Dt>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Compare two PLC logs over a time range
+    ///
+    /// Sources can be an upstream export url (ending in `/export`), a postgres
+    /// url, an http prefix for weekly bundles, or a local bundle folder.
+    ///
+    /// Prints one JSON object per difference: `missing_left`, `missing_right`,
+    /// `nullified_differs`, or `operation_differs`.
+    Diff {
+        /// The first log to compare
+        #[arg(long)]
+        left: String,
+        /// The second log to compare
+        #[arg(long)]
+        right: String,
+        /// Compare ops after this time
+        #[arg(short, long)]
+        after: Dt,
+        /// Compare ops before this time [default: now]
+        #[arg(long)]
+        until: Option<Dt>,
+        /// Path to a tls cert for postgres sources, if needed
+        #[arg(long)]
+        pg_cert: Option<PathBuf>,
+    },
+    /// Resolve a DID from local PLC data, optionally at a point in time
+    ///
+    /// Prints the DID document, PLC data, or audit log as JSON, like the
+    /// reference server's `/:did`, `/:did/data` and `/:did/log/audit`.
+    Resolve {
+        /// The DID to resolve
+        did: String,
+        /// Where to find ops: a bundle folder, jsonl file, postgres url, or
+        /// bundle http prefix
+        #[arg(long)]
+        source: String,
+        /// Resolve the DID as it was at this time
+        #[arg(long)]
+        at: Option<Dt>,
+        /// Fetch newer ops than the source has from upstream's /export
+        #[arg(long, action)]
+        catch_up: bool,
+        /// What to print
+        #[arg(long, value_enum)]
+        #[clap(default_value = "document")]
+        show: ResolveOutput,
+        /// Path to a tls cert for a postgres source, if needed
+        #[arg(long)]
+        pg_cert: Option<PathBuf>,
+    },
+    /// Forward PLC op submissions to several upstreams at once
+    ///
+    /// Responds with each upstream's result, and whether enough of them
+    /// accepted the op for the `--rule`.
+    Scatter {
+        /// An upstream PLC directory to forward to (comma-separated or repeated)
+        #[arg(
+            long,
+            required = true,
+            value_delimiter = ',',
+            env = "ALLEGEDLY_SCATTER_TO"
+        )]
+        to: Vec<Url>,
+        /// When a write counts as accepted
+        #[arg(long, value_enum, env = "ALLEGEDLY_SCATTER_RULE")]
+        #[clap(default_value = "first")]
+        rule: ScatterRule,
+        /// Timeout for each upstream's response
+        #[arg(long, env = "ALLEGEDLY_SCATTER_TIMEOUT_MS")]
+        #[clap(default_value = "15000")]
+        timeout_ms: u64,
+        /// Scatter server listen address
+        #[arg(short, long, env = "ALLEGEDLY_BIND")]
+        #[clap(default_value = "127.0.0.1:8002")]
+        bind: SocketAddr,
+        /// Trust client-ip forwarding headers from these proxies
+        #[arg(long, value_delimiter = ',', value_parser = parse_ip_net, env = "ALLEGEDLY_TRUSTED_PROXY")]
+        trusted_proxy: Vec<IpNet>,
+        /// Which header trusted proxies use to forward the client ip
+        #[arg(long, value_enum, env = "ALLEGEDLY_TRUSTED_PROXY_HEADER")]
+        #[clap(default_value = "x-forwarded-for")]
+        trusted_proxy_header: ForwardedHeader,
+        /// Per-ip quota for op submissions
+        #[arg(long, value_parser = parse_quota, env = "ALLEGEDLY_WRITE_IP_QUOTA")]
+        #[clap(default_value = "10/hour")]
+        write_ip_quota: Quota,
+        /// Per-did quota for op submissions
+        #[arg(long, value_parser = parse_quota, env = "ALLEGEDLY_WRITE_DID_QUOTA")]
+        #[clap(default_value = "4/hour")]
+        write_did_quota: Quota,
+    },
+    /// Follow a mirror's tlog, verifying and cosigning its checkpoints
+    ///
+    /// The witness builds its own view of the log from upstream's ops. Each
+    /// new checkpoint must be signed by the log's key, match that view, and be
+    /// consistent with the last one this witness cosigned. The latest
+    /// cosigned checkpoint is served at `/checkpoint`.
+    Witness {
+        /// Base url of the mirror serving the tlog
+        #[arg(long, env = "ALLEGEDLY_WITNESS_LOG")]
+        log: Url,
+        /// The log's checkpoint verifier key, like `<name>+<id>+<key>`
+        #[arg(long, env = "ALLEGEDLY_WITNESS_LOG_KEY")]
+        log_key: String,
+        /// File with this witness's private note key (see `allegedly keygen`)
+        #[arg(long, env = "ALLEGEDLY_WITNESS_SIGNING_KEY")]
+        signing_key: PathBuf,
+        /// Keep the latest cosigned checkpoint in this file across restarts
+        #[arg(long, env = "ALLEGEDLY_WITNESS_STATE")]
+        state: Option<PathBuf>,
+        /// Keep this witness's own view of the log in this file
+        ///
+        /// Without it, the view is rebuilt from the start of PLC history on
+        /// every restart, and nothing is cosigned until it catches up.
+        #[arg(long, env = "ALLEGEDLY_WITNESS_TLOG_FILE")]
+        tlog_file: Option<PathBuf>,
+        /// How often to check the log for a new checkpoint
+        #[arg(long, env = "ALLEGEDLY_WITNESS_INTERVAL_MS")]
+        #[clap(default_value = "10000")]
+        interval_ms: u64,
+        /// Witness server listen address
+        #[arg(short, long, env = "ALLEGEDLY_BIND")]
+        #[clap(default_value = "127.0.0.1:8001")]
+        bind: SocketAddr,
+    },
+    /// Serve ops from local data as a fake PLC server, for offline testing
+    ///
+    /// Serves `/export`, `/:did` and `/_health` like the reference server, and
+    /// can misbehave in the ways real upstreams sometimes do.
+    ServeFixture {
+        /// Where to find ops: a bundle folder or jsonl file (or any diff source)
+        #[arg(long)]
+        source: String,
+        /// Fixture server listen address
+        #[arg(short, long, env = "ALLEGEDLY_BIND")]
+        #[clap(default_value = "127.0.0.1:8003")]
+        bind: SocketAddr,
+        /// Repeat ops at the `after` timestamp at the start of each page
+        #[arg(long, action)]
+        duplicate_boundaries: bool,
+        /// Answer every nth request with a 429
+        #[arg(long)]
+        rate_limit_every: Option<u32>,
+        /// `Retry-After` seconds for injected 429s
+        #[arg(long)]
+        #[clap(default_value = "1")]
+        retry_after_secs: u64,
+        /// Wait this long before every response
+        #[arg(long)]
+        #[clap(default_value = "0")]
+        delay_ms: u64,
+        /// Cut every nth /export response off partway through its last op
+        #[arg(long)]
+        truncate_every: Option<u32>,
+        /// Path to a tls cert for a postgres source, if needed
+        #[arg(long)]
+        pg_cert: Option<PathBuf>,
+    },
+    /// Generate a note signing key for tlog checkpoints or witnessing
+    ///
+    /// Writes the private key to a file, and prints the public verifier key.
+    Keygen {
+        /// Key name: for a log, its origin. Conventionally a hostname.
+        name: String,
+        /// Where to write the private key
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Work with TOML config files (see `--config`)
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+enum ResolveOutput {
+    /// the DID document
+    Document,
+    /// the DID's current PLC data
+    Data,
+    /// every op, including nullified ones
+    Audit,
+}
+
+/// the earliest PLC export, as bundled
+const PLC_START: &str = "2022-11-17T00:00:00Z";
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Check that a config file's options exist and their values are valid
+    Check {
+        /// The config file to check
+        path: PathBuf,
     },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Cli::parse();
-    let matches = Cli::command().get_matches();
+    let matches = command_with_config(Cli::command(), None)?.get_matches();
+    let args = Cli::from_arg_matches(&matches)?;
     let name = matches.subcommand().map(|(name, _)| name).unwrap_or("???");
     bin_init(name);
 
     let globals = args.globals.clone();
+    let client = globals.http_client()?;
+    let parsing = globals.page_parsing();
+    globals.init_dead_letters().await?;
 
     let t0 = Instant::now();
     match args.command {
@@ -84,7 +341,7 @@ async fn main() -> anyhow::Result<()> {
             let throttle = Duration::from_millis(globals.upstream_throttle_ms);
             let (tx, rx) = mpsc::channel(32); // read ahead if gzip stalls for some reason
             tokio::task::spawn(async move {
-                poll_upstream(Some(after), url, throttle, tx)
+                poll_upstream(client, Some(after), url, throttle, parsing, tx)
                     .await
                     .expect("to poll upstream")
             });
@@ -98,20 +355,276 @@ async fn main() -> anyhow::Result<()> {
         }
         Commands::Mirror { args } => mirror::run(globals, args, true).await?,
         Commands::Wrap { args } => mirror::run(globals, args, false).await?,
-        Commands::Tail { after } => {
+        Commands::Tail {
+            after,
+            until,
+            count,
+            exit_when_caught_up,
+            filter,
+            output,
+        } => {
+            let filter = filter.op_filter()?;
+            let out = output.stdout_writer()?;
             let mut url = globals.upstream;
             url.set_path("/export");
             let start_at = after.or_else(|| Some(chrono::Utc::now()));
             let throttle = Duration::from_millis(globals.upstream_throttle_ms);
-            let (tx, rx) = mpsc::channel(1);
-            tokio::task::spawn(async move {
-                poll_upstream(start_at, url, throttle, tx)
-                    .await
-                    .expect("to poll upstream")
-            });
-            pages_to_stdout(rx, None)
-                .await
-                .expect("to write pages to stdout");
+            let mut tasks = JoinSet::<anyhow::Result<&'static str>>::new();
+            let (poll_tx, poll_rx) = mpsc::channel(1);
+            tasks.spawn(
+                poll_upstream(
+                    client.clone(),
+                    start_at,
+                    url.clone(),
+                    throttle,
+                    parsing,
+                    poll_tx,
+                )
+                .map_err(Into::into),
+            );
+            // with no bounds set, this only trims any ops at `after` itself
+            let target = CatchUpTarget {
+                after: start_at,
+                until,
+                stop_at_head: exit_when_caught_up,
+                ..CatchUpTarget::new(client, url)
+            };
+            let (tx, mut rx) = mpsc::channel(1);
+            tasks.spawn(caught_up(poll_rx, tx, target, None).map_err(Into::into));
+            // filter first, so --count counts matching ops
+            if let Some(filter) = filter {
+                let (tx, filtered_rx) = mpsc::channel(1);
+                tasks.spawn(filter_pages(rx, tx, filter).map_err(Into::into));
+                rx = filtered_rx;
+            }
+            if let Some(n) = count {
+                let (tx, counted_rx) = mpsc::channel(1);
+                tasks.spawn(take_ops(rx, tx, n).map_err(Into::into));
+                rx = counted_rx;
+            }
+            pages_to_output(rx, None, None, out).await?;
+            // the sink only ends once a stage before it stops: was it an error?
+            while let Some(res) = tasks.join_next().await {
+                res??;
+            }
+        }
+        Commands::Webhook {
+            urls,
+            secret,
+            batch_size,
+            batch_wait_ms,
+            cursor_file,
+            after,
+            filter,
+        } => {
+            let filter = filter.op_filter()?;
+            let saved = cursor_file
+                .as_deref()
+                .map(read_cursor)
+                .transpose()?
+                .flatten();
+            if let Some(at) = saved {
+                log::info!("resuming webhook deliveries after {at}");
+            }
+            let start_at = saved.or(after).or_else(|| Some(chrono::Utc::now()));
+            let conf = WebhookConf {
+                urls,
+                secret,
+                batch_size,
+                batch_wait: Duration::from_millis(batch_wait_ms),
+                cursor: cursor_file,
+            };
+            let mut url = globals.upstream;
+            url.set_path("/export");
+            let throttle = Duration::from_millis(globals.upstream_throttle_ms);
+            let mut tasks = JoinSet::<anyhow::Result<&'static str>>::new();
+            let (tx, rx) = mpsc::channel(32);
+            tasks.spawn(
+                poll_upstream(client.clone(), start_at, url, throttle, parsing, tx)
+                    .map_err(Into::into),
+            );
+            tasks.spawn(pages_to_webhooks(rx, client, conf, filter).map_err(Into::into));
+            // neither stops on its own without an error
+            if let Some(res) = tasks.join_next().await {
+                res??;
+            }
+        }
+        Commands::Diff {
+            left,
+            right,
+            after,
+            until,
+            pg_cert,
+        } => {
+            let until = until.unwrap_or_else(chrono::Utc::now);
+            let throttle = Duration::from_millis(globals.upstream_throttle_ms);
+            let left = OpSource::from_arg(&left, pg_cert.clone(), &client).await?;
+            let right = OpSource::from_arg(&right, pg_cert, &client).await?;
+
+            let (left_tx, left_rx) = mpsc::channel(4);
+            let (right_tx, right_rx) = mpsc::channel(4);
+            let (diff_tx, mut diff_rx) = mpsc::channel::<OpDiff>(64);
+            let print = async move {
+                let mut counts = [0usize; 4];
+                while let Some(diff) = diff_rx.recv().await {
+                    println!("{}", serde_json::to_string(&diff)?);
+                    counts[diff.kind as usize] += 1;
+                }
+                let count = |kind: DiffKind| counts[kind as usize];
+                log::info!(
+                    "{} differences: {} missing left, {} missing right, {} nullified, {} operation",
+                    counts.iter().sum::<usize>(),
+                    count(DiffKind::MissingLeft),
+                    count(DiffKind::MissingRight),
+                    count(DiffKind::NullifiedDiffers),
+                    count(DiffKind::OperationDiffers),
+                );
+                anyhow::Ok(())
+            };
+            tokio::try_join!(
+                source_to_pages(left, after, until, throttle, left_tx),
+                source_to_pages(right, after, until, throttle, right_tx),
+                diff_pages(left_rx, right_rx, diff_tx),
+                print,
+            )?;
+        }
+        Commands::Resolve {
+            did,
+            source,
+            at,
+            catch_up,
+            show,
+            pg_cert,
+        } => {
+            let start: Dt = PLC_START.parse()?;
+            let until = chrono::Utc::now();
+            let throttle = Duration::from_millis(globals.upstream_throttle_ms);
+            let source = OpSource::from_arg(&source, pg_cert, &client).await?;
+            let (mut ops, latest) = collect_did_ops(source, &did, start, until, throttle).await?;
+            log::info!(
+                "found {} ops for {did}, source is current to {latest:?}",
+                ops.len()
+            );
+
+            if catch_up && at.is_none_or(|at| latest.is_none_or(|l| l < at)) {
+                let mut url = globals.upstream;
+                url.set_path("/export");
+                let after = latest.unwrap_or(start);
+                log::info!("catching up from {url} after {after}...");
+                let (newer, _) =
+                    collect_did_ops(OpSource::Export(url, client), &did, after, until, throttle)
+                        .await?;
+                log::info!("found {} more ops upstream", newer.len());
+                ops.extend(newer);
+            }
+
+            let history = DidHistory::replay(&did, ops, at)?;
+            let out = match show {
+                ResolveOutput::Document => history.document()?,
+                ResolveOutput::Data => history.data()?,
+                ResolveOutput::Audit => serde_json::to_value(&history.audit_log)?,
+            };
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+        Commands::Scatter {
+            to,
+            rule,
+            timeout_ms,
+            bind,
+            trusted_proxy,
+            trusted_proxy_header,
+            write_ip_quota,
+            write_did_quota,
+        } => {
+            let conf = ScatterConf {
+                upstreams: to,
+                rule,
+                timeout: Duration::from_millis(timeout_ms),
+            };
+            let limits = RateLimitConf {
+                client_ip: ClientIp::new(trusted_proxy, trusted_proxy_header),
+                write_per_ip: write_ip_quota,
+                write_per_did: write_did_quota,
+                ..Default::default()
+            };
+            serve_scatter(conf, bind, limits).await?;
+        }
+        Commands::Witness {
+            log,
+            log_key,
+            signing_key,
+            state,
+            tlog_file,
+            interval_ms,
+            bind,
+        } => {
+            let log_key = NoteVerifier::from_vkey(&log_key)?;
+            let tlog = Tlog::open(log_key.name().to_string(), tlog_file)?;
+            // ops sharing the last timestamp might not all be logged yet
+            let after = tlog.last_at().map(|t| t - chrono::TimeDelta::seconds(1));
+            let tlog = Arc::new(RwLock::new(tlog));
+            let conf = WitnessConf {
+                log,
+                log_key,
+                signer: NoteSigner::from_skey(&std::fs::read_to_string(signing_key)?)?,
+                tlog: tlog.clone(),
+                state,
+                interval: Duration::from_millis(interval_ms),
+            };
+            let witnessed = load_witnessed(&conf.state).await?;
+            let mut url = globals.upstream;
+            url.set_path("/export");
+            let throttle = Duration::from_millis(globals.upstream_throttle_ms);
+            let (tx, rx) = mpsc::channel(32);
+            tokio::select! {
+                res = poll_upstream(client, after, url, throttle, parsing, tx) => res?,
+                res = pages_to_tlog(rx, tlog) => res?,
+                res = witness(conf, witnessed.clone()) => res?,
+                res = serve_witness(bind, witnessed) => res?,
+            };
+        }
+        Commands::ServeFixture {
+            source,
+            bind,
+            duplicate_boundaries,
+            rate_limit_every,
+            retry_after_secs,
+            delay_ms,
+            truncate_every,
+            pg_cert,
+        } => {
+            let faults = FixtureFaults {
+                duplicate_boundaries,
+                rate_limit_every: rate_limit_every.unwrap_or(0),
+                retry_after: Duration::from_secs(retry_after_secs),
+                delay: Duration::from_millis(delay_ms),
+                truncate_every: truncate_every.unwrap_or(0),
+            };
+            let source = OpSource::from_arg(&source, pg_cert, &client).await?;
+            let fixture = Fixture::from_source(source, PLC_START.parse()?, faults).await?;
+            log::info!(
+                "serving {} fixture ops at http://{bind}",
+                fixture.ops().len()
+            );
+            serve_fixture(fixture, bind).await?;
+        }
+        Commands::Keygen { name, out } => {
+            if out.exists() {
+                anyhow::bail!("not overwriting existing key file {out:?}");
+            }
+            let signer = NoteSigner::generate(&name)?;
+            std::fs::write(&out, format!("{}\n", signer.skey()))?;
+            println!("{}", signer.verifier().vkey());
+            log::info!(
+                "as a witness, its cosignature verifier key is {}",
+                signer.cosigner().vkey()
+            );
+        }
+        Commands::Config {
+            command: ConfigCommand::Check { path },
+        } => {
+            check_config(Cli::command(), &path)?;
+            log::info!("config file {path:?} looks good");
         }
     }
     log::info!("whew, {:?}. goodbye!", t0.elapsed());
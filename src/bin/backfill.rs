@@ -1,8 +1,11 @@
 use allegedly::{
-    Db, Dt, ExportPage, FolderSource, HttpSource, backfill, backfill_to_pg, bin::GlobalArgs,
-    bin_init, full_pages, pages_to_pg, pages_to_stdout, poll_upstream,
+    CatchUpTarget, Db, Dt, ExportPage, FolderSource, HttpSource, OutputFormat, backfill,
+    backfill_to_pg,
+    bin::{FilterArgs, GlobalArgs, OutputArgs, command_with_config},
+    bin_init, catch_up_upstream, caught_up, pages_to_output, pages_to_pg, poll_upstream,
 };
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures::TryFutureExt;
 use reqwest::Url;
 use std::{path::PathBuf, time::Duration};
 use tokio::{
@@ -44,19 +47,29 @@ pub struct Args {
     /// only used if `--to-postgres` is present
     #[arg(long, action)]
     postgres_reset: bool,
-    /// Stop at the week ending before this date
+    /// Stop at this date
+    ///
+    /// Bulk loading stops at the week ending before it, and polling upstream
+    /// (with `--no-bulk` or `--catch-up`) stops right at it.
     #[arg(long)]
     until: Option<Dt>,
     /// After the weekly imports, poll upstream until we're caught up
     #[arg(long, action)]
     catch_up: bool,
+    /// Catch up in this many concurrent time slices
+    ///
+    /// They share the upstream request budget. 1 polls sequentially.
+    #[arg(long, default_value = "1")]
+    catch_up_slices: usize,
+    /// Only output some ops (stdout only)
+    #[command(flatten)]
+    filter: FilterArgs,
+    #[command(flatten)]
+    output: OutputArgs,
 }
 
 pub async fn run(
-    GlobalArgs {
-        upstream,
-        upstream_throttle_ms,
-    }: GlobalArgs,
+    globals: GlobalArgs,
     Args {
         http,
         dir,
@@ -67,8 +80,25 @@ pub async fn run(
         postgres_reset,
         until,
         catch_up,
+        catch_up_slices,
+        filter,
+        output,
     }: Args,
 ) -> anyhow::Result<()> {
+    let filter = filter.op_filter()?;
+    if filter.is_some() && to_postgres.is_some() {
+        anyhow::bail!("op filters only apply to stdout, not --to-postgres");
+    }
+    if output.format != OutputFormat::Jsonl && to_postgres.is_some() {
+        anyhow::bail!("--format only applies to stdout, not --to-postgres");
+    }
+    let client = globals.http_client()?;
+    let parsing = globals.page_parsing();
+    let GlobalArgs {
+        upstream,
+        upstream_throttle_ms,
+        ..
+    } = globals;
     let mut tasks = JoinSet::<anyhow::Result<&'static str>>::new();
 
     let (bulk_tx, bulk_out) = mpsc::channel(32); // bulk uses big pages
@@ -83,7 +113,17 @@ pub async fn run(
     };
 
     let (poll_tx, poll_out) = mpsc::channel::<ExportPage>(128); // normal/small pages
-    let (full_tx, full_out) = mpsc::channel(1); // don't need to buffer at this filter
+    let (caught_up_tx, caught_up_out) = mpsc::channel(1); // don't need to buffer at this filter
+    // the catch-up sink hears when the catch-up source has caught up
+    let (notify_tx, notify_out) = oneshot::channel();
+
+    let mut upstream = upstream;
+    upstream.set_path("/export");
+    let throttle = Duration::from_millis(upstream_throttle_ms);
+    let target = CatchUpTarget {
+        until,
+        ..CatchUpTarget::new(client.clone(), upstream.clone())
+    };
 
     // set up sources
     if no_bulk {
@@ -94,17 +134,14 @@ pub async fn run(
         if let Some(d) = dir {
             log::warn!("ignoring bulk dir setting ({d:?}) since --no-bulk was set.");
         }
-        if let Some(u) = until {
-            log::warn!(
-                "ignoring `until` setting ({u:?}) since --no-bulk was set. (feature request?)"
-            );
-        }
-        let mut upstream = upstream;
-        upstream.set_path("/export");
-        let throttle = Duration::from_millis(upstream_throttle_ms);
-        tasks.spawn(poll_upstream(None, upstream, throttle, poll_tx));
-        tasks.spawn(full_pages(poll_out, full_tx));
-        tasks.spawn(pages_to_stdout(full_out, None));
+        tasks.spawn(
+            poll_upstream(client, None, upstream, throttle, parsing, poll_tx).map_err(Into::into),
+        );
+        tasks.spawn(caught_up(poll_out, caught_up_tx, target, None).map_err(Into::into));
+        tasks.spawn(
+            pages_to_output(caught_up_out, None, filter, output.stdout_writer()?)
+                .map_err(Into::into),
+        );
     } else {
         // fun mode
 
@@ -115,29 +152,62 @@ pub async fn run(
                     "non-default bulk http setting can't be used with bulk dir setting ({dir:?})"
                 );
             }
-            tasks.spawn(backfill(
-                FolderSource(dir),
-                bulk_tx,
-                source_workers.unwrap_or(1),
-                until,
-            ));
+            tasks.spawn(
+                backfill(
+                    FolderSource(dir),
+                    bulk_tx,
+                    source_workers.unwrap_or(1),
+                    until,
+                )
+                .map_err(Into::into),
+            );
         } else {
-            tasks.spawn(backfill(
-                HttpSource(http),
-                bulk_tx,
-                source_workers.unwrap_or(4),
-                until,
-            ));
+            tasks.spawn(
+                backfill(
+                    HttpSource(http, client.clone()),
+                    bulk_tx,
+                    source_workers.unwrap_or(4),
+                    until,
+                )
+                .map_err(Into::into),
+            );
         }
 
         // and the catch-up source...
         if let Some(last) = found_last_out {
-            let throttle = Duration::from_millis(upstream_throttle_ms);
+            let notify = to_postgres.is_some().then_some(notify_tx);
             tasks.spawn(async move {
-                let mut upstream = upstream;
-                upstream.set_path("/export");
-
-                poll_upstream(last.await?, upstream, throttle, poll_tx).await
+                let found_last = last.await?;
+                // start from the bulk's last op, so upstream's head can be
+                // checked even if no newer pages ever show up
+                let target = CatchUpTarget {
+                    after: found_last,
+                    ..target
+                };
+                let catching_up = caught_up(poll_out, caught_up_tx, target, notify);
+                let polling = async {
+                    match found_last {
+                        Some(after) if catch_up_slices > 1 => {
+                            catch_up_upstream(
+                                client,
+                                after,
+                                until,
+                                upstream,
+                                catch_up_slices,
+                                throttle,
+                                parsing,
+                                poll_tx,
+                            )
+                            .await
+                        }
+                        after => {
+                            poll_upstream(client, after, upstream, throttle, parsing, poll_tx).await
+                        }
+                    }
+                };
+                // caught_up stopping drops the poller's channel, which stops it too
+                tokio::try_join!(catching_up, polling)?;
+                Ok("catch-up")
             });
         }
 
@@ -147,19 +217,23 @@ pub async fn run(
             let db = Db::new(pg_url.as_str(), postgres_cert).await?;
             log::trace!("connected to postgres");
 
-            tasks.spawn(backfill_to_pg(
-                db.clone(),
-                postgres_reset,
-                bulk_out,
-                found_last_tx,
-            ));
+            tasks.spawn(
+                backfill_to_pg(db.clone(), postgres_reset, bulk_out, found_last_tx)
+                    .map_err(Into::into),
+            );
             if catch_up {
-                tasks.spawn(pages_to_pg(db, full_out));
+                let notified = Some(notify_out);
+                tasks.spawn(pages_to_pg(db, caught_up_out, notified).map_err(Into::into));
             }
         } else {
-            tasks.spawn(pages_to_stdout(bulk_out, found_last_tx));
+            // one writer for both, so there's one header (or footer)
+            let out = output.stdout_writer()?;
+            tasks.spawn(
+                pages_to_output(bulk_out, found_last_tx, filter.clone(), out.clone())
+                    .map_err(Into::into),
+            );
             if catch_up {
-                tasks.spawn(pages_to_stdout(full_out, None));
+                tasks.spawn(pages_to_output(caught_up_out, None, filter, out).map_err(Into::into));
             }
         }
     }
@@ -198,8 +272,10 @@ struct CliArgs {
 #[allow(dead_code)]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = CliArgs::parse();
+    let matches = command_with_config(CliArgs::command(), Some("backfill"))?.get_matches();
+    let args = CliArgs::from_arg_matches(&matches)?;
     bin_init("backfill");
+    args.globals.init_dead_letters().await?;
     run(args.globals, args.args).await?;
     Ok(())
 }
@@ -1,9 +1,21 @@
 use allegedly::{
-    Db, ExperimentalConf, ListenConf, bin::GlobalArgs, bin_init, pages_to_pg, poll_upstream, serve,
+    ClientIp, Db, ExperimentalConf, FallbackConf, ForwardedHeader, IpAccess, ListenConf,
+    NoteSigner, RateLimitConf, ScatterConf, ScatterRule, TimingConf, Tlog,
+    bin::{GlobalArgs, command_with_config},
+    bin_init, pages_to_pg, parse_ip_net, parse_quota, parse_route_quota, poll_upstream, serve,
+    tlog_sync,
 };
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures::TryFutureExt;
+use governor::Quota;
+use ipnet::IpNet;
 use reqwest::Url;
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tokio::{fs::create_dir_all, sync::mpsc, task::JoinSet};
 
 #[derive(Debug, clap::Args)]
@@ -44,6 +56,65 @@ pub struct Args {
     /// try to listen for ipv6
     #[arg(long, action, requires("acme_domain"), env = "ALLEGEDLY_ACME_IPV6")]
     acme_ipv6: bool,
+    /// trust client-ip forwarding headers from these proxies (eg. a load balancer)
+    ///
+    /// accepts CIDR networks or bare IPs, comma-separated or repeated. requests
+    /// from other peers are always rate-limited by their socket address.
+    #[arg(long, value_delimiter = ',', value_parser = parse_ip_net, env = "ALLEGEDLY_TRUSTED_PROXY")]
+    trusted_proxy: Vec<IpNet>,
+    /// which header trusted proxies use to forward the client ip
+    #[arg(long, value_enum, env = "ALLEGEDLY_TRUSTED_PROXY_HEADER")]
+    #[clap(default_value = "x-forwarded-for")]
+    trusted_proxy_header: ForwardedHeader,
+    /// per-ip request quota for all requests, like "3000/min"
+    ///
+    /// ipv6 clients are limited by their /64, with scaled-up quotas applied
+    /// to their /56 and /48 as well.
+    #[arg(long, value_parser = parse_quota, env = "ALLEGEDLY_QUOTA")]
+    #[clap(default_value = "3000/min")]
+    quota: Quota,
+    /// extra per-ip quota for a route, like "/export=100/min" (repeatable)
+    ///
+    /// routes match by path prefix, and `*` matches any one segment: "/*/log"
+    /// covers every `/:did/log/...`. applies on top of `--quota`.
+    #[arg(long, value_parser = parse_route_quota, env = "ALLEGEDLY_ROUTE_QUOTA")]
+    route_quota: Vec<(String, Quota)>,
+    /// per-ip quota for forwarded op submissions
+    #[arg(long, value_parser = parse_quota, env = "ALLEGEDLY_WRITE_IP_QUOTA")]
+    #[clap(default_value = "10/hour")]
+    write_ip_quota: Quota,
+    /// per-did quota for forwarded op submissions
+    #[arg(long, value_parser = parse_quota, env = "ALLEGEDLY_WRITE_DID_QUOTA")]
+    #[clap(default_value = "4/hour")]
+    write_did_quota: Quota,
+    /// networks exempt from per-ip quotas, like your own indexers
+    ///
+    /// accepts CIDR networks or bare IPs, comma-separated or repeated.
+    #[arg(long, value_delimiter = ',', value_parser = parse_ip_net, env = "ALLEGEDLY_QUOTA_ALLOW")]
+    quota_allow: Vec<IpNet>,
+    /// networks to refuse requests from entirely (403)
+    #[arg(long, value_delimiter = ',', value_parser = parse_ip_net, env = "ALLEGEDLY_QUOTA_DENY")]
+    quota_deny: Vec<IpNet>,
+    /// timeout for proxied requests to the wrapped server
+    #[arg(long, env = "ALLEGEDLY_WRAP_TIMEOUT_MS")]
+    #[clap(default_value = "3000")]
+    wrap_timeout_ms: u64,
+    /// timeout for op submissions forwarded upstream
+    #[arg(long, env = "ALLEGEDLY_UPSTREAM_WRITE_TIMEOUT_MS")]
+    #[clap(default_value = "15000")]
+    upstream_write_timeout_ms: u64,
+    /// timeout for reads sent upstream when the wrapped server can't answer
+    #[arg(long, env = "ALLEGEDLY_UPSTREAM_READ_TIMEOUT_MS")]
+    #[clap(default_value = "5000")]
+    upstream_read_timeout_ms: u64,
+    /// how long to cache the latest op time reported by /_health
+    #[arg(long, env = "ALLEGEDLY_LATEST_AT_CACHE_MS")]
+    #[clap(default_value = "2000")]
+    latest_at_cache_ms: u64,
+    /// how long to cache the upstream status reported by /_health
+    #[arg(long, env = "ALLEGEDLY_UPSTREAM_STATUS_CACHE_MS")]
+    #[clap(default_value = "6000")]
+    upstream_status_cache_ms: u64,
     /// only accept experimental requests at this hostname
     ///
     /// a cert will be provisioned for it from letsencrypt. if you're not using
@@ -57,13 +128,63 @@ pub struct Args {
     /// accept writes! by forwarding them upstream
     #[arg(long, action, env = "ALLEGEDLY_EXPERIMENTAL_WRITE_UPSTREAM")]
     experimental_write_upstream: bool,
+    /// also forward writes to these upstreams (comma-separated or repeated)
+    ///
+    /// the response then lists every upstream's result.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        requires("experimental_write_upstream"),
+        env = "ALLEGEDLY_SCATTER_UPSTREAM"
+    )]
+    scatter_upstream: Vec<Url>,
+    /// when a scattered write counts as accepted
+    #[arg(long, value_enum, env = "ALLEGEDLY_SCATTER_RULE")]
+    #[clap(default_value = "first")]
+    scatter_rule: ScatterRule,
+    /// serve reads from upstream when the wrapped server fails or times out
+    ///
+    /// responses from upstream have an `x-allegedly-fallback: upstream` header
+    #[arg(long, action, env = "ALLEGEDLY_EXPERIMENTAL_READ_FALLBACK")]
+    experimental_read_fallback: bool,
+    /// consecutive wrapped server failures before reads go straight upstream
+    #[arg(
+        long,
+        requires("experimental_read_fallback"),
+        env = "ALLEGEDLY_FALLBACK_FAILURE_THRESHOLD"
+    )]
+    #[clap(default_value = "5")]
+    fallback_failure_threshold: u32,
+    /// how long to send reads straight upstream before retrying the wrapped server
+    #[arg(
+        long,
+        requires("experimental_read_fallback"),
+        env = "ALLEGEDLY_FALLBACK_COOLDOWN_MS"
+    )]
+    #[clap(default_value = "30000")]
+    fallback_cooldown_ms: u64,
+    /// serve a transparency log of mirrored ops, persisting its leaves to this file
+    ///
+    /// the log is built from the whole db history on first start, which takes
+    /// a while: the checkpoint grows as it catches up. mirror mode only.
+    #[arg(long, requires("tlog_origin"), env = "ALLEGEDLY_EXPERIMENTAL_TLOG")]
+    experimental_tlog: Option<PathBuf>,
+    /// the tlog's origin line, identifying it in checkpoints (eg. your hostname)
+    #[arg(long, requires("experimental_tlog"), env = "ALLEGEDLY_TLOG_ORIGIN")]
+    tlog_origin: Option<String>,
+    /// sign tlog checkpoints with the note key in this file (see `allegedly keygen`)
+    ///
+    /// the key's name should match `--tlog-origin`.
+    #[arg(
+        long,
+        requires("experimental_tlog"),
+        env = "ALLEGEDLY_TLOG_SIGNING_KEY"
+    )]
+    tlog_signing_key: Option<PathBuf>,
 }
 
 pub async fn run(
-    GlobalArgs {
-        upstream,
-        upstream_throttle_ms,
-    }: GlobalArgs,
+    globals: GlobalArgs,
     Args {
         wrap,
         wrap_pg,
@@ -73,11 +194,39 @@ pub async fn run(
         acme_cache_path,
         acme_directory_url,
         acme_ipv6,
+        trusted_proxy,
+        trusted_proxy_header,
+        quota,
+        route_quota,
+        write_ip_quota,
+        write_did_quota,
+        quota_allow,
+        quota_deny,
+        wrap_timeout_ms,
+        upstream_write_timeout_ms,
+        upstream_read_timeout_ms,
+        latest_at_cache_ms,
+        upstream_status_cache_ms,
         experimental_acme_domain,
         experimental_write_upstream,
+        scatter_upstream,
+        scatter_rule,
+        experimental_read_fallback,
+        fallback_failure_threshold,
+        fallback_cooldown_ms,
+        experimental_tlog,
+        tlog_origin,
+        tlog_signing_key,
     }: Args,
     sync: bool,
 ) -> anyhow::Result<()> {
+    let client = globals.http_client()?;
+    let parsing = globals.page_parsing();
+    let GlobalArgs {
+        upstream,
+        upstream_throttle_ms,
+        ..
+    } = globals;
     let listen_conf = match (bind, acme_domain.is_empty(), acme_cache_path) {
         (_, false, Some(cache_path)) => {
             create_dir_all(&cache_path).await?;
@@ -94,15 +243,64 @@ pub async fn run(
             }
         }
         (bind, true, None) => ListenConf::Bind(bind),
-        (_, _, _) => unreachable!(),
+        (_, true, Some(_)) => anyhow::bail!("acme cache path requires an acme domain"),
+        (_, false, None) => anyhow::bail!("acme domain requires an acme cache path"),
+    };
+
+    let limits = RateLimitConf {
+        client_ip: ClientIp::new(trusted_proxy, trusted_proxy_header),
+        global: quota,
+        routes: route_quota,
+        write_per_ip: write_ip_quota,
+        write_per_did: write_did_quota,
+        access: IpAccess {
+            allow: quota_allow,
+            deny: quota_deny,
+        },
+    };
+
+    let timing = TimingConf {
+        wrapped: Duration::from_millis(wrap_timeout_ms),
+        upstream_write: Duration::from_millis(upstream_write_timeout_ms),
+        upstream_read: Duration::from_millis(upstream_read_timeout_ms),
+        latest_at_validity: Duration::from_millis(latest_at_cache_ms),
+        upstream_status_validity: Duration::from_millis(upstream_status_cache_ms),
+    };
+
+    let tlog = match (experimental_tlog, tlog_origin) {
+        (Some(_), _) if !sync => anyhow::bail!("a tlog needs mirror mode, not wrap mode"),
+        (Some(path), Some(origin)) => {
+            log::info!("loading tlog from {path:?}...");
+            let mut tlog = Tlog::open(origin.clone(), Some(path))?;
+            if let Some(key_path) = tlog_signing_key {
+                let signer = NoteSigner::from_skey(&std::fs::read_to_string(key_path)?)?;
+                if signer.name() != origin {
+                    log::warn!("tlog signing key name doesn't match the origin {origin:?}");
+                }
+                log::info!("signing tlog checkpoints as {}", signer.verifier().vkey());
+                tlog = tlog.with_signer(signer);
+            }
+            Some(Arc::new(RwLock::new(tlog)))
+        }
+        _ => None,
     };
 
     let experimental_conf = ExperimentalConf {
         acme_domain: experimental_acme_domain,
         write_upstream: experimental_write_upstream,
+        scatter: (!scatter_upstream.is_empty()).then(|| ScatterConf {
+            upstreams: scatter_upstream,
+            rule: scatter_rule,
+            timeout: Duration::from_millis(upstream_write_timeout_ms),
+        }),
+        read_fallback: experimental_read_fallback.then(|| FallbackConf {
+            failure_threshold: fallback_failure_threshold,
+            cooldown: Duration::from_millis(fallback_cooldown_ms),
+        }),
+        tlog: tlog.clone(),
     };
 
-    let mut tasks = JoinSet::new();
+    let mut tasks = JoinSet::<anyhow::Result<&'static str>>::new();
 
     let db = if sync {
         let wrap_pg = wrap_pg.ok_or(anyhow::anyhow!(
@@ -123,8 +321,14 @@ pub async fn run(
         poll_url.set_path("/export");
         let throttle = Duration::from_millis(upstream_throttle_ms);
 
-        tasks.spawn(poll_upstream(Some(latest), poll_url, throttle, send_page));
-        tasks.spawn(pages_to_pg(db.clone(), recv_page));
+        tasks.spawn(
+            poll_upstream(client, Some(latest), poll_url, throttle, parsing, send_page)
+                .map_err(Into::into),
+        );
+        tasks.spawn(pages_to_pg(db.clone(), recv_page, None).map_err(Into::into));
+        if let Some(tlog) = tlog {
+            tasks.spawn(tlog_sync(tlog, db.clone(), Duration::from_secs(1)));
+        }
         Some(db)
     } else {
         None
@@ -134,6 +338,8 @@ pub async fn run(
         upstream,
         wrap,
         listen_conf,
+        limits,
+        timing,
         experimental_conf,
         db.clone(),
     ));
@@ -175,8 +381,10 @@ struct CliArgs {
 #[allow(dead_code)]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = CliArgs::parse();
+    let matches = command_with_config(CliArgs::command(), Some("mirror"))?.get_matches();
+    let args = CliArgs::from_arg_matches(&matches)?;
     bin_init("mirror");
+    args.globals.init_dead_letters().await?;
     run(args.globals, args.args, !args.wrap_mode).await?;
     Ok(())
 }
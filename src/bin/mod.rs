@@ -1,4 +1,14 @@
+use clap::{ArgAction, Command, error::ErrorKind};
 use reqwest::Url;
+// this file is also built as a (dummy) binary, so no `crate::` paths
+use allegedly::{
+    ClientConf, Db, DeadLetterSink, Field, HttpClient, OpFilter, OpType, OpWriter, OutputFormat,
+    PageParsing, UpstreamHeader, set_dead_letters,
+};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct GlobalArgs {
@@ -8,13 +18,551 @@ pub struct GlobalArgs {
     pub upstream: Url,
     /// Self-rate-limit upstream request interval
     ///
-    /// plc.directory's rate limiting is 500 requests per 5 mins (600ms)
+    /// plc.directory's rate limiting is 500 requests per 5 mins (600ms). The
+    /// poller goes faster while catching up if upstream's rate-limit headers
+    /// say there's budget, and slower if they say it's running out.
     #[arg(long, global = true, env = "ALLEGEDLY_UPSTREAM_THROTTLE_MS")]
     #[clap(default_value = "600")]
     pub upstream_throttle_ms: u64,
+    /// Parse export pages in batches of this many ops on blocking threads
+    ///
+    /// Worth it for upstreams that serve pages much bigger than 1000 ops. By
+    /// default each op is parsed as soon as it arrives.
+    #[arg(long, global = true, env = "ALLEGEDLY_PARSE_BATCH")]
+    pub parse_batch: Option<usize>,
+    /// Read option values from a TOML config file
+    ///
+    /// Top-level keys set global options, and tables like `[mirror]` set the
+    /// options for that command. Keys are option names (`wrap-pg` or
+    /// `wrap_pg`). Flags and env vars override values from the file.
+    #[arg(long, global = true, env = "ALLEGEDLY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Contact info for our user-agent, so upstream operators can reach you
+    #[arg(long, global = true, env = "ALLEGEDLY_UA_CONTACT")]
+    pub ua_contact: Option<String>,
+    /// Timeout for each request to upstreams and bundle hosts
+    #[arg(long, global = true, env = "ALLEGEDLY_HTTP_TIMEOUT_MS")]
+    pub http_timeout_ms: Option<u64>,
+    /// How many times to retry failed upstream requests, with backoff
+    #[arg(long, global = true, env = "ALLEGEDLY_HTTP_RETRIES")]
+    #[clap(default_value = "12")]
+    pub http_retries: u32,
+    /// Shortest wait between upstream request retries
+    #[arg(long, global = true, env = "ALLEGEDLY_HTTP_RETRY_MIN_MS")]
+    #[clap(default_value = "1000")]
+    pub http_retry_min_ms: u64,
+    /// Longest wait between upstream request retries
+    #[arg(long, global = true, env = "ALLEGEDLY_HTTP_RETRY_MAX_MS")]
+    #[clap(default_value = "1800000")]
+    pub http_retry_max_ms: u64,
+    /// Send upstream requests through this proxy
+    #[arg(long, global = true, env = "ALLEGEDLY_HTTP_PROXY")]
+    pub http_proxy: Option<Url>,
+    /// Also trust this PEM root certificate for upstream requests (repeatable)
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        env = "ALLEGEDLY_HTTP_ROOT_CERT"
+    )]
+    pub http_root_cert: Vec<PathBuf>,
+    /// Add a header to requests for one upstream host, like
+    /// `plc.example.com=Authorization: Bearer ...` (repeatable)
+    #[arg(long, global = true, env = "ALLEGEDLY_UPSTREAM_HEADER")]
+    pub upstream_header: Vec<UpstreamHeader>,
+    /// Record lines that fail to parse as ops, for auditing
+    ///
+    /// A file path (appended as jsonl), or a postgres url for an
+    /// `allegedly_dead_letters` table in a plc database
+    #[arg(long, global = true, env = "ALLEGEDLY_DEAD_LETTERS")]
+    pub dead_letters: Option<String>,
+    /// Abort on lines that fail to parse as ops, instead of skipping them
+    #[arg(long, global = true, env = "ALLEGEDLY_STRICT")]
+    pub strict: bool,
+}
+
+impl GlobalArgs {
+    /// The http client for upstreams and bundle hosts
+    pub fn http_client(&self) -> anyhow::Result<HttpClient> {
+        let conf = ClientConf {
+            contact: self.ua_contact.clone(),
+            timeout: self.http_timeout_ms.map(Duration::from_millis),
+            max_retries: self.http_retries,
+            retry_bounds: (
+                Duration::from_millis(self.http_retry_min_ms),
+                Duration::from_millis(self.http_retry_max_ms),
+            ),
+            proxy: self.http_proxy.clone(),
+            root_certs: self.http_root_cert.clone(),
+            upstream_headers: self.upstream_header.clone(),
+            ..Default::default()
+        };
+        Ok(conf.build()?)
+    }
+
+    /// How to parse export pages, from `--parse-batch`
+    pub fn page_parsing(&self) -> PageParsing {
+        match self.parse_batch {
+            Some(batch) => PageParsing::Parallel { batch },
+            None => PageParsing::Inline,
+        }
+    }
+
+    /// Set up the process-wide dead letters from `--dead-letters` and `--strict`
+    pub async fn init_dead_letters(&self) -> anyhow::Result<()> {
+        let sink = match self.dead_letters.as_deref() {
+            None => None,
+            Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+                Some(DeadLetterSink::Postgres(Db::new(url, None).await?))
+            }
+            Some(path) => Some(DeadLetterSink::File(path.into())),
+        };
+        set_dead_letters(sink, self.strict).await?;
+        Ok(())
+    }
+}
+
+/// Only output some ops
+///
+/// Each option can be repeated (or comma-separated) to match any of its
+/// values. Ops must match every option that's given.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct FilterArgs {
+    /// Only ops for this DID
+    #[arg(long, value_delimiter = ',')]
+    pub did: Vec<String>,
+    /// Only ops for DIDs listed in this file, one per line
+    #[arg(long)]
+    pub did_list: Option<PathBuf>,
+    /// Only ops of this type: genesis, update or tombstone
+    #[arg(long, value_delimiter = ',')]
+    pub op_type: Vec<OpType>,
+    /// Only nullified (true) or valid (false) ops
+    #[arg(long)]
+    pub nullified: Option<bool>,
+    /// Only ops that set this handle (without `at://`)
+    #[arg(long, value_delimiter = ',')]
+    pub handle: Vec<String>,
+    /// Only ops that set this PDS endpoint
+    #[arg(long, value_delimiter = ',')]
+    pub pds: Vec<String>,
+    /// Only ops that list this rotation key (a `did:key`)
+    #[arg(long, value_delimiter = ',')]
+    pub rotation_key: Vec<String>,
+}
+
+impl FilterArgs {
+    /// The op filter, or `None` if no filter options were given
+    pub fn op_filter(&self) -> anyhow::Result<Option<OpFilter>> {
+        let mut filter = OpFilter {
+            dids: (!self.did.is_empty()).then(|| self.did.iter().cloned().collect::<HashSet<_>>()),
+            op_types: self.op_type.clone(),
+            nullified: self.nullified,
+            handles: self.handle.clone(),
+            pds: self.pds.clone(),
+            rotation_keys: self.rotation_key.clone(),
+        };
+        if let Some(ref path) = self.did_list {
+            filter.add_did_list(path)?;
+        }
+        Ok((!filter.is_empty()).then_some(filter))
+    }
+}
+
+/// How to write ops to stdout
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct OutputArgs {
+    /// Output format: jsonl, envelope, csv, tsv, dag-cbor, arrow or parquet
+    ///
+    /// `envelope` wraps each op with the cursor to resume after it. `dag-cbor`
+    /// writes each op prefixed by its length as a varint. `arrow` (IPC stream)
+    /// and `parquet` need the `columnar` feature.
+    #[arg(long, default_value = "jsonl")]
+    pub format: OutputFormat,
+    /// Fields for csv, tsv, arrow and parquet
+    ///
+    /// Any of did, cid, created_at, nullified, type, handle, pds and operation.
+    /// Default: did, cid, created_at, nullified, operation
+    #[arg(long, value_delimiter = ',')]
+    pub fields: Vec<Field>,
+}
+
+impl OutputArgs {
+    /// A stdout writer that backfill's sinks can share
+    pub fn stdout_writer(&self) -> anyhow::Result<Arc<Mutex<OpWriter<std::io::Stdout>>>> {
+        let writer = OpWriter::new(self.format, self.fields.clone(), std::io::stdout())?;
+        Ok(Arc::new(Mutex::new(writer)))
+    }
+}
+
+/// Find `--config` before clap runs, so the file can supply clap's defaults
+pub fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os("ALLEGEDLY_CONFIG").map(PathBuf::from)
+}
+
+pub fn read_config(path: &Path) -> anyhow::Result<toml::Table> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read config file {path:?}: {e}"))?;
+    text.parse::<toml::Table>()
+        .map_err(|e| anyhow::anyhow!("failed to parse config file {path:?}: {e}"))
+}
+
+/// Use a config file's values as the command's defaults
+///
+/// Top-level keys apply to the command itself. Tables apply to the subcommand
+/// of the same name, except for `flatten`: its table also applies to the
+/// command itself, and other tables are ignored. (standalone binaries like
+/// `mirror` flatten their subcommand's options into the top level.)
+pub fn apply_config(
+    mut cmd: Command,
+    config: &toml::Table,
+    flatten: Option<&str>,
+) -> anyhow::Result<Command> {
+    for (key, value) in config {
+        match value {
+            toml::Value::Table(table) if flatten == Some(key.as_str()) => {
+                cmd = apply_args(cmd, table, key)?;
+            }
+            toml::Value::Table(_) if flatten.is_some() => {
+                log::trace!("ignoring config table [{key}] for this binary");
+            }
+            toml::Value::Table(table) => {
+                if cmd.find_subcommand(key).is_none() {
+                    anyhow::bail!("config: unknown command table [{key}]");
+                }
+                let mut res = Ok(());
+                cmd = cmd.mut_subcommand(key, |sub| match apply_args(sub.clone(), table, key) {
+                    Ok(sub) => sub,
+                    Err(e) => {
+                        res = Err(e);
+                        sub
+                    }
+                });
+                res?;
+            }
+            value => {
+                cmd = apply_args(
+                    cmd,
+                    &toml::Table::from_iter([(key.clone(), value.clone())]),
+                    "",
+                )?;
+            }
+        }
+    }
+    Ok(cmd)
+}
+
+fn apply_args(mut cmd: Command, table: &toml::Table, section: &str) -> anyhow::Result<Command> {
+    let where_ = |key: &str| {
+        if section.is_empty() {
+            format!("`{key}`")
+        } else {
+            format!("`{key}` in [{section}]")
+        }
+    };
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        let Some(arg) = cmd.get_arguments().find(|a| a.get_id() == id.as_str()) else {
+            anyhow::bail!("config: unknown option {}", where_(key));
+        };
+        let multiple = matches!(arg.get_action(), ArgAction::Append);
+        let values = match value {
+            toml::Value::Array(items) if multiple => {
+                items.iter().map(config_string).collect::<Option<Vec<_>>>()
+            }
+            toml::Value::Array(_) => anyhow::bail!(
+                "config: option {} takes a single value, not a list",
+                where_(key)
+            ),
+            value => config_string(value).map(|v| vec![v]),
+        }
+        .ok_or_else(|| anyhow::anyhow!("config: unsupported value for {}", where_(key)))?;
+
+        // a value from the file satisfies a required option
+        cmd = cmd.mut_arg(id, |a| a.default_values(values).required(false));
+    }
+    Ok(cmd)
+}
+
+fn config_string(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(dt) => Some(dt.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Apply the config file from `--config` (if any) to a command
+pub fn command_with_config(cmd: Command, flatten: Option<&str>) -> anyhow::Result<Command> {
+    match config_path() {
+        Some(path) => apply_config(cmd, &read_config(&path)?, flatten),
+        None => Ok(cmd),
+    }
+}
+
+/// Validate a config file against a command, without running anything
+///
+/// Checks that every key is a known option, and that each value parses.
+pub fn check_config(cmd: Command, path: &Path) -> anyhow::Result<()> {
+    let config = read_config(path)?;
+    let mut cmd = apply_config(cmd, &config, None)?;
+    cmd.build();
+
+    let bin = cmd.get_name().to_string();
+    let mut sections = vec![None];
+    sections.extend(
+        config
+            .iter()
+            .filter(|(_, v)| v.is_table())
+            .map(|(k, _)| Some(k.as_str())),
+    );
+    for section in sections {
+        let argv = std::iter::once(bin.as_str()).chain(section);
+        match cmd.clone().try_get_matches_from(argv) {
+            Ok(_) => {}
+            // options might still come from flags or env at runtime
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::MissingRequiredArgument
+                        | ErrorKind::MissingSubcommand
+                        | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+                ) => {}
+            Err(e) => anyhow::bail!(
+                "config: bad value in {}: {}",
+                section
+                    .map(|s| format!("[{s}]"))
+                    .unwrap_or("top level".into()),
+                e.render()
+            ),
+        }
+    }
+    Ok(())
 }
 
 #[allow(dead_code)]
 fn main() {
     panic!("this is not actually a module")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::{CommandFactory, Parser, Subcommand};
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[command(flatten)]
+        globals: GlobalArgs,
+        #[command(subcommand)]
+        command: Option<Commands>,
+    }
+
+    #[derive(Debug, Subcommand)]
+    enum Commands {
+        Mirror(MirrorArgs),
+        Backfill(BackfillArgs),
+        Tail(TailArgs),
+    }
+
+    #[derive(Debug, clap::Args)]
+    struct MirrorArgs {
+        #[arg(long)]
+        wrap: Url,
+        #[arg(
+            long,
+            env = "ALLEGEDLY_TEST_CONFIG_LISTEN",
+            default_value = "127.0.0.1:8000"
+        )]
+        listen: String,
+        #[arg(long)]
+        acme_domain: Vec<String>,
+    }
+
+    #[derive(Debug, clap::Args)]
+    struct BackfillArgs {
+        #[arg(long, default_value = "4")]
+        source_workers: usize,
+    }
+
+    #[derive(Debug, clap::Args)]
+    struct TailArgs {
+        #[arg(long)]
+        count: Option<usize>,
+    }
+
+    /// A standalone binary, like `mirror`, with its options at the top level
+    #[derive(Debug, Parser)]
+    struct MirrorCli {
+        #[command(flatten)]
+        globals: GlobalArgs,
+        #[command(flatten)]
+        args: MirrorArgs,
+    }
+
+    fn config(text: &str) -> toml::Table {
+        text.parse().unwrap()
+    }
+
+    fn parse<T: Parser>(config_text: &str, flatten: Option<&str>, argv: &[&str]) -> T {
+        let cmd = apply_config(T::command(), &config(config_text), flatten).unwrap();
+        let matches = cmd.try_get_matches_from(argv).unwrap();
+        T::from_arg_matches(&matches).unwrap()
+    }
+
+    fn mirror(cli: Cli) -> MirrorArgs {
+        match cli.command {
+            Some(Commands::Mirror(args)) => args,
+            other => panic!("expected mirror, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_flag_beats_file_beats_default() {
+        let cli: Cli = parse("", None, &["allegedly"]);
+        assert_eq!(cli.globals.upstream_throttle_ms, 600);
+
+        let file = "upstream-throttle-ms = 100";
+        let cli: Cli = parse(file, None, &["allegedly"]);
+        assert_eq!(cli.globals.upstream_throttle_ms, 100);
+
+        let argv = ["allegedly", "--upstream-throttle-ms", "5"];
+        let cli: Cli = parse(file, None, &argv);
+        assert_eq!(cli.globals.upstream_throttle_ms, 5);
+    }
+
+    #[test]
+    fn test_env_beats_file() {
+        let file = r#"
+            [mirror]
+            wrap = "http://127.0.0.1:3000"
+            listen = "0.0.0.0:9000"
+        "#;
+        let cli: Cli = parse(file, None, &["allegedly", "mirror"]);
+        assert_eq!(mirror(cli).listen, "0.0.0.0:9000");
+
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("ALLEGEDLY_TEST_CONFIG_LISTEN", "[::]:9001") };
+        let cli: Cli = parse(file, None, &["allegedly", "mirror"]);
+        unsafe { std::env::remove_var("ALLEGEDLY_TEST_CONFIG_LISTEN") };
+        assert_eq!(mirror(cli).listen, "[::]:9001");
+    }
+
+    #[test]
+    fn test_list_and_scalar_values() {
+        let file = r#"
+            http-retries = 3
+            strict = true
+            http_root_cert = ["a.pem", "b.pem"]
+            [mirror]
+            wrap = "http://127.0.0.1:3000"
+            acme-domain = ["a.example.com", "b.example.com"]
+        "#;
+        let cli: Cli = parse(file, None, &["allegedly", "mirror"]);
+        assert_eq!(cli.globals.http_retries, 3);
+        assert!(cli.globals.strict);
+        assert_eq!(
+            cli.globals.http_root_cert,
+            vec![PathBuf::from("a.pem"), PathBuf::from("b.pem")]
+        );
+        assert_eq!(
+            mirror(cli).acme_domain,
+            vec!["a.example.com", "b.example.com"]
+        );
+
+        // a list for a single-valued option
+        let bad = config("http-retries = [1, 2]");
+        let err = apply_config(Cli::command(), &bad, None).unwrap_err();
+        assert!(err.to_string().contains("single value"), "{err}");
+        // a table for an option
+        let bad = config("[mirror]\nwrap = { url = \"http://x\" }");
+        let err = apply_config(Cli::command(), &bad, None).unwrap_err();
+        assert!(err.to_string().contains("unsupported value"), "{err}");
+    }
+
+    #[test]
+    fn test_command_sections() {
+        let file = r#"
+            [mirror]
+            wrap = "http://127.0.0.1:3000"
+            [backfill]
+            source-workers = 8
+            [tail]
+            count = 10
+        "#;
+        let cli: Cli = parse(file, None, &["allegedly", "mirror"]);
+        assert_eq!(mirror(cli).wrap.as_str(), "http://127.0.0.1:3000/");
+        let cli: Cli = parse(file, None, &["allegedly", "backfill"]);
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Backfill(BackfillArgs { source_workers: 8 }))
+        ));
+        let cli: Cli = parse(file, None, &["allegedly", "tail"]);
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Tail(TailArgs { count: Some(10) }))
+        ));
+    }
+
+    #[test]
+    fn test_flattened_section() {
+        // the standalone binary takes [mirror] as its own options, ignores the
+        // other commands' tables, and still reads top-level globals
+        let file = r#"
+            upstream-throttle-ms = 100
+            [mirror]
+            wrap = "http://127.0.0.1:3000"
+            [backfill]
+            source-workers = 8
+            [tail]
+            nonsense = true
+        "#;
+        let cli: MirrorCli = parse(file, Some("mirror"), &["allegedly-mirror"]);
+        assert_eq!(cli.args.wrap.as_str(), "http://127.0.0.1:3000/");
+        assert_eq!(cli.globals.upstream_throttle_ms, 100);
+
+        // an unknown key in its own table is still an error
+        let bad = config("[mirror]\nnonsense = true");
+        assert!(apply_config(MirrorCli::command(), &bad, Some("mirror")).is_err());
+    }
+
+    #[test]
+    fn test_check_rejects_unknown_keys() {
+        let check = |name: &str, text: &str| {
+            let path =
+                std::env::temp_dir().join(format!("config-{name}-{}.toml", std::process::id()));
+            std::fs::write(&path, text).unwrap();
+            let res = check_config(Cli::command(), &path);
+            std::fs::remove_file(&path).unwrap();
+            res
+        };
+        check("ok", "strict = true\n[mirror]\nlisten = \"[::]:80\"").unwrap();
+
+        let err = check("unknown-top", "nonsense = 1").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown option `nonsense`"),
+            "{err}"
+        );
+        let err = check("unknown-key", "[tail]\nnonsense = 1").unwrap_err();
+        assert!(err.to_string().contains("`nonsense` in [tail]"), "{err}");
+        let err = check("unknown-table", "[nonsense]\nx = 1").unwrap_err();
+        assert!(err.to_string().contains("unknown command table"), "{err}");
+        let err = check("bad-value", "[backfill]\nsource-workers = \"many\"").unwrap_err();
+        assert!(err.to_string().contains("bad value in [backfill]"), "{err}");
+    }
+}
@@ -1,10 +1,11 @@
-use std::error::Error;
+use std::fmt::Display;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 pub trait Fetcher<T> {
-    fn fetch(&self) -> impl Future<Output = Result<T, Box<dyn Error>>>;
+    type Error: Display;
+    fn fetch(&self) -> impl Future<Output = Result<T, Self::Error>>;
 }
 
 #[derive(Debug)]
@@ -25,7 +26,6 @@ impl<T: Clone> ExpiringValue<T> {
     }
 }
 
-// TODO: generic over the fetcher's actual error type
 #[derive(Clone)]
 pub struct CachedValue<T: Clone, F: Fetcher<T>> {
     latest: Arc<Mutex<Option<ExpiringValue<T>>>>,
@@ -41,11 +41,11 @@ impl<T: Clone, F: Fetcher<T>> CachedValue<T, F> {
             validitiy,
         }
     }
-    pub async fn get(&self) -> Result<T, Box<dyn Error>> {
+    pub async fn get(&self) -> Result<T, F::Error> {
         let now = Instant::now();
         return self.get_impl(now).await;
     }
-    async fn get_impl(&self, now: Instant) -> Result<T, Box<dyn Error>> {
+    async fn get_impl(&self, now: Instant) -> Result<T, F::Error> {
         let mut val = self.latest.lock().await;
         if let Some(v) = val.as_ref().and_then(|v| v.get(now)) {
             return Ok(v);
@@ -0,0 +1,447 @@
+use crate::poll::{MAX_LAG, fetch_page, is_behind, next_delay, rate_limited_delay};
+use crate::{
+    CaughtUp, CaughtUpReason, Dt, ExportPage, GetPageError, HttpClient, PageBoundaryState,
+    PageParsing, PollError, RateLimitHeaders, get_page, poll_upstream, stream_to_channel,
+};
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::Url;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+/// Pages each slice can fetch ahead of the merge before it waits
+const SLICE_BUFFER: usize = 64;
+
+/// Slices ask for pages of this size, and merged ops are re-paged to it, like
+/// upstream's own full pages
+const PAGE_SIZE: usize = 1000;
+
+/// A request budget shared by all slices: one request per `interval`
+///
+/// The interval follows upstream's rate-limit headers like the sequential
+/// poller does, and a 429 to any slice holds off every slice.
+struct Budget {
+    throttle: Duration,
+    state: Mutex<BudgetState>,
+}
+
+struct BudgetState {
+    interval: Duration,
+    next_at: Instant,
+}
+
+impl Budget {
+    fn new(throttle: Duration) -> Self {
+        Self {
+            throttle,
+            state: Mutex::new(BudgetState {
+                interval: throttle,
+                next_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait for our turn to make a request
+    async fn acquire(&self) {
+        let at = {
+            let mut state = self.state.lock().expect("budget lock not to be poisoned");
+            let at = state.next_at.max(Instant::now());
+            state.next_at = at + state.interval;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+
+    /// Follow upstream's hints about how much budget is left
+    fn adapt(&self, limits: &RateLimitHeaders) {
+        self.state
+            .lock()
+            .expect("budget lock not to be poisoned")
+            .interval = next_delay(self.throttle, true, limits);
+    }
+
+    /// Back everyone off after a 429
+    fn rate_limited(&self, limits: &RateLimitHeaders) -> Duration {
+        let mut state = self.state.lock().expect("budget lock not to be poisoned");
+        let wait = rate_limited_delay(state.interval, limits);
+        state.next_at = state.next_at.max(Instant::now() + wait);
+        wait
+    }
+}
+
+/// Fetch the ops after `after` and up to `until` from an upstream's /export,
+/// in concurrent time slices
+///
+/// The range is split into `slices` equal spans of time, which are fetched at
+/// the same time within one shared request budget of one request per
+/// `throttle` (or faster, if upstream's rate-limit headers allow it). Pages
+/// come out in order with duplicates removed, just like [`crate::poll_stream`],
+/// re-paged to 1000 ops each, and the stream ends at `until`.
+///
+/// Each slice buffers a limited number of pages ahead of the merge, so later
+/// slices pause if the consumer is slow to get through the early ones.
+pub fn catch_up_stream(
+    client: HttpClient,
+    base: Url,
+    after: Dt,
+    until: Dt,
+    slices: usize,
+    throttle: Duration,
+    parsing: PageParsing,
+) -> impl Stream<Item = Result<ExportPage, PollError>> {
+    let slices = slices.max(1);
+    log::info!("catching up from {base} after {after} until {until} in {slices} slices");
+
+    let spawned = futures::stream::once(async move {
+        let budget = std::sync::Arc::new(Budget::new(throttle));
+        let span = (until - after) / slices as i32;
+        let receivers: Vec<_> = (0..slices)
+            .map(|i| {
+                let start = after + span * i as i32;
+                let end = if i == slices - 1 { until } else { start + span };
+                let (tx, rx) = mpsc::channel(SLICE_BUFFER);
+                tokio::task::spawn(fetch_slice(
+                    client.clone(),
+                    base.clone(),
+                    start,
+                    end,
+                    budget.clone(),
+                    parsing,
+                    tx,
+                ));
+                rx
+            })
+            .collect();
+        futures::stream::iter(receivers).flat_map(slice_pages)
+    })
+    .flatten();
+
+    let mut boundary: Option<PageBoundaryState> = None;
+    let deduped = spawned.map_ok(move |mut page| {
+        match boundary {
+            Some(ref mut boundary) => boundary.apply_to_next(&mut page),
+            None => boundary = PageBoundaryState::new(&page),
+        }
+        page
+    });
+    full_sized(deduped)
+}
+
+/// Re-page ops into full pages, since each slice's last page is usually short
+///
+/// Only the final page can be short (and is never empty).
+fn full_sized(
+    pages: impl Stream<Item = Result<ExportPage, PollError>>,
+) -> impl Stream<Item = Result<ExportPage, PollError>> {
+    let state = (Box::pin(pages), Vec::new(), false);
+    futures::stream::unfold(state, |(mut pages, mut ops, done)| async move {
+        if done {
+            return None;
+        }
+        loop {
+            if ops.len() >= PAGE_SIZE {
+                let rest = ops.split_off(PAGE_SIZE);
+                let page = ExportPage {
+                    ops: std::mem::replace(&mut ops, rest),
+                };
+                return Some((Ok(page), (pages, ops, false)));
+            }
+            match pages.next().await {
+                Some(Ok(page)) => ops.extend(page.ops),
+                Some(Err(e)) => return Some((Err(e), (pages, ops, true))),
+                None if ops.is_empty() => return None,
+                None => {
+                    let page = ExportPage {
+                        ops: std::mem::take(&mut ops),
+                    };
+                    return Some((Ok(page), (pages, ops, true)));
+                }
+            }
+        }
+    })
+}
+
+/// Catch up with an upstream in concurrent time slices, then keep polling
+///
+/// Catches up from `after` to `until` (or now) with [`catch_up_stream`], then
+/// carries on from the last op with [`poll_upstream`] if `until` is still
+/// ahead. Pages are written to `dest`.
+#[allow(clippy::too_many_arguments)]
+pub async fn catch_up_upstream(
+    client: HttpClient,
+    after: Dt,
+    until: Option<Dt>,
+    base: Url,
+    slices: usize,
+    throttle: Duration,
+    parsing: PageParsing,
+    dest: mpsc::Sender<ExportPage>,
+) -> Result<&'static str, PollError> {
+    let mut last_at = after;
+    let now = chrono::Utc::now();
+    let end = until.map_or(now, |until| until.min(now));
+    let pages = catch_up_stream(
+        client.clone(),
+        base.clone(),
+        after,
+        end,
+        slices,
+        throttle,
+        parsing,
+    )
+    .inspect_ok(|page| {
+        if let Some(op) = page.ops.last() {
+            last_at = last_at.max(op.created_at);
+        }
+    });
+    stream_to_channel(pages, dest.clone()).await?;
+    if dest.is_closed() {
+        return Ok("catch_up_upstream (destination done)");
+    }
+    if until.is_some_and(|until| until <= end) {
+        log::info!("sliced catch-up reached {end}, not polling further");
+        return Ok("catch_up_upstream (until reached)");
+    }
+    log::info!("sliced catch-up done at {last_at}, continuing with the poller");
+    poll_upstream(client, Some(last_at), base, throttle, parsing, dest).await
+}
+
+/// One slice's pages, with an error if its task died without finishing
+fn slice_pages(
+    rx: mpsc::Receiver<Result<Option<ExportPage>, PollError>>,
+) -> impl Stream<Item = Result<ExportPage, PollError>> {
+    futures::stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        match rx.recv().await {
+            Some(Ok(Some(page))) => Some((Ok(page), Some(rx))),
+            Some(Ok(None)) => None,
+            Some(Err(e)) => Some((Err(e), None)),
+            None => Some((Err(PollError::SliceStopped), None)),
+        }
+    })
+}
+
+/// Page through /export for ops after `start` and up to `end`
+///
+/// Sends `Ok(None)` when the slice is done, so the merge can tell a finished
+/// slice from one whose task died.
+async fn fetch_slice(
+    client: HttpClient,
+    base: Url,
+    start: Dt,
+    end: Dt,
+    budget: std::sync::Arc<Budget>,
+    parsing: PageParsing,
+    tx: mpsc::Sender<Result<Option<ExportPage>, PollError>>,
+) {
+    let mut cursor = start;
+    loop {
+        budget.acquire().await;
+        let mut url = base.clone();
+        url.query_pairs_mut()
+            .append_pair("count", &PAGE_SIZE.to_string())
+            .append_pair("after", &cursor.to_rfc3339());
+        let source = url.to_string();
+
+        let mut page = match fetch_page(client.get_paced(url), &source, parsing).await {
+            Ok((page, _, limits)) => {
+                budget.adapt(&limits);
+                page
+            }
+            Err(GetPageError::Truncated { page, limits }) => {
+                log::warn!("slice page was cut off after {} ops", page.ops.len());
+                budget.adapt(&limits);
+                page
+            }
+            Err(GetPageError::RateLimited { limits, .. }) => {
+                let wait = budget.rate_limited(&limits);
+                log::warn!("rate limited by upstream, holding off all slices for {wait:?}");
+                continue;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e.into())).await;
+                return;
+            }
+        };
+
+        let Some(last_at) = page.ops.last().map(|op| op.created_at) else {
+            break; // nothing more upstream
+        };
+        let full = page.ops.len() >= PAGE_SIZE;
+        page.ops.retain(|op| op.created_at <= end);
+        if !page.ops.is_empty() && tx.send(Ok(Some(page))).await.is_err() {
+            return; // nobody's listening anymore
+        }
+        if last_at >= end {
+            break;
+        }
+        if last_at <= cursor {
+            if full {
+                // there's more, but `after` can't get past it
+                let _ = tx.send(Err(PollError::SliceStuck { at: cursor })).await;
+                return;
+            }
+            break;
+        }
+        cursor = last_at;
+    }
+    log::trace!("slice after {start} up to {end} done");
+    let _ = tx.send(Ok(None)).await;
+}
+
+/// When to consider a poller caught up with its upstream
+#[derive(Debug, Clone)]
+pub struct CatchUpTarget {
+    pub client: HttpClient,
+    /// upstream's /export, to ask for anything newer than our latest op
+    pub upstream: Url,
+    /// where polling started: ops at or before it are dropped
+    ///
+    /// Without it, upstream's head isn't checked until the first page arrives.
+    pub after: Option<Dt>,
+    /// stop here instead of at upstream's head: later ops are dropped
+    pub until: Option<Dt>,
+    /// whether reaching upstream's head counts as caught up
+    ///
+    /// Without it, only `until` stops: either an op at or after it, or
+    /// upstream having nothing newer once it has passed.
+    pub stop_at_head: bool,
+    /// once the latest op is this recent, check whether upstream has anything newer
+    pub max_lag: Duration,
+    /// also check upstream's head if no page shows up for this long, and
+    /// never check more often than this
+    pub idle: Duration,
+}
+
+impl CatchUpTarget {
+    pub fn new(client: HttpClient, upstream: Url) -> Self {
+        Self {
+            client,
+            upstream,
+            after: None,
+            until: None,
+            stop_at_head: true,
+            max_lag: MAX_LAG,
+            idle: Duration::from_secs(10),
+        }
+    }
+
+    /// Whether upstream has no ops after `last_at`
+    ///
+    /// Errors count as "not sure", so we keep going.
+    async fn at_head(&self, last_at: Option<Dt>) -> bool {
+        let mut url = self.upstream.clone();
+        url.query_pairs_mut().append_pair("count", "1");
+        if let Some(at) = last_at {
+            url.query_pairs_mut().append_pair("after", &at.to_rfc3339());
+        }
+        match get_page(&self.client, url).await {
+            // some upstreams repeat ops at the `after` time
+            Ok((page, _)) => !page.ops.iter().any(|op| Some(op.created_at) > last_at),
+            Err(e) => {
+                log::warn!("failed to check upstream's head, assuming there's more: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// Forward pages until caught up with upstream (or the `until` target), then stop
+///
+/// Caught up means either an op at or after `until` showed up (it and later
+/// ops are dropped), or upstream had nothing newer than our latest op. That's
+/// checked once the latest op is within `max_lag` of now, or if pages stop
+/// showing up for a while. With `stop_at_head` off, only `until` counts.
+///
+/// A source that ends once `until` has passed (like [`catch_up_upstream`]
+/// with an `until`) also counts as reaching it.
+///
+/// `notify` hears about it when caught up. Dropping the channels afterwards
+/// lets the poller upstream of this stop too.
+pub async fn caught_up(
+    mut rx: mpsc::Receiver<ExportPage>,
+    tx: mpsc::Sender<ExportPage>,
+    target: CatchUpTarget,
+    notify: Option<oneshot::Sender<CaughtUp>>,
+) -> Result<&'static str, PollError> {
+    let mut last_at = target.after;
+    let mut last_check: Option<Instant> = None;
+    let reason = loop {
+        let recv = async {
+            if last_at.is_some() {
+                tokio::time::timeout(target.idle, rx.recv()).await.ok()
+            } else {
+                Some(rx.recv().await)
+            }
+        };
+        // pages can stop for a long time, so watch for the receiver going away
+        let next = tokio::select! {
+            next = recv => next,
+            () = tx.closed() => return Ok("caught_up (destination done)"),
+        };
+        let checked_recently = last_check.is_some_and(|t| t.elapsed() < target.idle);
+        let until_passed = target
+            .until
+            .is_some_and(|until| chrono::Utc::now() >= until);
+        let Some(next) = next else {
+            // quiet for a while: maybe there's nothing more
+            if !target.stop_at_head && !until_passed {
+                continue;
+            }
+            last_check = Some(Instant::now());
+            if target.at_head(last_at).await {
+                break if until_passed {
+                    CaughtUpReason::Until
+                } else {
+                    CaughtUpReason::Head
+                };
+            }
+            continue;
+        };
+        let Some(mut page) = next else {
+            if until_passed {
+                break CaughtUpReason::Until;
+            }
+            return Err(PollError::SourceClosed);
+        };
+
+        // some upstreams include ops at `after` itself
+        if let Some(after) = target.after {
+            page.ops.retain(|op| op.created_at > after);
+        }
+        let mut reached = false;
+        if let Some(until) = target.until {
+            let n = page.ops.len();
+            page.ops.retain(|op| op.created_at < until);
+            reached = page.ops.len() < n;
+        }
+        if let Some(op) = page.ops.last() {
+            last_at = last_at.max(Some(op.created_at));
+        }
+        if !page.is_empty() && tx.send(page).await.is_err() {
+            return Ok("caught_up (destination done)");
+        }
+        if reached {
+            break CaughtUpReason::Until;
+        }
+
+        let recent = last_at.is_some_and(|at| !is_behind(at, target.max_lag));
+        if target.stop_at_head && recent && !checked_recently {
+            last_check = Some(Instant::now());
+            if target.at_head(last_at).await {
+                break CaughtUpReason::Head;
+            }
+        }
+    };
+
+    log::info!("caught up ({reason:?}), latest op at {last_at:?}");
+    if let Some(notify) = notify
+        && notify.send(CaughtUp { last_at, reason }).is_err()
+    {
+        log::warn!("nobody was listening for caught up");
+    }
+    Ok("caught_up")
+}
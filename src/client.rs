@@ -1,7 +1,14 @@
-use reqwest::Client;
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
-use std::sync::LazyLock;
+use reqwest::{
+    Certificate, Client, Proxy, Response, StatusCode, Url,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, RequestBuilder};
+use reqwest_retry::{
+    DefaultRetryableStrategy, RetryTransientMiddleware, Retryable, RetryableStrategy,
+    policies::ExponentialBackoff,
+};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+use thiserror::Error;
 
 pub const UA: &str = concat!(
     "allegedly, v",
@@ -9,16 +16,228 @@ pub const UA: &str = concat!(
     " (from @microcosm.blue; contact @bad-example.com)"
 );
 
-pub static CLIENT: LazyLock<ClientWithMiddleware> = LazyLock::new(|| {
-    let inner = Client::builder()
-        .user_agent(UA)
-        .gzip(true)
-        .build()
-        .expect("reqwest client to build");
+/// The user-agent, with someone else's contact info if given
+pub fn user_agent(contact: Option<&str>) -> String {
+    match contact {
+        Some(contact) => format!(
+            "allegedly, v{} (from @microcosm.blue; contact {contact})",
+            env!("CARGO_PKG_VERSION")
+        ),
+        None => UA.to_string(),
+    }
+}
 
-    let policy = ExponentialBackoff::builder().build_with_max_retries(12);
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("failed to read root cert {path:?}: {source}")]
+    CertRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error("expected an upstream header like `host=Name: value`, got {0:?}")]
+    BadUpstreamHeader(String),
+    #[error("shortest retry wait {min:?} is longer than the longest {max:?}")]
+    RetryBounds { min: Duration, max: Duration },
+}
 
-    ClientBuilder::new(inner)
-        .with(RetryTransientMiddleware::new_with_policy(policy))
-        .build()
-});
+/// A header to send with every request to one upstream host, like an
+/// `Authorization` for a private mirror
+#[derive(Debug, Clone)]
+pub struct UpstreamHeader {
+    pub host: String,
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for UpstreamHeader {
+    type Err = ClientError;
+    /// `host=Name: value`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || ClientError::BadUpstreamHeader(s.to_string());
+        let (host, header) = s.split_once('=').ok_or_else(bad)?;
+        let (name, value) = header.split_once(':').ok_or_else(bad)?;
+        let mut value = HeaderValue::from_str(value.trim()).map_err(|_| bad())?;
+        value.set_sensitive(true);
+        Ok(Self {
+            host: host.trim().to_string(),
+            name: name.trim().parse().map_err(|_| bad())?,
+            value,
+        })
+    }
+}
+
+/// Settings for the http client that talks to upstreams and bundle hosts
+#[derive(Debug, Clone)]
+pub struct ClientConf {
+    /// contact info for the user-agent, so upstream operators can reach you
+    pub contact: Option<String>,
+    /// limit for a whole request, including reading the body
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// how many times to retry transient failures, with exponential backoff
+    pub max_retries: u32,
+    /// backoff between retries starts at `min` and is capped at `max`
+    pub retry_bounds: (Duration, Duration),
+    pub proxy: Option<Url>,
+    /// extra PEM root certificates to trust
+    pub root_certs: Vec<PathBuf>,
+    pub upstream_headers: Vec<UpstreamHeader>,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            contact: None,
+            timeout: None,
+            connect_timeout: None,
+            max_retries: 12,
+            retry_bounds: (Duration::from_secs(1), Duration::from_secs(30 * 60)),
+            proxy: None,
+            root_certs: vec![],
+            upstream_headers: vec![],
+        }
+    }
+}
+
+impl ClientConf {
+    pub fn build(&self) -> Result<HttpClient, ClientError> {
+        let mut builder = Client::builder()
+            .user_agent(user_agent(self.contact.as_deref()))
+            .gzip(true);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(ref proxy) = self.proxy {
+            builder = builder.proxy(Proxy::all(proxy.clone())?);
+        }
+        for path in &self.root_certs {
+            let pem = std::fs::read(path).map_err(|source| ClientError::CertRead {
+                path: path.clone(),
+                source,
+            })?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        let (min, max) = self.retry_bounds;
+        if min > max {
+            return Err(ClientError::RetryBounds { min, max });
+        }
+        let policy = ExponentialBackoff::builder()
+            .retry_bounds(min, max)
+            .build_with_max_retries(self.max_retries);
+        let inner = builder.build()?;
+        let client = ClientBuilder::new(inner.clone())
+            .with(RetryTransientMiddleware::new_with_policy(policy))
+            .build();
+        let paced = ClientBuilder::new(inner)
+            .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+                policy,
+                NotRateLimits,
+            ))
+            .build();
+        Ok(HttpClient {
+            client,
+            paced,
+            upstream_headers: Arc::new(self.upstream_headers.clone()),
+        })
+    }
+}
+
+/// An http client for upstreams, with retries and per-upstream headers
+///
+/// Cheap to clone: clones share a connection pool.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: ClientWithMiddleware,
+    /// same connection pool, but 429s come back to the caller
+    paced: ClientWithMiddleware,
+    upstream_headers: Arc<Vec<UpstreamHeader>>,
+}
+
+impl HttpClient {
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        let headers = self.headers_for(&url);
+        self.client.get(url).headers(headers)
+    }
+
+    /// Like [`HttpClient::get`], but a `429 Too Many Requests` is returned
+    /// instead of retried, for callers that pace themselves
+    pub fn get_paced(&self, url: Url) -> RequestBuilder {
+        let headers = self.headers_for(&url);
+        self.paced.get(url).headers(headers)
+    }
+
+    /// A POST with the same transient-failure retries as [`HttpClient::get`]
+    pub fn post(&self, url: Url) -> RequestBuilder {
+        let headers = self.headers_for(&url);
+        self.client.post(url).headers(headers)
+    }
+
+    fn headers_for(&self, url: &Url) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for h in self.upstream_headers.iter() {
+            if url.host_str() == Some(h.host.as_str()) {
+                headers.insert(h.name.clone(), h.value.clone());
+            }
+        }
+        headers
+    }
+}
+
+/// Retry transient failures, except rate limits
+struct NotRateLimits;
+
+impl RetryableStrategy for NotRateLimits {
+    fn handle(&self, res: &Result<Response, reqwest_middleware::Error>) -> Option<Retryable> {
+        match res {
+            Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => Some(Retryable::Fatal),
+            res => DefaultRetryableStrategy.handle(res),
+        }
+    }
+}
+
+impl Default for HttpClient {
+    /// The default settings: allegedly's own contact info, and 12 retries
+    fn default() -> Self {
+        ClientConf::default()
+            .build()
+            .expect("default http client to build")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upstream_header() {
+        let h: UpstreamHeader = "plc.example.com=Authorization: Bearer abc:123"
+            .parse()
+            .unwrap();
+        assert_eq!(h.host, "plc.example.com");
+        assert_eq!(h.name, "authorization");
+        assert_eq!(h.value, "Bearer abc:123");
+
+        assert!("no-header.example.com".parse::<UpstreamHeader>().is_err());
+        assert!("host=no colon".parse::<UpstreamHeader>().is_err());
+    }
+
+    #[test]
+    fn test_retry_bounds() {
+        let conf = |min, max| ClientConf {
+            retry_bounds: (Duration::from_millis(min), Duration::from_millis(max)),
+            ..Default::default()
+        };
+        assert!(conf(100, 500).build().is_ok());
+        assert!(conf(500, 500).build().is_ok());
+        assert!(matches!(
+            conf(1000, 500).build(),
+            Err(ClientError::RetryBounds { .. })
+        ));
+    }
+}
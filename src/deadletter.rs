@@ -0,0 +1,319 @@
+//! Somewhere to keep lines that failed to parse as ops
+//!
+//! Like the metrics counters, the dead-letter sink is process-wide: set it up
+//! once with [`set_dead_letters`]. Without one, failures are only logged.
+//!
+//! Unlike the http client, which callers configure per upstream and pass
+//! along, this is global on purpose. Lines fail to parse deep inside
+//! `get_page`, `poll_stream`, the sliced catch-up and `week_stream`, so
+//! passing a sink would add a parameter to every one of those entry points,
+//! and to every `BundleSource` (a `FolderSource` has no client to hang it
+//! on). A process only ever has one place to keep its dead letters, and
+//! nothing reads them back, so a write-only global set at startup doesn't
+//! leak state between callers.
+
+use crate::{Dt, metrics};
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use thiserror::Error;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+#[cfg(feature = "postgres")]
+use tokio::sync::{mpsc, oneshot};
+
+/// A line we couldn't ingest, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub at: Dt,
+    /// the page url or bundle week it came from
+    pub source: String,
+    pub error: String,
+    pub raw: String,
+}
+
+impl DeadLetter {
+    pub fn new(source: &str, raw: &[u8], error: impl Display) -> Self {
+        Self {
+            at: chrono::Utc::now(),
+            source: source.to_string(),
+            error: error.to_string(),
+            raw: String::from_utf8_lossy(raw).into_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RejectError {
+    #[error("strict mode: failed to parse an op from {}: {} ({})", .0.source, .0.error, .0.raw)]
+    Strict(Box<DeadLetter>),
+    #[error("failed to record a dead letter from {from}: {error}")]
+    Record { from: String, error: String },
+}
+
+#[derive(Debug, Error)]
+pub enum DeadLetterError {
+    #[error("dead letters were already set up")]
+    AlreadySet,
+    #[error("failed to open dead-letter file {path:?}: {source}")]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[cfg(feature = "postgres")]
+    #[error(transparent)]
+    Pg(#[from] tokio_postgres::Error),
+}
+
+/// Where dead letters are kept
+#[derive(Clone)]
+pub enum DeadLetterSink {
+    /// appended as json lines
+    File(PathBuf),
+    /// rows in an `allegedly_dead_letters` table (created if missing)
+    #[cfg(feature = "postgres")]
+    Postgres(crate::Db),
+}
+
+/// a row for the postgres writer, and where to say how inserting it went
+#[cfg(feature = "postgres")]
+type PgInsert = (DeadLetter, oneshot::Sender<Result<(), String>>);
+
+enum Writer {
+    File(Mutex<File>),
+    #[cfg(feature = "postgres")]
+    Postgres(mpsc::UnboundedSender<PgInsert>),
+}
+
+impl Writer {
+    /// Returns once the letter is written
+    async fn write(&self, letter: &DeadLetter) -> Result<(), String> {
+        match self {
+            Writer::File(file) => {
+                let mut line = serde_json::to_string(letter).map_err(|e| e.to_string())?;
+                line.push('\n');
+                let mut file = file.lock().await;
+                file.write_all(line.as_bytes())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                // tokio writes in the background: make sure it's down before
+                // strict mode aborts
+                file.flush().await.map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "postgres")]
+            Writer::Postgres(tx) => {
+                let gone = || "the postgres writer is gone".to_string();
+                let (done, inserted) = oneshot::channel();
+                tx.send((letter.clone(), done)).map_err(|_| gone())?;
+                inserted.await.map_err(|_| gone())?
+            }
+        }
+    }
+}
+
+struct DeadLetters {
+    writer: Option<Writer>,
+    strict: bool,
+}
+
+impl DeadLetters {
+    async fn reject(&self, letter: DeadLetter) -> Result<(), RejectError> {
+        if let Some(ref writer) = self.writer {
+            writer
+                .write(&letter)
+                .await
+                .map_err(|error| RejectError::Record {
+                    from: letter.source.clone(),
+                    error,
+                })?;
+        }
+        if self.strict {
+            return Err(RejectError::Strict(Box::new(letter)));
+        }
+        Ok(())
+    }
+}
+
+static DEAD_LETTERS: OnceLock<DeadLetters> = OnceLock::new();
+
+/// Set up the process-wide dead-letter sink
+///
+/// With `strict`, parse failures are errors that abort whatever was reading,
+/// after the line is recorded.
+pub async fn set_dead_letters(
+    sink: Option<DeadLetterSink>,
+    strict: bool,
+) -> Result<(), DeadLetterError> {
+    let writer = match sink {
+        None => None,
+        Some(DeadLetterSink::File(path)) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+                .map_err(|source| DeadLetterError::Open { path, source })?;
+            Some(Writer::File(Mutex::new(file)))
+        }
+        #[cfg(feature = "postgres")]
+        Some(DeadLetterSink::Postgres(db)) => Some(Writer::Postgres(pg_writer(db).await?)),
+    };
+    DEAD_LETTERS
+        .set(DeadLetters { writer, strict })
+        .map_err(|_| DeadLetterError::AlreadySet)
+}
+
+/// Record a line that failed to parse
+///
+/// Errors if the letter couldn't be recorded, and in strict mode, once it's
+/// been recorded.
+pub async fn reject(letter: DeadLetter) -> Result<(), RejectError> {
+    metrics::DEAD_LETTERS.inc();
+    log::warn!(
+        "failed to parse op from {}: {} ({})",
+        letter.source,
+        letter.error,
+        letter.raw
+    );
+    match DEAD_LETTERS.get() {
+        Some(dead_letters) => dead_letters.reject(letter).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "postgres")]
+async fn pg_writer(db: crate::Db) -> Result<mpsc::UnboundedSender<PgInsert>, DeadLetterError> {
+    let (client, conn_task) = db.connect().await?;
+    client
+        .execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS allegedly_dead_letters (
+                id BIGSERIAL PRIMARY KEY,
+                "at" TIMESTAMPTZ NOT NULL,
+                source TEXT NOT NULL,
+                error TEXT NOT NULL,
+                raw TEXT NOT NULL
+            )"#,
+            &[],
+        )
+        .await?;
+    let insert = client
+        .prepare(
+            r#"INSERT INTO allegedly_dead_letters ("at", source, error, raw)
+               VALUES ($1, $2, $3, $4)"#,
+        )
+        .await?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<PgInsert>();
+    tokio::task::spawn(async move {
+        while let Some((l, done)) = rx.recv().await {
+            let res = client
+                .execute(&insert, &[&l.at, &l.source, &l.error, &l.raw])
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("failed to insert dead letter: {e}"));
+            let _ = done.send(res);
+        }
+        drop(client);
+        let _ = conn_task.await;
+    });
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn letter(raw: &str) -> DeadLetter {
+        DeadLetter::new("https://plc.example.com/export", raw.as_bytes(), "nope")
+    }
+
+    async fn file_writer(name: &str) -> (PathBuf, Writer) {
+        let path = std::env::temp_dir().join(format!("{name}-{}.jsonl", std::process::id()));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        (path, Writer::File(Mutex::new(file)))
+    }
+
+    fn raws(path: &PathBuf) -> Vec<String> {
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        written
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["raw"].to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_file_writer() {
+        let (path, writer) = file_writer("dead-letters").await;
+        for raw in ["{not json", "{\"did\":1}"] {
+            writer.write(&letter(raw)).await.unwrap();
+        }
+        assert_eq!(raws(&path), vec![r#""{not json""#, r#""{\"did\":1}""#]);
+    }
+
+    #[tokio::test]
+    async fn test_strict_records_first() {
+        let (path, writer) = file_writer("dead-letters-strict").await;
+        let dead_letters = DeadLetters {
+            writer: Some(writer),
+            strict: true,
+        };
+        let res = dead_letters.reject(letter("{not json")).await;
+        assert!(matches!(res, Err(RejectError::Strict(_))));
+        assert_eq!(raws(&path), vec![r#""{not json""#]);
+    }
+
+    /// a stand-in for the postgres writer task, answering with `res`
+    #[cfg(feature = "postgres")]
+    fn fake_pg(res: Result<(), String>) -> (Writer, std::sync::Arc<std::sync::atomic::AtomicBool>) {
+        use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
+        let inserted = Arc::new(AtomicBool::new(false));
+        let (tx, mut rx) = mpsc::unbounded_channel::<PgInsert>();
+        let flag = inserted.clone();
+        tokio::task::spawn(async move {
+            while let Some((_, done)) = rx.recv().await {
+                // slower than the caller, if it didn't wait
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                flag.store(res.is_ok(), Ordering::SeqCst);
+                let _ = done.send(res.clone());
+            }
+        });
+        (Writer::Postgres(tx), inserted)
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_pg_strict_waits_for_insert() {
+        use std::sync::atomic::Ordering;
+        let (writer, inserted) = fake_pg(Ok(()));
+        let dead_letters = DeadLetters {
+            writer: Some(writer),
+            strict: true,
+        };
+        let res = dead_letters.reject(letter("{not json")).await;
+        assert!(matches!(res, Err(RejectError::Strict(_))));
+        assert!(inserted.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_pg_insert_failure_surfaces() {
+        let (writer, _) = fake_pg(Err("no space left".to_string()));
+        let dead_letters = DeadLetters {
+            writer: Some(writer),
+            strict: false,
+        };
+        match dead_letters.reject(letter("{not json")).await {
+            Err(RejectError::Record { error, .. }) => assert_eq!(error, "no space left"),
+            other => panic!("expected a record error, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    BundleSource, Db, Dt, ExportPage, FolderSource, GetPageError, HttpClient, HttpSource, Op, Week,
+    get_page, week_to_pages,
+};
+use futures::TryStreamExt;
+use reqwest::Url;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::LinesStream;
+
+/// Somewhere to read a range of PLC ops from
+#[derive(Clone)]
+pub enum OpSource {
+    /// a PLC server's `/export` endpoint
+    Export(Url, HttpClient),
+    /// weekly bundles in a local folder
+    Folder(FolderSource),
+    /// weekly bundles under an http prefix
+    Http(HttpSource),
+    /// a did-method-plc postgres database
+    Db(Db),
+    /// a file of ops, one json object per line
+    Jsonl(PathBuf),
+}
+
+impl OpSource {
+    /// Figure out a source from a cli argument
+    ///
+    /// - `postgres://...` or `postgresql://...`: a database
+    /// - `http(s)://.../export`: an upstream PLC server
+    /// - other `http(s)://...`: a bundle prefix
+    /// - a file: ops as jsonl
+    /// - anything else: a bundle folder
+    pub async fn from_arg(
+        arg: &str,
+        pg_cert: Option<PathBuf>,
+        client: &HttpClient,
+    ) -> anyhow::Result<Self> {
+        if arg.starts_with("postgres://") || arg.starts_with("postgresql://") {
+            return Ok(Self::Db(Db::new(arg, pg_cert).await?));
+        }
+        if arg.starts_with("http://") || arg.starts_with("https://") {
+            let url: Url = arg.parse()?;
+            if url.path().trim_end_matches('/').ends_with("/export") {
+                return Ok(Self::Export(url, client.clone()));
+            }
+            return Ok(Self::Http(HttpSource(url, client.clone())));
+        }
+        let path = PathBuf::from(arg);
+        if path.is_file() {
+            return Ok(Self::Jsonl(path));
+        }
+        Ok(Self::Folder(FolderSource(path)))
+    }
+}
+
+/// Send a source's ops after `after` and before `until` to `dest`
+pub async fn source_to_pages(
+    source: OpSource,
+    after: Dt,
+    until: Dt,
+    throttle: Duration,
+    dest: mpsc::Sender<ExportPage>,
+) -> anyhow::Result<&'static str> {
+    match source {
+        OpSource::Export(url, client) => {
+            export_to_pages(client, url, after, until, throttle, dest).await
+        }
+        OpSource::Folder(source) => bundles_to_pages(source, after, until, dest).await,
+        OpSource::Http(source) => bundles_to_pages(source, after, until, dest).await,
+        OpSource::Db(db) => {
+            let mut chunks = pin!(
+                db.ops_between(Some(after), Some(until))
+                    .await?
+                    .try_chunks(1000)
+            );
+            while let Some(ops) = chunks.try_next().await.map_err(|e| e.1)? {
+                dest.send(ExportPage { ops }).await?;
+            }
+            Ok("db ops")
+        }
+        OpSource::Jsonl(path) => {
+            let lines = BufReader::new(File::open(path).await?).lines();
+            let mut chunks = pin!(LinesStream::new(lines).try_chunks(1000));
+            while let Some(lines) = chunks.try_next().await.map_err(|e| e.1)? {
+                let ops = lines
+                    .iter()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| serde_json::from_str::<Op>(line))
+                    .filter(|op| {
+                        op.as_ref()
+                            .map_or(true, |op| op.created_at > after && op.created_at < until)
+                    })
+                    .collect::<Result<_, _>>()?;
+                dest.send(ExportPage { ops }).await?;
+            }
+            Ok("jsonl ops")
+        }
+    }
+}
+
+/// Ask /export for pages this big, so a short page means there's no more
+const EXPORT_PAGE: usize = 1000;
+
+/// Page through /export until reaching `until`
+///
+/// unlike `poll_upstream`, this stops as soon as there's nothing new. Fails
+/// if a full page can't get past `after` (more ops at one timestamp than
+/// fit in a page), rather than leaving the rest of the range out.
+async fn export_to_pages(
+    client: HttpClient,
+    base: Url,
+    mut after: Dt,
+    until: Dt,
+    throttle: Duration,
+    dest: mpsc::Sender<ExportPage>,
+) -> anyhow::Result<&'static str> {
+    let mut tick = tokio::time::interval(throttle);
+    loop {
+        tick.tick().await;
+        let mut url = base.clone();
+        url.query_pairs_mut()
+            .append_pair("count", &EXPORT_PAGE.to_string())
+            .append_pair("after", &after.to_rfc3339());
+        let (mut page, last) = match get_page(&client, url).await {
+            Ok(got) => got,
+            Err(GetPageError::Truncated { page, .. }) => {
+                // keep the complete ops: the next request picks up after them
+                log::warn!("export page was cut off after {} ops", page.ops.len());
+                let last = page.ops.last().map(Into::into);
+                (page, last)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Some(last) = last else {
+            return Ok("export ops (caught up)");
+        };
+        let full = page.ops.len() >= EXPORT_PAGE;
+        page.ops.retain(|op| op.created_at < until);
+        dest.send(page).await?;
+        if last.created_at >= until {
+            return Ok("export ops (reached until)");
+        }
+        if last.created_at <= after {
+            if full {
+                anyhow::bail!("export is stuck at {after}: a full page of ops at one timestamp");
+            }
+            // only repeats of ops at `after`
+            return Ok("export ops (caught up)");
+        }
+        after = last.created_at;
+    }
+}
+
+async fn bundles_to_pages(
+    source: impl BundleSource + Send + 'static,
+    after: Dt,
+    until: Dt,
+    dest: mpsc::Sender<ExportPage>,
+) -> anyhow::Result<&'static str> {
+    let (tx, mut rx) = mpsc::channel(2);
+    let weeks = Week::range(Week::from(after), Bound::Included(Week::from(until)));
+    let reader = tokio::task::spawn(async move {
+        for week in weeks {
+            match week_to_pages(source.clone(), week, tx.clone()).await {
+                Ok(()) => {}
+                // recent weeks might not be bundled yet
+                Err(e) if !week.is_immutable() => {
+                    log::warn!("stopping at a recent week without a bundle: {e}");
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    });
+    while let Some(mut page) = rx.recv().await {
+        page.ops
+            .retain(|op| op.created_at > after && op.created_at < until);
+        dest.send(page).await?;
+    }
+    reader.await??;
+    Ok("bundle ops")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffKind {
+    /// only the right side has this op
+    MissingLeft,
+    /// only the left side has this op
+    MissingRight,
+    NullifiedDiffers,
+    OperationDiffers,
+}
+
+/// One difference between two logs, for one op
+#[derive(Debug, Serialize)]
+pub struct OpDiff {
+    pub kind: DiffKind,
+    pub did: String,
+    pub cid: String,
+    pub created_at: Dt,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub left: Option<Op>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub right: Option<Op>,
+}
+
+fn same_operation(a: &Op, b: &Op) -> bool {
+    if a.operation.get() == b.operation.get() {
+        return true;
+    }
+    // postgres jsonb reorders keys and whitespace, so compare values
+    let parse = |op: &Op| serde_json::from_str::<serde_json::Value>(op.operation.get()).ok();
+    parse(a) == parse(b)
+}
+
+/// Ops keyed in the order they're compared: `created_at`, then did, cid
+type Ordered = BTreeMap<(Dt, String, String), Op>;
+
+/// Compare two sets of ops, ordered by `created_at` (then did, cid)
+pub fn diff_ops(left: Ordered, right: Ordered) -> Vec<OpDiff> {
+    let entry = |kind, op: &Op, left: Option<Op>, right: Option<Op>| OpDiff {
+        kind,
+        did: op.did.clone(),
+        cid: op.cid.clone(),
+        created_at: op.created_at,
+        left,
+        right,
+    };
+    let mut diffs = vec![];
+    let mut left = left.into_values().peekable();
+    let mut right = right.into_values().peekable();
+    let key = |op: &Op| (op.created_at, op.did.clone(), op.cid.clone());
+    loop {
+        let order = match (left.peek(), right.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(r)) => key(l).cmp(&key(r)),
+        };
+        match order {
+            Ordering::Less => {
+                let l = left.next().unwrap();
+                diffs.push(entry(DiffKind::MissingRight, &l, Some(l.clone()), None));
+            }
+            Ordering::Greater => {
+                let r = right.next().unwrap();
+                diffs.push(entry(DiffKind::MissingLeft, &r, None, Some(r.clone())));
+            }
+            Ordering::Equal => {
+                let (l, r) = (left.next().unwrap(), right.next().unwrap());
+                if l.nullified != r.nullified {
+                    let d = entry(
+                        DiffKind::NullifiedDiffers,
+                        &l,
+                        Some(l.clone()),
+                        Some(r.clone()),
+                    );
+                    diffs.push(d);
+                }
+                if !same_operation(&l, &r) {
+                    let d = entry(
+                        DiffKind::OperationDiffers,
+                        &l,
+                        Some(l.clone()),
+                        Some(r.clone()),
+                    );
+                    diffs.push(d);
+                }
+            }
+        }
+    }
+    diffs
+}
+
+/// One side of a streaming diff
+struct DiffSide {
+    rx: mpsc::Receiver<ExportPage>,
+    /// received but not compared yet
+    pending: Ordered,
+    /// the latest op time received: more ops might still come at it
+    frontier: Option<Dt>,
+    done: bool,
+}
+
+impl DiffSide {
+    fn new(rx: mpsc::Receiver<ExportPage>) -> Self {
+        Self {
+            rx,
+            pending: Default::default(),
+            frontier: None,
+            done: false,
+        }
+    }
+
+    async fn pull(&mut self, compared: Option<Dt>, name: &str) {
+        let Some(page) = self.rx.recv().await else {
+            self.done = true;
+            return;
+        };
+        for op in page.ops {
+            if compared.is_some_and(|c| op.created_at < c) {
+                log::warn!(
+                    "{name} op {} at {} arrived after that time was compared, and may show as a difference",
+                    op.cid,
+                    op.created_at,
+                );
+            }
+            self.frontier = self.frontier.max(Some(op.created_at));
+            self.pending
+                .insert((op.created_at, op.did.clone(), op.cid.clone()), op);
+        }
+    }
+
+    /// Take the pending ops before `before`, or all of them
+    fn take_before(&mut self, before: Option<Dt>) -> Ordered {
+        match before {
+            Some(at) => {
+                let later = self.pending.split_off(&(at, String::new(), String::new()));
+                std::mem::replace(&mut self.pending, later)
+            }
+            None => std::mem::take(&mut self.pending),
+        }
+    }
+}
+
+/// Diff two sources of pages in `created_at` order, without holding either
+/// one whole
+///
+/// Once both sides have moved past a time, the ops before it are compared and
+/// dropped, so memory stays around a few pages per side. Differences are sent
+/// to `dest` in `created_at` (then did, cid) order.
+pub async fn diff_pages(
+    left: mpsc::Receiver<ExportPage>,
+    right: mpsc::Receiver<ExportPage>,
+    dest: mpsc::Sender<OpDiff>,
+) -> anyhow::Result<&'static str> {
+    let mut left = DiffSide::new(left);
+    let mut right = DiffSide::new(right);
+    let mut compared: Option<Dt> = None;
+    loop {
+        // pull from whichever side is behind
+        match (left.done, right.done) {
+            (true, true) => break,
+            (false, true) => left.pull(compared, "left").await,
+            (true, false) => right.pull(compared, "right").await,
+            (false, false) if left.frontier <= right.frontier => left.pull(compared, "left").await,
+            (false, false) => right.pull(compared, "right").await,
+        }
+        // everything before the slower side's frontier is complete on both
+        let before = match (left.done, right.done) {
+            (true, true) => None,
+            (false, true) => left.frontier,
+            (true, false) => right.frontier,
+            (false, false) => left.frontier.min(right.frontier),
+        };
+        if before.is_none() && !(left.done && right.done) {
+            continue;
+        }
+        compared = compared.max(before);
+        let diffs = diff_ops(left.take_before(before), right.take_before(before));
+        for diff in diffs {
+            if dest.send(diff).await.is_err() {
+                return Ok("diff_pages (destination done)");
+            }
+        }
+    }
+    Ok("diff_pages")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn op(did: &str, nullified: bool, operation: serde_json::Value) -> Op {
+        serde_json::from_value(serde_json::json!({
+            "did": did,
+            "cid": "cid",
+            "createdAt": "2025-01-01T00:00:00Z",
+            "nullified": nullified,
+            "operation": operation,
+        }))
+        .unwrap()
+    }
+
+    fn ops(ops: Vec<Op>) -> Ordered {
+        ops.into_iter()
+            .map(|op| ((op.created_at, op.did.clone(), op.cid.clone()), op))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_ops() {
+        let left = ops(vec![
+            op("same", false, json!({"a": 1, "b": 2})),
+            op("only-left", false, json!({})),
+            op("nullified", false, json!({})),
+            op("changed", false, json!({"a": 1})),
+        ]);
+        let mut reordered = op("same", false, json!({}));
+        reordered.operation =
+            serde_json::value::RawValue::from_string(r#"{ "b": 2, "a": 1 }"#.into()).unwrap();
+        let right = ops(vec![
+            reordered,
+            op("only-right", false, json!({})),
+            op("nullified", true, json!({})),
+            op("changed", false, json!({"a": 2})),
+        ]);
+
+        let mut found: Vec<_> = diff_ops(left, right)
+            .into_iter()
+            .map(|d| (d.did, d.kind))
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            found,
+            vec![
+                ("changed".into(), DiffKind::OperationDiffers),
+                ("nullified".into(), DiffKind::NullifiedDiffers),
+                ("only-left".into(), DiffKind::MissingRight),
+                ("only-right".into(), DiffKind::MissingLeft),
+            ]
+        );
+    }
+
+    fn op_at(did: &str, secs: i64) -> Op {
+        let mut op = op(did, false, json!({}));
+        op.created_at = Dt::from_timestamp(1_735_689_600 + secs, 0).unwrap();
+        op
+    }
+
+    #[tokio::test]
+    async fn test_diff_pages_streaming() {
+        let (left_tx, left_rx) = mpsc::channel(8);
+        let (right_tx, right_rx) = mpsc::channel(8);
+        let (tx, mut rx) = mpsc::channel(8);
+        // pages split at different places on each side
+        for ops in [
+            vec![op_at("a", 0), op_at("b", 1)],
+            vec![op_at("c", 1), op_at("d", 3)],
+        ] {
+            left_tx.send(ExportPage { ops }).await.unwrap();
+        }
+        for ops in [
+            vec![op_at("a", 0)],
+            vec![op_at("b", 1), op_at("c", 1)],
+            vec![op_at("e", 4)],
+        ] {
+            right_tx.send(ExportPage { ops }).await.unwrap();
+        }
+        drop((left_tx, right_tx));
+        diff_pages(left_rx, right_rx, tx).await.unwrap();
+
+        let mut found = vec![];
+        while let Some(d) = rx.recv().await {
+            found.push((d.did, d.kind));
+        }
+        assert_eq!(
+            found,
+            vec![
+                ("d".into(), DiffKind::MissingRight),
+                ("e".into(), DiffKind::MissingLeft),
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Select ops by who they're for and what they say
+//!
+//! Cheaper than piping everything through `jq`: ops are matched before they
+//! get serialized, and the operation json is only parsed if a filter needs it.
+
+use crate::{ExportPage, Op};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("failed to read did list {path:?}: {source}")]
+    DidList {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unknown op type {0:?}: expected genesis, update or tombstone")]
+    OpType(String),
+}
+
+/// What an op does to its DID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpType {
+    /// creates the DID: no `prev`, including the legacy `create` format
+    Genesis,
+    Update,
+    Tombstone,
+}
+
+impl OpType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Genesis => "genesis",
+            Self::Update => "update",
+            Self::Tombstone => "tombstone",
+        }
+    }
+}
+
+impl FromStr for OpType {
+    type Err = FilterError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "genesis" => Ok(Self::Genesis),
+            "update" => Ok(Self::Update),
+            "tombstone" => Ok(Self::Tombstone),
+            other => Err(FilterError::OpType(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    endpoint: String,
+}
+
+/// Just the filterable parts of any kind of PLC operation
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OpFields {
+    r#type: String,
+    prev: Option<String>,
+    #[serde(default)]
+    rotation_keys: Vec<String>,
+    #[serde(default)]
+    also_known_as: Vec<String>,
+    #[serde(default)]
+    services: BTreeMap<String, Service>,
+    // legacy `create` ops
+    handle: Option<String>,
+    service: Option<String>,
+    recovery_key: Option<String>,
+    signing_key: Option<String>,
+}
+
+impl OpFields {
+    pub(crate) fn parse(op: &Op) -> Option<Self> {
+        serde_json::from_str(op.operation.get()).ok()
+    }
+
+    pub(crate) fn op_type(&self) -> OpType {
+        match (self.r#type.as_str(), &self.prev) {
+            ("plc_tombstone", _) => OpType::Tombstone,
+            (_, None) => OpType::Genesis,
+            (_, Some(_)) => OpType::Update,
+        }
+    }
+
+    fn handles(&self) -> impl Iterator<Item = &str> {
+        self.handle.as_deref().into_iter().chain(
+            self.also_known_as
+                .iter()
+                .filter_map(|aka| aka.strip_prefix("at://")),
+        )
+    }
+
+    /// the first handle, if any
+    pub(crate) fn handle(&self) -> Option<&str> {
+        self.handles().next()
+    }
+
+    pub(crate) fn pds(&self) -> Option<&str> {
+        self.service.as_deref().or(self
+            .services
+            .get("atproto_pds")
+            .map(|s| s.endpoint.as_str()))
+    }
+
+    fn has_handle(&self, handle: &str) -> bool {
+        self.handles().any(|h| h == handle)
+    }
+
+    fn has_pds(&self, pds: &str) -> bool {
+        self.pds()
+            .is_some_and(|endpoint| endpoint.trim_end_matches('/') == pds.trim_end_matches('/'))
+    }
+
+    fn has_rotation_key(&self, key: &str) -> bool {
+        self.rotation_keys.iter().any(|k| k == key)
+            || self.recovery_key.as_deref() == Some(key)
+            || self.signing_key.as_deref() == Some(key)
+    }
+}
+
+/// Which ops to keep
+///
+/// Every criterion that's set must match. Within one criterion, matching any
+/// of its values is enough. The default keeps everything.
+#[derive(Debug, Clone, Default)]
+pub struct OpFilter {
+    pub dids: Option<HashSet<String>>,
+    pub op_types: Vec<OpType>,
+    pub nullified: Option<bool>,
+    /// handles without the `at://`
+    pub handles: Vec<String>,
+    /// PDS endpoint urls
+    pub pds: Vec<String>,
+    /// `did:key`s
+    pub rotation_keys: Vec<String>,
+}
+
+impl OpFilter {
+    /// Add DIDs from a file with one per line
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn add_did_list(&mut self, path: &Path) -> Result<(), FilterError> {
+        let text = std::fs::read_to_string(path).map_err(|source| FilterError::DidList {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let dids = self.dids.get_or_insert_default();
+        dids.extend(
+            text.lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dids.is_none() && self.nullified.is_none() && !self.needs_operation()
+    }
+
+    fn needs_operation(&self) -> bool {
+        !(self.op_types.is_empty()
+            && self.handles.is_empty()
+            && self.pds.is_empty()
+            && self.rotation_keys.is_empty())
+    }
+
+    pub fn matches(&self, op: &Op) -> bool {
+        if let Some(ref dids) = self.dids
+            && !dids.contains(&op.did)
+        {
+            return false;
+        }
+        if self.nullified.is_some_and(|n| n != op.nullified) {
+            return false;
+        }
+        if !self.needs_operation() {
+            return true;
+        }
+        let Some(fields) = OpFields::parse(op) else {
+            log::trace!("can't read operation of {} for filtering, dropping", op.cid);
+            return false;
+        };
+        (self.op_types.is_empty() || self.op_types.contains(&fields.op_type()))
+            && (self.handles.is_empty() || self.handles.iter().any(|h| fields.has_handle(h)))
+            && (self.pds.is_empty() || self.pds.iter().any(|p| fields.has_pds(p)))
+            && (self.rotation_keys.is_empty()
+                || self
+                    .rotation_keys
+                    .iter()
+                    .any(|k| fields.has_rotation_key(k)))
+    }
+
+    /// Drop the page's ops that don't match
+    pub fn apply(&self, page: &mut ExportPage) {
+        if !self.is_empty() {
+            page.ops.retain(|op| self.matches(op));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn op(did: &str, nullified: bool, operation: serde_json::Value) -> Op {
+        serde_json::from_value(json!({
+            "did": did,
+            "cid": "cid",
+            "createdAt": "2025-01-01T00:00:00Z",
+            "nullified": nullified,
+            "operation": operation,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_filters() {
+        let genesis = op(
+            "did:plc:a",
+            false,
+            json!({
+                "type": "plc_operation",
+                "rotationKeys": ["did:key:zQ3shrotation"],
+                "verificationMethods": {"atproto": "did:key:zQ3shsigning"},
+                "alsoKnownAs": ["at://alice.test"],
+                "services": {"atproto_pds": {"type": "AtprotoPersonalDataServer", "endpoint": "https://pds.example.com"}},
+                "prev": null,
+                "sig": "sig",
+            }),
+        );
+        let legacy = op(
+            "did:plc:b",
+            true,
+            json!({
+                "type": "create",
+                "signingKey": "did:key:zQ3shsigning",
+                "recoveryKey": "did:key:zQ3shrecovery",
+                "handle": "bob.test",
+                "service": "https://old-pds.example.com/",
+                "prev": null,
+                "sig": "sig",
+            }),
+        );
+        let tombstone = op(
+            "did:plc:a",
+            false,
+            json!({"type": "plc_tombstone", "prev": "cid", "sig": "sig"}),
+        );
+        let ops = [&genesis, &legacy, &tombstone];
+        let kept = |filter: OpFilter| -> Vec<usize> {
+            (0..ops.len()).filter(|&i| filter.matches(ops[i])).collect()
+        };
+
+        assert_eq!(kept(OpFilter::default()), vec![0, 1, 2]);
+        let dids = Some(HashSet::from(["did:plc:a".to_string()]));
+        assert_eq!(
+            kept(OpFilter {
+                dids: dids.clone(),
+                ..Default::default()
+            }),
+            vec![0, 2]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                op_types: vec![OpType::Genesis],
+                ..Default::default()
+            }),
+            vec![0, 1]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                dids,
+                op_types: vec![OpType::Tombstone],
+                ..Default::default()
+            }),
+            vec![2]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                nullified: Some(true),
+                ..Default::default()
+            }),
+            vec![1]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                handles: vec!["bob.test".to_string(), "alice.test".to_string()],
+                ..Default::default()
+            }),
+            vec![0, 1]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                pds: vec!["https://old-pds.example.com".to_string()],
+                ..Default::default()
+            }),
+            vec![1]
+        );
+        assert_eq!(
+            kept(OpFilter {
+                rotation_keys: vec!["did:key:zQ3shrotation".to_string()],
+                ..Default::default()
+            }),
+            vec![0]
+        );
+    }
+}
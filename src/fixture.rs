@@ -0,0 +1,234 @@
+use crate::{DidHistory, Dt, Op, OpSource, ResolveError, logo, source_to_pages};
+use poem::{
+    EndpointExt, IntoResponse, Response, Route, Server, get, handler,
+    http::StatusCode,
+    listener::{Acceptor, Listener, TcpListener},
+    middleware::{AddData, Tracing},
+    web::{Data, Json, Path, Query},
+};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// PLC caps /export pages at this many ops
+const PAGE_CAP: usize = 1000;
+
+/// Misbehaviours for a fixture server to inject
+#[derive(Debug, Clone, Default)]
+pub struct FixtureFaults {
+    /// repeat ops at the `after` timestamp at the start of each page
+    pub duplicate_boundaries: bool,
+    /// answer every nth request with a 429 (0: never)
+    pub rate_limit_every: u32,
+    /// the `Retry-After` for injected 429s
+    pub retry_after: Duration,
+    /// wait this long before every response
+    pub delay: Duration,
+    /// cut every nth /export response off partway through its last op (0: never)
+    pub truncate_every: u32,
+}
+
+/// A fake PLC server's data and behaviour
+#[derive(Debug)]
+pub struct Fixture {
+    /// in `createdAt` order
+    ops: Vec<Op>,
+    by_did: HashMap<String, Vec<usize>>,
+    faults: FixtureFaults,
+    requests: AtomicU32,
+    exports: AtomicU32,
+}
+
+impl Fixture {
+    pub fn new(mut ops: Vec<Op>, faults: FixtureFaults) -> Self {
+        ops.sort_by_key(|op| op.created_at);
+        let mut by_did: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, op) in ops.iter().enumerate() {
+            by_did.entry(op.did.clone()).or_default().push(i);
+        }
+        Self {
+            ops,
+            by_did,
+            faults,
+            requests: AtomicU32::new(0),
+            exports: AtomicU32::new(0),
+        }
+    }
+
+    /// Load a source's ops after `after`, usually from a bundle folder or jsonl file
+    pub async fn from_source(
+        source: OpSource,
+        after: Dt,
+        faults: FixtureFaults,
+    ) -> anyhow::Result<Self> {
+        let (tx, mut rx) = mpsc::channel(4);
+        let until = chrono::Utc::now();
+        let reader = tokio::task::spawn(source_to_pages(source, after, until, Duration::ZERO, tx));
+        let mut ops = vec![];
+        while let Some(page) = rx.recv().await {
+            ops.extend(page.ops);
+        }
+        reader.await??;
+        log::info!("fixture loaded {} ops", ops.len());
+        Ok(Self::new(ops, faults))
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// The ops /export would return
+    fn export(&self, after: Option<Dt>, count: usize) -> &[Op] {
+        let start = match after {
+            Some(after) if self.faults.duplicate_boundaries => {
+                self.ops.partition_point(|op| op.created_at < after)
+            }
+            Some(after) => self.ops.partition_point(|op| op.created_at <= after),
+            None => 0,
+        };
+        let end = (start + count.min(PAGE_CAP)).min(self.ops.len());
+        &self.ops[start..end]
+    }
+
+    /// every nth, counting from 1
+    fn nth(counter: &AtomicU32, n: u32) -> bool {
+        let i = counter.fetch_add(1, Ordering::Relaxed) + 1;
+        n > 0 && i.is_multiple_of(n)
+    }
+}
+
+/// Apply the request-level faults: delays and rate limits
+async fn faulty(fixture: &Fixture) -> Option<Response> {
+    let FixtureFaults {
+        delay,
+        rate_limit_every,
+        retry_after,
+        ..
+    } = &fixture.faults;
+    if !delay.is_zero() {
+        tokio::time::sleep(*delay).await;
+    }
+    if Fixture::nth(&fixture.requests, *rate_limit_every) {
+        return Some(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("Retry-After", retry_after.as_secs().to_string())
+                .body("fixture: injected rate limit"),
+        );
+    }
+    None
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    after: Option<Dt>,
+    count: Option<usize>,
+}
+
+#[handler]
+async fn export(
+    Data(fixture): Data<&Arc<Fixture>>,
+    Query(ExportQuery { after, count }): Query<ExportQuery>,
+) -> Response {
+    if let Some(res) = faulty(fixture).await {
+        return res;
+    }
+    let mut body = String::new();
+    for op in fixture.export(after, count.unwrap_or(PAGE_CAP)) {
+        body.push_str(&serde_json::to_string(op).expect("op to serialize"));
+        body.push('\n');
+    }
+    if Fixture::nth(&fixture.exports, fixture.faults.truncate_every) {
+        let last_line = body.trim_end().rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let cut = last_line + (body.len() - last_line) / 2;
+        body.truncate(body.floor_char_boundary(cut));
+    }
+    body.with_content_type("application/jsonlines")
+        .into_response()
+}
+
+#[handler]
+async fn resolve(Data(fixture): Data<&Arc<Fixture>>, Path(did): Path<String>) -> Response {
+    if let Some(res) = faulty(fixture).await {
+        return res;
+    }
+    let ops = fixture
+        .by_did
+        .get(&did)
+        .into_iter()
+        .flatten()
+        .map(|&i| fixture.ops[i].clone());
+    let doc = DidHistory::replay(&did, ops, None).and_then(|h| h.document());
+    match doc {
+        Ok(doc) => Json(doc).into_response(),
+        Err(ResolveError::Tombstoned(_)) => (StatusCode::GONE, "DID not available").into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
+#[handler]
+fn health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "version": "allegedly fixture" }))
+}
+
+#[handler]
+fn hello() -> String {
+    format!(
+        r#"{}
+
+This is a fake PLC server for testing, serving ops from a fixed set.
+
+    - GET  /export   Export ops in order (`?after=<time>&count=<n>`)
+    - GET  /:did     Resolve a DID document
+    - GET  /_health  Health check
+"#,
+        logo("fixture")
+    )
+}
+
+fn app(fixture: Fixture) -> impl poem::Endpoint {
+    Route::new()
+        .at("/", get(hello))
+        .at("/_health", get(health))
+        .at("/export", get(export))
+        .at("/:did<did:plc:[^/]+>", get(resolve))
+        .with(AddData::new(Arc::new(fixture)))
+        .with(Tracing)
+}
+
+/// Serve a fixture until the server fails
+pub async fn serve_fixture(fixture: Fixture, bind: SocketAddr) -> anyhow::Result<&'static str> {
+    Server::new(TcpListener::bind(bind))
+        .run(app(fixture))
+        .await?;
+    Ok("serve_fixture (uh oh?)")
+}
+
+/// Serve a fixture on a free local port, for tests
+///
+/// Returns the server's base url. The server stops when the handle is aborted
+/// or dropped with its runtime.
+pub async fn spawn_fixture(fixture: Fixture) -> anyhow::Result<(Url, JoinHandle<()>)> {
+    let acceptor = TcpListener::bind("127.0.0.1:0").into_acceptor().await?;
+    let addr = acceptor
+        .local_addr()
+        .into_iter()
+        .find_map(|a| a.as_socket_addr().cloned())
+        .ok_or_else(|| anyhow::anyhow!("fixture listener has no socket address"))?;
+    let url = format!("http://{addr}/").parse()?;
+    let handle = tokio::task::spawn(async move {
+        if let Err(e) = Server::new_with_acceptor(acceptor).run(app(fixture)).await {
+            log::error!("fixture server failed: {e}");
+        }
+    });
+    Ok((url, handle))
+}
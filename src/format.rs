@@ -0,0 +1,598 @@
+//! Ways to write ops out besides json lines
+//!
+//! Everything here writes to a blocking [`Write`], one page at a time. The
+//! columnar formats need the `columnar` feature.
+
+use crate::filter::OpFields;
+use crate::{Dt, ExportPage, Op};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("unknown output format {0:?}")]
+    UnknownFormat(String),
+    #[error("unknown field {0:?}")]
+    UnknownField(String),
+    #[error("{0} output isn't available without the `columnar` feature")]
+    NotBuilt(&'static str),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "columnar")]
+    #[error(transparent)]
+    Arrow(#[from] arrow::error::ArrowError),
+    #[cfg(feature = "columnar")]
+    #[error(transparent)]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// one op per line, as upstream's /export has them
+    #[default]
+    Jsonl,
+    /// json lines like `{"cursor":"<createdAt>","op":{...}}`
+    ///
+    /// the cursor is what to pass as `after` to resume from that op
+    Envelope,
+    /// a header row, then the selected fields
+    Csv,
+    /// like csv, with tabs, and `\t`, `\n` and `\\` escapes instead of quoting
+    Tsv,
+    /// each op as a DAG-CBOR map, prefixed by its length as an unsigned varint
+    DagCbor,
+    /// an arrow IPC stream of the selected fields
+    Arrow,
+    /// a parquet file of the selected fields
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = FormatError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::Jsonl),
+            "envelope" => Ok(Self::Envelope),
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            "dag-cbor" | "cbor" => Ok(Self::DagCbor),
+            "arrow" | "arrow-ipc" => Ok(Self::Arrow),
+            "parquet" => Ok(Self::Parquet),
+            other => Err(FormatError::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+/// A column for the tabular formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Did,
+    Cid,
+    CreatedAt,
+    Nullified,
+    /// genesis, update or tombstone
+    Type,
+    /// the first handle the op sets
+    Handle,
+    /// the op's PDS endpoint
+    Pds,
+    /// the raw operation json
+    Operation,
+}
+
+impl Field {
+    /// what tabular formats get unless told otherwise
+    pub const DEFAULT: [Field; 5] = [
+        Field::Did,
+        Field::Cid,
+        Field::CreatedAt,
+        Field::Nullified,
+        Field::Operation,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Did => "did",
+            Self::Cid => "cid",
+            Self::CreatedAt => "created_at",
+            Self::Nullified => "nullified",
+            Self::Type => "type",
+            Self::Handle => "handle",
+            Self::Pds => "pds",
+            Self::Operation => "operation",
+        }
+    }
+
+    fn needs_operation(&self) -> bool {
+        matches!(self, Self::Type | Self::Handle | Self::Pds)
+    }
+}
+
+impl FromStr for Field {
+    type Err = FormatError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "did" => Ok(Self::Did),
+            "cid" => Ok(Self::Cid),
+            "created_at" | "createdAt" => Ok(Self::CreatedAt),
+            "nullified" => Ok(Self::Nullified),
+            "type" => Ok(Self::Type),
+            "handle" => Ok(Self::Handle),
+            "pds" => Ok(Self::Pds),
+            "operation" => Ok(Self::Operation),
+            other => Err(FormatError::UnknownField(other.to_string())),
+        }
+    }
+}
+
+/// One op's selected fields, as text
+fn field_values(op: &Op, fields: &[Field]) -> Vec<Option<String>> {
+    let parsed = fields
+        .iter()
+        .any(Field::needs_operation)
+        .then(|| OpFields::parse(op))
+        .flatten();
+    fields
+        .iter()
+        .map(|field| match field {
+            Field::Did => Some(op.did.clone()),
+            Field::Cid => Some(op.cid.clone()),
+            Field::CreatedAt => Some(cursor(&op.created_at)),
+            Field::Nullified => Some(op.nullified.to_string()),
+            Field::Type => parsed.as_ref().map(|f| f.op_type().as_str().to_string()),
+            Field::Handle => parsed.as_ref().and_then(|f| f.handle()).map(String::from),
+            Field::Pds => parsed.as_ref().and_then(|f| f.pds()).map(String::from),
+            Field::Operation => Some(op.operation.get().to_string()),
+        })
+        .collect()
+}
+
+/// timestamps the way upstream's /export writes them
+fn cursor(at: &Dt) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn csv_escape(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+fn tsv_escape(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains(['\t', '\n', '\r', '\\']) {
+        value
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+            .into()
+    } else {
+        value.into()
+    }
+}
+
+fn write_row(
+    out: &mut impl Write,
+    values: impl IntoIterator<Item = Option<String>>,
+    sep: char,
+) -> std::io::Result<()> {
+    let escape = if sep == '\t' { tsv_escape } else { csv_escape };
+    let mut line = String::new();
+    for (i, value) in values.into_iter().enumerate() {
+        if i > 0 {
+            line.push(sep);
+        }
+        line.push_str(&escape(value.as_deref().unwrap_or_default()));
+    }
+    writeln!(out, "{line}")
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    cursor: String,
+    op: &'a Op,
+}
+
+/// Append DAG-CBOR for a json value
+///
+/// Maps are sorted the DAG-CBOR way: shorter keys first, then bytewise.
+fn dag_cbor(value: &Value, buf: &mut Vec<u8>) {
+    fn head(major: u8, n: u64, buf: &mut Vec<u8>) {
+        let major = major << 5;
+        match n {
+            0..24 => buf.push(major | n as u8),
+            24..0x100 => buf.extend([major | 24, n as u8]),
+            0x100..0x10000 => {
+                buf.push(major | 25);
+                buf.extend((n as u16).to_be_bytes());
+            }
+            0x10000..0x1_0000_0000 => {
+                buf.push(major | 26);
+                buf.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                buf.push(major | 27);
+                buf.extend(n.to_be_bytes());
+            }
+        }
+    }
+    fn text(s: &str, buf: &mut Vec<u8>) {
+        head(3, s.len() as u64, buf);
+        buf.extend(s.as_bytes());
+    }
+    match value {
+        Value::Null => buf.push(0xf6),
+        Value::Bool(false) => buf.push(0xf4),
+        Value::Bool(true) => buf.push(0xf5),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                head(0, u, buf);
+            } else if let Some(i) = n.as_i64() {
+                head(1, !(i as u64), buf);
+            } else {
+                buf.push(0xfb);
+                buf.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(s) => text(s, buf),
+        Value::Array(items) => {
+            head(4, items.len() as u64, buf);
+            for item in items {
+                dag_cbor(item, buf);
+            }
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+            head(5, entries.len() as u64, buf);
+            for (k, v) in entries {
+                text(k, buf);
+                dag_cbor(v, buf);
+            }
+        }
+    }
+}
+
+fn write_varint(out: &mut impl Write, mut n: u64) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(10);
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+    out.write_all(&bytes)
+}
+
+#[cfg(feature = "columnar")]
+mod columnar {
+    use super::{Field, FormatError, field_values};
+    use crate::Op;
+    use arrow::array::{ArrayRef, BooleanArray, StringArray, TimestampMillisecondArray};
+    use arrow::datatypes::{DataType, Schema, TimeUnit};
+    use arrow::record_batch::RecordBatch;
+    use std::sync::Arc;
+
+    pub fn schema(fields: &[Field]) -> Arc<Schema> {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|field| {
+                let (data_type, nullable) = match field {
+                    Field::CreatedAt => (
+                        DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                        false,
+                    ),
+                    Field::Nullified => (DataType::Boolean, false),
+                    Field::Type | Field::Handle | Field::Pds => (DataType::Utf8, true),
+                    Field::Did | Field::Cid | Field::Operation => (DataType::Utf8, false),
+                };
+                arrow::datatypes::Field::new(field.name(), data_type, nullable)
+            })
+            .collect();
+        Arc::new(Schema::new(fields))
+    }
+
+    pub fn batch(
+        schema: &Arc<Schema>,
+        fields: &[Field],
+        ops: &[Op],
+    ) -> Result<RecordBatch, FormatError> {
+        let rows: Vec<_> = ops.iter().map(|op| field_values(op, fields)).collect();
+        let columns = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| -> ArrayRef {
+                match field {
+                    Field::CreatedAt => Arc::new(
+                        TimestampMillisecondArray::from_iter_values(
+                            ops.iter().map(|op| op.created_at.timestamp_millis()),
+                        )
+                        .with_timezone("UTC"),
+                    ),
+                    Field::Nullified => Arc::new(BooleanArray::from_iter(
+                        ops.iter().map(|op| Some(op.nullified)),
+                    )),
+                    _ => Arc::new(StringArray::from_iter(
+                        rows.iter().map(|row| row[i].as_deref()),
+                    )),
+                }
+            })
+            .collect();
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+enum Inner<W: Write + Send> {
+    Jsonl(BufWriter<W>),
+    Envelope(BufWriter<W>),
+    Separated {
+        out: BufWriter<W>,
+        sep: char,
+        header: bool,
+    },
+    DagCbor(BufWriter<W>),
+    #[cfg(feature = "columnar")]
+    Arrow {
+        schema: std::sync::Arc<arrow::datatypes::Schema>,
+        writer: arrow::ipc::writer::StreamWriter<BufWriter<W>>,
+    },
+    #[cfg(feature = "columnar")]
+    Parquet {
+        schema: std::sync::Arc<arrow::datatypes::Schema>,
+        writer: parquet::arrow::ArrowWriter<W>,
+    },
+}
+
+/// Writes ops in some [`OutputFormat`]
+///
+/// Call [`OpWriter::finish`] at the end: some formats have a footer.
+pub struct OpWriter<W: Write + Send> {
+    inner: Inner<W>,
+    fields: Vec<Field>,
+}
+
+impl<W: Write + Send> OpWriter<W> {
+    /// `fields` are for the tabular formats (csv, tsv, arrow, parquet)
+    pub fn new(format: OutputFormat, fields: Vec<Field>, out: W) -> Result<Self, FormatError> {
+        let fields = if fields.is_empty() {
+            Field::DEFAULT.to_vec()
+        } else {
+            fields
+        };
+        let inner = match format {
+            OutputFormat::Jsonl => Inner::Jsonl(BufWriter::new(out)),
+            OutputFormat::Envelope => Inner::Envelope(BufWriter::new(out)),
+            OutputFormat::Csv | OutputFormat::Tsv => Inner::Separated {
+                out: BufWriter::new(out),
+                sep: if format == OutputFormat::Tsv {
+                    '\t'
+                } else {
+                    ','
+                },
+                header: false,
+            },
+            OutputFormat::DagCbor => Inner::DagCbor(BufWriter::new(out)),
+            #[cfg(feature = "columnar")]
+            OutputFormat::Arrow => {
+                let schema = columnar::schema(&fields);
+                let writer =
+                    arrow::ipc::writer::StreamWriter::try_new(BufWriter::new(out), &schema)?;
+                Inner::Arrow { schema, writer }
+            }
+            #[cfg(feature = "columnar")]
+            OutputFormat::Parquet => {
+                let schema = columnar::schema(&fields);
+                let props = parquet::file::properties::WriterProperties::builder()
+                    .set_compression(parquet::basic::Compression::SNAPPY)
+                    .build();
+                let writer =
+                    parquet::arrow::ArrowWriter::try_new(out, schema.clone(), Some(props))?;
+                Inner::Parquet { schema, writer }
+            }
+            #[cfg(not(feature = "columnar"))]
+            OutputFormat::Arrow => return Err(FormatError::NotBuilt("arrow")),
+            #[cfg(not(feature = "columnar"))]
+            OutputFormat::Parquet => return Err(FormatError::NotBuilt("parquet")),
+        };
+        Ok(Self { inner, fields })
+    }
+
+    pub fn write_ops(&mut self, ops: &[Op]) -> Result<(), FormatError> {
+        match &mut self.inner {
+            Inner::Jsonl(out) => {
+                for op in ops {
+                    serde_json::to_writer(&mut *out, op)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            Inner::Envelope(out) => {
+                for op in ops {
+                    let cursor = cursor(&op.created_at);
+                    serde_json::to_writer(&mut *out, &Envelope { cursor, op })?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            Inner::Separated { out, sep, header } => {
+                if !*header {
+                    let names = self.fields.iter().map(|f| Some(f.name().to_string()));
+                    write_row(out, names, *sep)?;
+                    *header = true;
+                }
+                for op in ops {
+                    write_row(out, field_values(op, &self.fields), *sep)?;
+                }
+            }
+            Inner::DagCbor(out) => {
+                let mut buf = vec![];
+                for op in ops {
+                    buf.clear();
+                    dag_cbor(&serde_json::to_value(op)?, &mut buf);
+                    write_varint(out, buf.len() as u64)?;
+                    out.write_all(&buf)?;
+                }
+            }
+            #[cfg(feature = "columnar")]
+            Inner::Arrow { schema, writer } => {
+                if !ops.is_empty() {
+                    writer.write(&columnar::batch(schema, &self.fields, ops)?)?;
+                }
+            }
+            #[cfg(feature = "columnar")]
+            Inner::Parquet { schema, writer } => {
+                if !ops.is_empty() {
+                    writer.write(&columnar::batch(schema, &self.fields, ops)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_page(&mut self, page: &ExportPage) -> Result<(), FormatError> {
+        self.write_ops(&page.ops)
+    }
+
+    /// Send along what's been written so far, for formats that stream
+    pub fn flush(&mut self) -> Result<(), FormatError> {
+        match &mut self.inner {
+            Inner::Jsonl(out)
+            | Inner::Envelope(out)
+            | Inner::Separated { out, .. }
+            | Inner::DagCbor(out) => out.flush()?,
+            #[cfg(feature = "columnar")]
+            Inner::Arrow { writer, .. } => writer.flush()?,
+            // flushing here would cut a row group for every page
+            #[cfg(feature = "columnar")]
+            Inner::Parquet { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Flush, and write any footer
+    pub fn finish(self) -> Result<(), FormatError> {
+        match self.inner {
+            Inner::Jsonl(mut out)
+            | Inner::Envelope(mut out)
+            | Inner::Separated { mut out, .. }
+            | Inner::DagCbor(mut out) => out.flush()?,
+            #[cfg(feature = "columnar")]
+            Inner::Arrow { mut writer, .. } => writer.finish()?,
+            #[cfg(feature = "columnar")]
+            Inner::Parquet { writer, .. } => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn op() -> Op {
+        serde_json::from_value(json!({
+            "did": "did:plc:a",
+            "cid": "bafyreia",
+            "createdAt": "2025-01-01T00:00:00.123Z",
+            "nullified": false,
+            "operation": {
+                "type": "plc_operation",
+                "rotationKeys": ["did:key:zQ3shrotation"],
+                "verificationMethods": {"atproto": "did:key:zQ3shsigning"},
+                "alsoKnownAs": ["at://alice.test"],
+                "services": {"atproto_pds": {"type": "AtprotoPersonalDataServer", "endpoint": "https://pds.example.com"}},
+                "prev": null,
+                "sig": "sig",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn written(format: OutputFormat, fields: Vec<Field>) -> Vec<u8> {
+        let mut out = vec![];
+        let mut writer = OpWriter::new(format, fields, &mut out).unwrap();
+        writer.write_ops(&[op(), op()]).unwrap();
+        writer.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let envelope = String::from_utf8(written(OutputFormat::Envelope, vec![])).unwrap();
+        let first: Value = serde_json::from_str(envelope.lines().next().unwrap()).unwrap();
+        assert_eq!(first["cursor"], "2025-01-01T00:00:00.123Z");
+        assert_eq!(first["op"]["did"], "did:plc:a");
+
+        let fields = vec![
+            Field::Did,
+            Field::Type,
+            Field::Handle,
+            Field::Pds,
+            Field::Operation,
+        ];
+        let csv = String::from_utf8(written(OutputFormat::Csv, fields.clone())).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "did,type,handle,pds,operation");
+        assert!(lines[1].starts_with(
+            r#"did:plc:a,genesis,alice.test,https://pds.example.com,"{""alsoKnownAs"":"#
+        ));
+
+        let tsv = String::from_utf8(written(OutputFormat::Tsv, fields)).unwrap();
+        let row: Vec<_> = tsv.lines().nth(1).unwrap().split('\t').collect();
+        assert_eq!(row.len(), 5);
+        assert_eq!(row[2], "alice.test");
+    }
+
+    #[test]
+    fn test_dag_cbor() {
+        let mut buf = vec![];
+        dag_cbor(&json!({"bb": 1, "a": [true, null], "c": -500}), &mut buf);
+        // a3: map of 3, with keys ordered a, c, bb
+        assert_eq!(
+            buf,
+            [
+                0xa3, 0x61, b'a', 0x82, 0xf5, 0xf6, 0x61, b'c', 0x39, 0x01, 0xf3, 0x62, b'b', b'b',
+                0x01
+            ]
+        );
+
+        let out = written(OutputFormat::DagCbor, vec![]);
+        // two records, each prefixed with its length
+        assert!(out[0] & 0x80 != 0, "a two-byte varint");
+        let len = (out[0] & 0x7f) as usize | ((out[1] as usize) << 7);
+        let rest = &out[2..];
+        assert_eq!(rest.len(), 2 * len + 2);
+        assert_eq!(rest[0], 0xa5, "a map of the op's five fields");
+    }
+
+    #[cfg(feature = "columnar")]
+    #[test]
+    fn test_columnar() {
+        let fields = vec![
+            Field::Did,
+            Field::CreatedAt,
+            Field::Nullified,
+            Field::Handle,
+        ];
+        let ipc = written(OutputFormat::Arrow, fields.clone());
+        let reader = arrow::ipc::reader::StreamReader::try_new(&ipc[..], None).unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        assert_eq!(batches[0].schema().field(1).name(), "created_at");
+
+        let parquet = written(OutputFormat::Parquet, fields);
+        assert!(parquet.starts_with(b"PAR1") && parquet.ends_with(b"PAR1"));
+    }
+}
@@ -1,25 +1,111 @@
+use futures::{Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io::Write;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
 use tokio::sync::{mpsc, oneshot};
 
+#[cfg(feature = "bundles")]
 mod backfill;
 mod cached_value;
+#[cfg(feature = "poll")]
+mod catchup;
+#[cfg(feature = "poll")]
 mod client;
+mod deadletter;
+#[cfg(all(feature = "bundles", feature = "postgres"))]
+mod diff;
+mod filter;
+#[cfg(feature = "server")]
+mod fixture;
+mod format;
+pub mod metrics;
+#[cfg(feature = "server")]
 mod mirror;
+#[cfg(feature = "server")]
+mod note;
+#[cfg(feature = "postgres")]
 mod plc_pg;
+#[cfg(feature = "poll")]
 mod poll;
+#[cfg(feature = "server")]
 mod ratelimit;
+#[cfg(all(feature = "bundles", feature = "postgres"))]
+mod resolve;
+#[cfg(feature = "server")]
+mod scatter;
+#[cfg(feature = "server")]
+mod tlog;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "bundles")]
 mod weekly;
+#[cfg(feature = "server")]
+mod witness;
 
+#[cfg(feature = "cli")]
 pub mod bin;
+// lets `bin` refer to the library the same way the binaries do
+#[cfg(feature = "cli")]
+extern crate self as allegedly;
 
-pub use backfill::backfill;
+#[cfg(feature = "bundles")]
+pub use backfill::{backfill, backfill_stream};
 pub use cached_value::{CachedValue, Fetcher};
-pub use client::{CLIENT, UA};
-pub use mirror::{ExperimentalConf, ListenConf, serve};
-pub use plc_pg::{Db, backfill_to_pg, pages_to_pg};
-pub use poll::{PageBoundaryState, get_page, poll_upstream};
-pub use ratelimit::{CreatePlcOpLimiter, GovernorMiddleware, IpLimiters};
-pub use weekly::{BundleSource, FolderSource, HttpSource, Week, pages_to_weeks, week_to_pages};
+#[cfg(feature = "poll")]
+pub use catchup::{CatchUpTarget, catch_up_stream, catch_up_upstream, caught_up};
+#[cfg(feature = "poll")]
+pub use client::{ClientConf, ClientError, HttpClient, UA, UpstreamHeader, user_agent};
+pub use deadletter::{
+    DeadLetter, DeadLetterError, DeadLetterSink, RejectError, reject, set_dead_letters,
+};
+#[cfg(all(feature = "bundles", feature = "postgres"))]
+pub use diff::{DiffKind, OpDiff, OpSource, diff_ops, diff_pages, source_to_pages};
+pub use filter::{FilterError, OpFilter, OpType};
+#[cfg(feature = "server")]
+pub use fixture::{Fixture, FixtureFaults, serve_fixture, spawn_fixture};
+pub use format::{Field, FormatError, OpWriter, OutputFormat};
+#[cfg(feature = "server")]
+pub use mirror::{ExperimentalConf, FallbackConf, ListenConf, TimingConf, serve};
+#[cfg(feature = "server")]
+pub use note::{Note, NoteError, NoteSignature, NoteSigner, NoteVerifier};
+#[cfg(feature = "postgres")]
+pub use plc_pg::{Db, DbError, backfill_to_pg, pages_to_pg, stream_to_pg};
+#[cfg(feature = "poll")]
+pub use poll::{
+    GetPageError, PageBoundaryState, PageParsing, PollError, RateLimitHeaders, get_page,
+    get_page_with, poll_stream, poll_upstream,
+};
+#[cfg(feature = "server")]
+pub use ratelimit::{
+    ClientIp, CreatePlcOpLimiter, ForwardedHeader, GovernorMiddleware, IpAccess, IpLimiters,
+    LimitState, Limiter, RateLimitConf, RouteLimiters, parse_ip_net, parse_quota,
+    parse_route_quota,
+};
+#[cfg(all(feature = "bundles", feature = "postgres"))]
+pub use resolve::{DidHistory, ResolveError, collect_did_ops};
+#[cfg(feature = "server")]
+pub use scatter::{
+    ScatterConf, ScatterError, ScatterOutcome, ScatterRule, UpstreamResult, scatter_post,
+    serve_scatter,
+};
+#[cfg(feature = "server")]
+pub use tlog::{
+    Checkpoint, Hash, MerkleTree, SharedTlog, Tlog, leaf_hash, node_hash, op_leaf, pages_to_tlog,
+    proof_text, tlog_sync, verify_consistency, verify_inclusion,
+};
+#[cfg(feature = "webhook")]
+pub use webhook::{
+    SIGNATURE_HEADER, WebhookConf, WebhookError, pages_to_webhooks, read_cursor, sign,
+};
+#[cfg(feature = "bundles")]
+pub use weekly::{
+    BundleError, BundleSource, FolderSource, HttpSource, Week, pages_to_weeks, stream_to_weeks,
+    week_stream, week_to_pages,
+};
+#[cfg(feature = "server")]
+pub use witness::{WitnessConf, Witnessed, load_witnessed, serve_witness, witness};
 
 pub type Dt = chrono::DateTime<chrono::Utc>;
 
@@ -37,6 +123,25 @@ impl ExportPage {
     }
 }
 
+/// Why a poller counts as caught up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaughtUpReason {
+    /// reached the `until` target
+    Until,
+    /// upstream had nothing newer than our latest op
+    Head,
+}
+
+/// Sent once a poller has caught up, for sinks to react to
+///
+/// See [`caught_up`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaughtUp {
+    /// the latest op forwarded
+    pub last_at: Option<Dt>,
+    pub reason: CaughtUpReason,
+}
+
 /// A fully-deserialized plc operation
 ///
 /// including the plc's wrapping with timestmap and nullified state
@@ -63,7 +168,7 @@ impl PartialEq for Op {
 }
 
 /// Database primary key for an op
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OpKey {
     pub did: String,
     pub cid: String,
@@ -78,51 +183,126 @@ impl From<&Op> for OpKey {
     }
 }
 
-/// page forwarder who drops its channels on receipt of a small page
+/// Read pages from a channel as a stream
+///
+/// It never errors, so `E` is whatever the consumer wants.
+pub fn channel_stream<E>(
+    rx: mpsc::Receiver<ExportPage>,
+) -> impl Stream<Item = Result<ExportPage, E>> {
+    tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok)
+}
+
+/// Forward a stream of pages into a channel, until either side is done
+///
+/// Fails with the stream's first error. A dropped receiver just means the
+/// consumer is done (say, it caught up), so that stops forwarding quietly.
+pub async fn stream_to_channel<E>(
+    pages: impl Stream<Item = Result<ExportPage, E>>,
+    dest: mpsc::Sender<ExportPage>,
+) -> Result<(), E> {
+    let mut pages = pin!(pages);
+    loop {
+        // a poller might go a long time between pages, so watch for the
+        // receiver going away while waiting
+        let page = tokio::select! {
+            page = pages.try_next() => page?,
+            () = dest.closed() => break,
+        };
+        let Some(page) = page else {
+            break;
+        };
+        match dest.try_send(page) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(page)) => {
+                log::warn!("destination channel full, awaiting...");
+                if dest.send(page).await.is_err() {
+                    break;
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => break,
+        };
+    }
+    Ok(())
+}
+
+/// Forward only the ops matching `filter`
+///
+/// For stages that count ops, like [`take_ops`], so they only count matches.
+/// Pages left empty are dropped.
+pub async fn filter_pages(
+    mut rx: mpsc::Receiver<ExportPage>,
+    tx: mpsc::Sender<ExportPage>,
+    filter: OpFilter,
+) -> Result<&'static str, Infallible> {
+    while let Some(mut page) = rx.recv().await {
+        filter.apply(&mut page);
+        if !page.is_empty() && tx.send(page).await.is_err() {
+            break;
+        }
+    }
+    Ok("filter_pages")
+}
+
+/// Forward pages until `n` ops have gone through, then stop
 ///
-/// PLC will return up to 1000 ops on a page, and returns full pages until it
-/// has caught up, so this is a (hacky?) way to stop polling once we're up.
-pub async fn full_pages(
+/// The page that reaches `n` is cut short. Dropping the channels afterwards
+/// lets the stages before this one stop too.
+pub async fn take_ops(
     mut rx: mpsc::Receiver<ExportPage>,
     tx: mpsc::Sender<ExportPage>,
-) -> anyhow::Result<&'static str> {
-    while let Some(page) = rx.recv().await {
-        let n = page.ops.len();
-        if n < 900 {
-            let last_age = page.ops.last().map(|op| chrono::Utc::now() - op.created_at);
-            let Some(age) = last_age else {
-                log::info!("full_pages done, empty final page");
-                return Ok("full pages (hmm)");
-            };
-            if age <= chrono::TimeDelta::hours(6) {
-                log::info!("full_pages done, final page of {n} ops");
-            } else {
-                log::warn!("full_pages finished with small page of {n} ops, but it's {age} old");
-            }
-            return Ok("full pages (cool)");
+    n: usize,
+) -> Result<&'static str, Infallible> {
+    let mut left = n;
+    while left > 0
+        && let Some(mut page) = rx.recv().await
+    {
+        page.ops.truncate(left);
+        left -= page.ops.len();
+        if tx.send(page).await.is_err() {
+            break;
         }
-        log::trace!("full_pages: continuing with page of {n} ops");
-        tx.send(page).await?;
     }
-    Err(anyhow::anyhow!(
-        "full_pages ran out of source material, sender closed"
-    ))
+    log::info!("take_ops done after {} ops", n - left);
+    Ok("take_ops")
 }
 
+/// Print ops as json lines, optionally only the ones matching `filter`
+///
+/// `notify_last_at` hears about the latest op seen, whether or not it was
+/// filtered out.
 pub async fn pages_to_stdout(
+    rx: mpsc::Receiver<ExportPage>,
+    notify_last_at: Option<oneshot::Sender<Option<Dt>>>,
+    filter: Option<OpFilter>,
+) -> Result<&'static str, FormatError> {
+    let out = OpWriter::new(OutputFormat::Jsonl, vec![], std::io::stdout())?;
+    pages_to_output(rx, notify_last_at, filter, Arc::new(Mutex::new(out))).await?;
+    Ok("pages_to_stdout")
+}
+
+/// Write ops out in some format, optionally only the ones matching `filter`
+///
+/// Several sinks can take turns with the same writer (like backfill's bulk
+/// sink and then its catch-up sink): whichever finishes last writes any
+/// footer. `notify_last_at` hears about the latest op seen, whether or not
+/// it was filtered out.
+pub async fn pages_to_output<W: Write + Send>(
     mut rx: mpsc::Receiver<ExportPage>,
     notify_last_at: Option<oneshot::Sender<Option<Dt>>>,
-) -> anyhow::Result<&'static str> {
+    filter: Option<OpFilter>,
+    out: Arc<Mutex<OpWriter<W>>>,
+) -> Result<&'static str, FormatError> {
     let mut last_at = None;
-    while let Some(page) = rx.recv().await {
-        for op in &page.ops {
-            println!("{}", serde_json::to_string(op)?);
+    while let Some(mut page) = rx.recv().await {
+        if let Some(op) = page.ops.last() {
+            last_at = last_at.max(Some(op.created_at));
         }
-        if notify_last_at.is_some()
-            && let Some(s) = PageBoundaryState::new(&page)
-        {
-            last_at = last_at.filter(|&l| l >= s.last_at).or(Some(s.last_at));
+        if let Some(ref filter) = filter {
+            filter.apply(&mut page);
         }
+        let mut out = out.lock().expect("output lock not to be poisoned");
+        out.write_page(&page)?;
+        out.flush()?;
     }
     if let Some(notify) = notify_last_at {
         log::trace!("notifying last_at: {last_at:?}");
@@ -130,7 +310,12 @@ pub async fn pages_to_stdout(
             log::error!("receiver for last_at dropped, can't notify");
         };
     }
-    Ok("pages_to_stdout")
+    if let Ok(out) = Arc::try_unwrap(out) {
+        out.into_inner()
+            .expect("output lock not to be poisoned")
+            .finish()?;
+    }
+    Ok("pages_to_output")
 }
 
 pub fn logo(name: &str) -> String {
@@ -146,6 +331,7 @@ pub fn logo(name: &str) -> String {
     )
 }
 
+#[cfg(feature = "cli")]
 pub fn bin_init(name: &str) {
     if std::env::var_os("RUST_LOG").is_none() {
         unsafe { std::env::set_var("RUST_LOG", "info") };
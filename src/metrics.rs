@@ -0,0 +1,98 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A process-wide counter, reported in prometheus text format
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static PROXY_FALLBACKS: Counter = Counter::new(
+    "allegedly_proxy_fallbacks_total",
+    "reads served from upstream because the wrapped server couldn't answer",
+);
+pub static PROXY_FALLBACK_FAILURES: Counter = Counter::new(
+    "allegedly_proxy_fallback_failures_total",
+    "fallback reads that upstream couldn't answer either",
+);
+pub static WRAPPED_CIRCUIT_OPENS: Counter = Counter::new(
+    "allegedly_wrapped_circuit_opens_total",
+    "times the wrapped server was marked down after repeated failures",
+);
+pub static WITNESS_COSIGNED: Counter = Counter::new(
+    "allegedly_witness_cosigned_total",
+    "new log checkpoints verified and cosigned",
+);
+pub static WITNESS_REFUSED: Counter = Counter::new(
+    "allegedly_witness_refused_total",
+    "log checkpoints that couldn't be fetched or failed verification",
+);
+
+pub static DEAD_LETTERS: Counter = Counter::new(
+    "allegedly_dead_letters_total",
+    "lines from upstream pages or bundles that failed to parse as ops",
+);
+
+pub static POLL_DUPLICATES: Counter = Counter::new(
+    "allegedly_poll_duplicates_total",
+    "ops dropped from upstream export pages because they were already seen",
+);
+pub static POLL_OUT_OF_ORDER: Counter = Counter::new(
+    "allegedly_poll_out_of_order_total",
+    "ops from upstream export pages that were older than an op before them",
+);
+
+pub static WEBHOOK_OPS: Counter = Counter::new(
+    "allegedly_webhook_ops_total",
+    "ops delivered to every configured webhook",
+);
+pub static WEBHOOK_FAILURES: Counter = Counter::new(
+    "allegedly_webhook_failures_total",
+    "webhook deliveries that failed after retries",
+);
+
+static ALL: &[&Counter] = &[
+    &PROXY_FALLBACKS,
+    &PROXY_FALLBACK_FAILURES,
+    &WRAPPED_CIRCUIT_OPENS,
+    &WITNESS_COSIGNED,
+    &WITNESS_REFUSED,
+    &DEAD_LETTERS,
+    &POLL_DUPLICATES,
+    &POLL_OUT_OF_ORDER,
+    &WEBHOOK_OPS,
+    &WEBHOOK_FAILURES,
+];
+
+/// All counters in prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in ALL {
+        let Counter { name, help, .. } = counter;
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+        writeln!(out, "{name} {}", counter.get()).unwrap();
+    }
+    out
+}
@@ -1,32 +1,110 @@
 use crate::{
-    CachedValue, CreatePlcOpLimiter, Db, Dt, Fetcher, GovernorMiddleware, IpLimiters, UA, logo,
+    CachedValue, ClientIp, CreatePlcOpLimiter, Db, Dt, Fetcher, GovernorMiddleware, IpLimiters,
+    RateLimitConf, RouteLimiters, ScatterConf, SharedTlog, UA, logo, metrics, proof_text,
+    scatter::MAX_OP_BYTES, scatter_post,
 };
 use futures::TryStreamExt;
-use governor::Quota;
 use poem::{
-    Body, Endpoint, EndpointExt, Error, IntoResponse, Request, Response, Result, Route, Server,
+    Body, Endpoint, EndpointExt, Error, IntoResponse, Request, Response, Result, Route,
+    RouteMethod, Server,
+    endpoint::make_sync,
+    error::{MethodNotAllowedError, NotFoundError},
     get, handler,
-    http::{StatusCode, header::USER_AGENT},
+    http::{
+        HeaderMap, HeaderName, StatusCode, Uri,
+        header::{
+            ACCEPT_ENCODING, ALLOW, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE,
+            TRAILER, TRANSFER_ENCODING, UPGRADE, USER_AGENT,
+        },
+    },
     listener::{Listener, TcpListener, acme::AutoCert},
     middleware::{AddData, CatchPanic, Compression, Cors, Tracing},
-    web::{Data, Json, Path},
+    web::{Data, Json, Path, Query},
 };
 use reqwest::{Client, Url};
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use serde::Deserialize;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio_postgres::Error as PgError;
 
 #[derive(Clone)]
 struct State {
     client: Client,
+    /// who a request is really from, for the wrapped server
+    client_ip: ClientIp,
     plc: Url,
     upstream: Url,
     sync_info: Option<SyncInfo>,
     experimental: ExperimentalConf,
+    timing: TimingConf,
+    /// tracks the wrapped server's health when upstream read fallback is on
+    wrapped_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+/// Stop sending requests to a server that keeps failing, for a while
+///
+/// After `failure_threshold` consecutive failures the circuit opens, and no
+/// requests are allowed until `cooldown` passes. Then requests are let through
+/// again: one success closes the circuit, one failure re-opens it.
+#[derive(Debug)]
+struct CircuitBreaker {
+    conf: FallbackConf,
+    failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    fn new(conf: FallbackConf) -> Self {
+        Self {
+            conf,
+            failures: AtomicU32::new(0),
+            open_until: Mutex::new(None),
+        }
+    }
+    fn allows(&self) -> bool {
+        match *self.open_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+    fn is_open(&self) -> bool {
+        self.open_until.lock().unwrap().is_some()
+    }
+    fn success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if self.open_until.lock().unwrap().take().is_some() {
+            log::info!("wrapped server is answering again, closing circuit");
+        }
+    }
+    fn failure(&self) {
+        let n = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if n < self.conf.failure_threshold {
+            return;
+        }
+        let mut open_until = self.open_until.lock().unwrap();
+        if open_until.is_none() {
+            log::warn!(
+                "wrapped server failed {n} times in a row, sending reads upstream for {:?}",
+                self.conf.cooldown
+            );
+            metrics::WRAPPED_CIRCUIT_OPENS.inc();
+        }
+        *open_until = Some(Instant::now() + self.conf.cooldown);
+    }
 }
 
 /// server info that only applies in mirror (synchronizing) mode
 #[derive(Clone)]
 struct SyncInfo {
-    latest_at: CachedValue<Dt, GetLatestAt>,
+    latest_at: CachedValue<Option<Dt>, GetLatestAt>,
     upstream_status: CachedValue<PlcStatus, CheckUpstream>,
 }
 
@@ -83,6 +161,19 @@ Configured upstream (only used if experimental op forwarding is enabled):
         ),
     };
 
+    let tlog_info = if exp.tlog.is_some() {
+        r#"
+
+Experimental transparency log over this mirror's ops (c2sp.org/tlog-checkpoint):
+
+    - GET  /tlog/checkpoint                         Latest checkpoint
+    - GET  /tlog/proof/inclusion?did=<did>&cid=<cid> Inclusion proof for an op
+    - GET  /tlog/proof/consistency?old=<n>&new=<n>  Consistency proof
+"#
+    } else {
+        ""
+    };
+
     format!(
         r#"{}
 {pre_info}
@@ -90,14 +181,20 @@ Configured upstream (only used if experimental op forwarding is enabled):
 Available APIs:
 
     - GET  /_health  Health and version info
+    - GET  /_metrics Prometheus metrics
 
-    - GET  /*        Proxies to wrapped server; see PLC API docs:
-                     https://web.plc.directory/api/redoc
+    - GET  /:did               Resolve a DID document
+    - GET  /:did/data          Current PLC data for a DID
+    - GET  /:did/log           Operation log for a DID
+    - GET  /:did/log/audit     Audit log for a DID, including nullified ops
+    - GET  /:did/log/last      Latest operation for a DID
+    - GET  /export             Export ops in order (`?after=<time>&count=<n>`)
 
-                     tip: try `GET /{{did}}` to resolve an identity
+                     These are proxied to the wrapped server, see PLC API docs:
+                     https://web.plc.directory/api/redoc
 
 {post_info}
-
+{tlog_info}
 
 Allegedly is a suite of open-source CLI tools from for working with PLC logs,
 from microcosm:
@@ -115,6 +212,72 @@ from microcosm:
     )
 }
 
+fn tlog_of(tlog: &Option<SharedTlog>) -> Result<&SharedTlog> {
+    tlog.as_ref()
+        .ok_or_else(|| Error::from_string("tlog is not enabled", StatusCode::NOT_FOUND))
+}
+
+#[handler]
+fn tlog_checkpoint(Data(state): Data<&State>) -> Result<String> {
+    let tlog = tlog_of(&state.experimental.tlog)?;
+    Ok(tlog.read().unwrap().published().1)
+}
+
+#[derive(Deserialize)]
+struct InclusionQuery {
+    did: String,
+    cid: String,
+}
+
+/// Inclusion proof for an op, against the latest checkpoint
+///
+/// Formatted like c2sp.org/tlog-proof: the leaf index, the audit path, then
+/// the checkpoint the proof is for.
+#[handler]
+fn tlog_inclusion(
+    Data(state): Data<&State>,
+    Query(InclusionQuery { did, cid }): Query<InclusionQuery>,
+) -> Result<String> {
+    let tlog = tlog_of(&state.experimental.tlog)?.read().unwrap();
+    let (checkpoint, note) = tlog.published();
+    let Some((index, proof)) = tlog.prove_inclusion(&did, &cid, checkpoint.size) else {
+        return Err(Error::from_string(
+            "op not found in the latest checkpoint",
+            StatusCode::NOT_FOUND,
+        ));
+    };
+    Ok(format!(
+        "c2sp.org/tlog-proof@v1\nindex {index}\n{}\n{note}",
+        proof_text(&proof),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ConsistencyQuery {
+    old: u64,
+    /// defaults to the current tree size
+    new: Option<u64>,
+}
+
+#[handler]
+fn tlog_consistency(
+    Data(state): Data<&State>,
+    Query(ConsistencyQuery { old, new }): Query<ConsistencyQuery>,
+) -> Result<String> {
+    let tlog = tlog_of(&state.experimental.tlog)?.read().unwrap();
+    let new = new.unwrap_or(tlog.published().0.size);
+    let Some(proof) = tlog.prove_consistency(old, new) else {
+        return Err(Error::from_string(
+            format!(
+                "no proof from size {old} to {new}, log size is {}",
+                tlog.size()
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    Ok(proof_text(&proof))
+}
+
 #[handler]
 fn favicon() -> impl IntoResponse {
     include_bytes!("../favicon.ico").with_content_type("image/x-icon")
@@ -130,6 +293,18 @@ Failed to reach the {name} server. Sorry.
     )
 }
 
+fn not_here(status: StatusCode, reason: &str) -> Response {
+    Response::builder().status(status).body(format!(
+        r#"{}
+
+{reason}
+
+See `GET /` for the APIs available here.
+"#,
+        logo(&format!("mirror {}", status.as_u16()))
+    ))
+}
+
 fn bad_create_op(reason: &str) -> Response {
     Response::builder()
         .status(StatusCode::BAD_REQUEST)
@@ -144,13 +319,13 @@ NooOOOooooo: {reason}
 
 type PlcStatus = (bool, serde_json::Value);
 
-async fn plc_status(url: &Url, client: &Client) -> PlcStatus {
+async fn plc_status(url: &Url, client: &Client, timeout: Duration) -> PlcStatus {
     use serde_json::json;
 
     let mut url = url.clone();
     url.set_path("/_health");
 
-    let Ok(response) = client.get(url).timeout(Duration::from_secs(3)).send().await else {
+    let Ok(response) = client.get(url).timeout(timeout).send().await else {
         return (false, json!({"error": "cannot reach plc server"}));
     };
 
@@ -182,20 +357,19 @@ async fn plc_status(url: &Url, client: &Client) -> PlcStatus {
 
 #[derive(Clone)]
 struct GetLatestAt(Db);
-impl Fetcher<Dt> for GetLatestAt {
-    async fn fetch(&self) -> Result<Dt, Box<dyn std::error::Error>> {
-        let now = self.0.get_latest().await?.ok_or(anyhow::anyhow!(
-            "expected to find at least one thing in the db"
-        ))?;
-        Ok(now)
+impl Fetcher<Option<Dt>> for GetLatestAt {
+    type Error = PgError;
+    async fn fetch(&self) -> Result<Option<Dt>, PgError> {
+        self.0.get_latest().await
     }
 }
 
 #[derive(Clone)]
-struct CheckUpstream(Url, Client);
+struct CheckUpstream(Url, Client, Duration);
 impl Fetcher<PlcStatus> for CheckUpstream {
-    async fn fetch(&self) -> Result<PlcStatus, Box<dyn std::error::Error>> {
-        Ok(plc_status(&self.0, &self.1).await)
+    type Error = Infallible;
+    async fn fetch(&self) -> Result<PlcStatus, Infallible> {
+        Ok(plc_status(&self.0, &self.1, self.2).await)
     }
 }
 
@@ -205,25 +379,28 @@ async fn health(
         plc,
         client,
         sync_info,
+        timing,
+        wrapped_breaker,
         ..
     }): Data<&State>,
 ) -> impl IntoResponse {
     let mut overall_status = StatusCode::OK;
-    let (ok, wrapped_status) = plc_status(plc, client).await;
+    let (ok, wrapped_status) = plc_status(plc, client, timing.wrapped).await;
     if !ok {
         overall_status = StatusCode::BAD_GATEWAY;
     }
+    let fallback = state_fallback(wrapped_breaker);
     if let Some(SyncInfo {
         latest_at,
         upstream_status,
     }) = sync_info
     {
         // mirror mode
-        let (ok, upstream_status) = upstream_status.get().await.expect("plc_status infallible");
+        let Ok((ok, upstream_status)) = upstream_status.get().await;
         if !ok {
             overall_status = StatusCode::BAD_GATEWAY;
         }
-        let latest = latest_at.get().await.ok();
+        let latest = latest_at.get().await.ok().flatten();
         (
             overall_status,
             Json(serde_json::json!({
@@ -232,6 +409,7 @@ async fn health(
                 "wrapped_plc": wrapped_status,
                 "upstream_plc": upstream_status,
                 "latest_at": latest,
+                "fallback": fallback,
             })),
         )
     } else {
@@ -242,14 +420,108 @@ async fn health(
                 "server": "allegedly (mirror)",
                 "version": env!("CARGO_PKG_VERSION"),
                 "wrapped_plc": wrapped_status,
+                "fallback": fallback,
             })),
         )
     }
 }
 
+/// remove headers that only apply to a single connection
+///
+/// includes any extra headers named by the `Connection` header
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in [
+        CONNECTION,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+}
+
+/// headers where clients (or proxies) say who a request is from
+const FORWARDING_HEADERS: [&str; 5] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+/// remove client-supplied forwarding headers, which anyone can make up
+fn strip_forwarding(headers: &mut HeaderMap) {
+    for name in FORWARDING_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Copy a client's request headers for forwarding an op submission
+///
+/// hop-by-hop headers are dropped, and our user-agent wraps the client's.
+pub(crate) fn forwarded_headers(req: &Request) -> HeaderMap {
+    let mut headers = req.headers().clone();
+    log::trace!("original request headers: {headers:?}");
+    strip_hop_by_hop(&mut headers);
+    strip_forwarding(&mut headers);
+    headers.remove(ACCEPT_ENCODING);
+    headers.remove(HOST);
+    let client_ua = headers
+        .get(USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("unknown");
+    headers.insert(
+        USER_AGENT,
+        format!("{UA} (forwarding from {client_ua:?})")
+            .parse()
+            .unwrap(),
+    );
+    log::trace!("adjusted request headers: {headers:?}");
+    headers
+}
+
+/// request path and query mapped onto a target server, keeping its base path
+pub(crate) fn proxy_target(base: &Url, uri: &Uri) -> Url {
+    let mut target = base.clone();
+    let prefix = base.path().trim_end_matches('/');
+    target.set_path(&format!("{prefix}{}", uri.path()));
+    target.set_query(uri.query());
+    target
+}
+
+fn state_fallback(breaker: &Option<Arc<CircuitBreaker>>) -> serde_json::Value {
+    match breaker {
+        None => serde_json::json!("disabled"),
+        Some(b) => serde_json::json!({
+            "wrapped_circuit": if b.is_open() { "open" } else { "closed" },
+            "reads_served_upstream": metrics::PROXY_FALLBACKS.get(),
+        }),
+    }
+}
+
+#[handler]
+fn prometheus_metrics() -> impl IntoResponse {
+    metrics::render().with_content_type("text/plain; version=0.0.4")
+}
+
 fn proxy_response(res: reqwest::Response) -> Response {
     let http_res: poem::http::Response<reqwest::Body> = res.into();
-    let (parts, reqw_body) = http_res.into_parts();
+    let (mut parts, reqw_body) = http_res.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
 
     let parts = poem::ResponseParts {
         status: parts.status,
@@ -266,25 +538,78 @@ fn proxy_response(res: reqwest::Response) -> Response {
 
 #[handler]
 async fn proxy(req: &Request, Data(state): Data<&State>) -> Result<Response> {
-    let mut target = state.plc.clone();
-    target.set_path(req.uri().path());
-    target.set_query(req.uri().query());
-    let wrapped_res = state
+    let target = proxy_target(&state.plc, req.uri());
+
+    let mut headers = req.headers().clone();
+    strip_hop_by_hop(&mut headers);
+    strip_forwarding(&mut headers);
+    headers.remove(HOST); // reqwest sets it for the target
+    headers.remove(ACCEPT_ENCODING); // our compression middleware negotiates this
+    // upstream gets no forwarding headers: it's not ours to tell about clients
+    let upstream_headers = headers.clone();
+    if let Ok(ip) = state.client_ip.extract(req)
+        && let Ok(v) = ip.to_string().parse()
+    {
+        headers.insert("x-forwarded-for", v);
+    }
+
+    let wrapped_req = state
+        .client
+        .request(req.method().clone(), target)
+        .timeout(state.timing.wrapped) // should be low latency to wrapped server
+        .headers(headers);
+
+    let Some(breaker) = &state.wrapped_breaker else {
+        let wrapped_res = wrapped_req.send().await.map_err(|e| {
+            log::error!("upstream req fail: {e}");
+            Error::from_string(
+                failed_to_reach_named("wrapped reference PLC"),
+                StatusCode::BAD_GATEWAY,
+            )
+        })?;
+        return Ok(proxy_response(wrapped_res));
+    };
+
+    if breaker.allows() {
+        match wrapped_req.send().await {
+            Ok(res) if !res.status().is_server_error() => {
+                breaker.success();
+                return Ok(proxy_response(res));
+            }
+            Ok(res) => {
+                log::warn!("wrapped server error ({}), falling back", res.status());
+                breaker.failure();
+            }
+            Err(e) => {
+                log::warn!("wrapped server req fail ({e}), falling back");
+                breaker.failure();
+            }
+        }
+    }
+
+    // the local server can't answer: try upstream instead
+    metrics::PROXY_FALLBACKS.inc();
+    let target = proxy_target(&state.upstream, req.uri());
+    let upstream_res = state
         .client
-        .get(target)
-        .timeout(Duration::from_secs(3)) // should be low latency to wrapped server
-        .headers(req.headers().clone())
+        .request(req.method().clone(), target)
+        .timeout(state.timing.upstream_read)
+        .headers(upstream_headers)
         .send()
         .await
         .map_err(|e| {
-            log::error!("upstream req fail: {e}");
+            log::error!("upstream fallback req fail: {e}");
+            metrics::PROXY_FALLBACK_FAILURES.inc();
             Error::from_string(
-                failed_to_reach_named("wrapped reference PLC"),
+                failed_to_reach_named("wrapped or upstream PLC"),
                 StatusCode::BAD_GATEWAY,
             )
         })?;
 
-    Ok(proxy_response(wrapped_res))
+    let mut res = proxy_response(upstream_res);
+    res.headers_mut()
+        .insert("x-allegedly-fallback", "upstream".parse().unwrap());
+    Ok(res)
 }
 
 #[handler]
@@ -293,6 +618,7 @@ async fn forward_create_op_upstream(
         upstream,
         client,
         experimental,
+        timing,
         ..
     }): Data<&State>,
     Path(did): Path<String>,
@@ -312,27 +638,27 @@ async fn forward_create_op_upstream(
         }
     }
 
-    // adjust proxied headers
-    let mut headers: reqwest::header::HeaderMap = req.headers().clone();
-    log::trace!("original request headers: {headers:?}");
-    headers.insert("Host", upstream.host_str().unwrap().parse().unwrap());
-    let client_ua = headers
-        .get(USER_AGENT)
-        .map(|h| h.to_str().unwrap())
-        .unwrap_or("unknown");
-    headers.insert(
-        USER_AGENT,
-        format!("{UA} (forwarding from {client_ua:?})")
-            .parse()
-            .unwrap(),
-    );
-    log::trace!("adjusted request headers: {headers:?}");
+    let mut headers = forwarded_headers(req);
+
+    if let Some(scatter) = &experimental.scatter {
+        let targets = std::iter::once(upstream)
+            .chain(&scatter.upstreams)
+            .map(|u| proxy_target(u, req.uri()))
+            .collect();
+        log::trace!("scattering create op for {did}");
+        let body = body.into_bytes_limit(MAX_OP_BYTES).await?.to_vec();
+        let outcome = scatter_post(client, scatter, targets, headers, body)
+            .await
+            .map_err(|e| Error::from_string(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?;
+        return Ok(outcome.into_response());
+    }
 
-    let mut target = upstream.clone();
-    target.set_path(&did);
+    headers.insert("Host", upstream.host_str().unwrap().parse().unwrap());
+    let target = proxy_target(upstream, req.uri());
+    log::trace!("forwarding create op for {did} to {target}");
     let upstream_res = client
         .post(target)
-        .timeout(Duration::from_secs(15)) // be a little generous
+        .timeout(timing.upstream_write) // be a little generous
         .headers(headers)
         .body(reqwest::Body::wrap_stream(body.into_bytes_stream()))
         .send()
@@ -366,6 +692,31 @@ If you operate this server, try running with `--experimental-write-upstream`.
     )
 }
 
+/// read-only PLC API routes, all proxied to the wrapped server
+const PLC_READ_ROUTES: &[&str] = &[
+    "/export",
+    "/:did<did:plc:[^/]+>/data",
+    "/:did<did:plc:[^/]+>/log",
+    "/:did<did:plc:[^/]+>/log/audit",
+    "/:did<did:plc:[^/]+>/log/last",
+];
+
+/// route for resolving dids (GET) and submitting ops (POST)
+const PLC_DID_ROUTE: &str = "/:did<did:plc:[^/]+>";
+
+fn allow(methods: &'static str) -> impl Endpoint<Output = Response> {
+    make_sync(move |_| {
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ALLOW, methods)
+            .finish()
+    })
+}
+
+fn proxied_read() -> RouteMethod {
+    get(proxy).head(proxy).options(allow("GET, HEAD, OPTIONS"))
+}
+
 #[derive(Debug)]
 pub enum ListenConf {
     Acme {
@@ -377,22 +728,77 @@ pub enum ListenConf {
     Bind(SocketAddr),
 }
 
+/// Timeouts and cache lifetimes for `serve`
+#[derive(Debug, Clone)]
+pub struct TimingConf {
+    /// requests to the wrapped server, which should be low latency
+    pub wrapped: Duration,
+    /// forwarded op submissions to upstream
+    pub upstream_write: Duration,
+    /// reads sent upstream when the wrapped server can't answer
+    pub upstream_read: Duration,
+    /// how long the db's latest op time is cached for health checks
+    pub latest_at_validity: Duration,
+    /// how long upstream's health is cached for health checks
+    pub upstream_status_validity: Duration,
+}
+
+impl Default for TimingConf {
+    fn default() -> Self {
+        Self {
+            wrapped: Duration::from_secs(3),
+            upstream_write: Duration::from_secs(15),
+            upstream_read: Duration::from_secs(5),
+            latest_at_validity: Duration::from_secs(2),
+            upstream_status_validity: Duration::from_secs(6),
+        }
+    }
+}
+
+/// When to stop trying the wrapped server and serve reads from upstream
+#[derive(Debug, Clone)]
+pub struct FallbackConf {
+    /// consecutive wrapped server failures before reads skip it entirely
+    pub failure_threshold: u32,
+    /// how long to skip the wrapped server before trying it again
+    pub cooldown: Duration,
+}
+
+impl Default for FallbackConf {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ExperimentalConf {
     pub acme_domain: Option<String>,
     pub write_upstream: bool,
+    /// serve reads from upstream when the wrapped server can't answer
+    pub read_fallback: Option<FallbackConf>,
+    /// forward writes to these upstreams too (needs `write_upstream`)
+    pub scatter: Option<ScatterConf>,
+    /// serve a transparency log over the mirrored ops (kept in sync separately)
+    pub tlog: Option<SharedTlog>,
 }
 
 pub async fn serve(
     upstream: Url,
     plc: Url,
     listen: ListenConf,
+    limits: RateLimitConf,
+    timing: TimingConf,
     experimental: ExperimentalConf,
     db: Option<Db>,
 ) -> anyhow::Result<&'static str> {
     log::info!("starting server...");
 
-    // not using crate CLIENT: don't want the retries etc
+    // a plain client, not the configured `HttpClient`: proxied requests have
+    // a waiting caller, so they get one try within the timing limits instead
+    // of that client's retries and backoff
     let client = Client::builder()
         .user_agent(UA)
         .timeout(Duration::from_secs(10)) // fallback
@@ -401,50 +807,28 @@ pub async fn serve(
 
     // when `db` is None, we're running in wrap mode. no db access, no upstream sync
     let sync_info = db.map(|db| SyncInfo {
-        latest_at: CachedValue::new(GetLatestAt(db), Duration::from_secs(2)),
+        latest_at: CachedValue::new(GetLatestAt(db), timing.latest_at_validity),
         upstream_status: CachedValue::new(
-            CheckUpstream(upstream.clone(), client.clone()),
-            Duration::from_secs(6),
+            CheckUpstream(upstream.clone(), client.clone(), timing.wrapped),
+            timing.upstream_status_validity,
         ),
     });
 
     let state = State {
         client,
+        client_ip: limits.client_ip.clone(),
         plc,
         upstream: upstream.clone(),
         sync_info,
         experimental: experimental.clone(),
+        timing,
+        wrapped_breaker: experimental.read_fallback.clone().map(|conf| {
+            log::info!("enabling experimental read fallback to upstream: {conf:?}");
+            Arc::new(CircuitBreaker::new(conf))
+        }),
     };
 
-    let mut app = Route::new()
-        .at("/", get(hello))
-        .at("/favicon.ico", get(favicon))
-        .at("/_health", get(health));
-
-    if experimental.write_upstream {
-        log::info!("enabling experimental write forwarding to upstream");
-
-        let ip_limiter = IpLimiters::new(Quota::per_hour(10.try_into().unwrap()));
-        let did_limiter = CreatePlcOpLimiter::new(Quota::per_hour(4.try_into().unwrap()));
-
-        let upstream_proxier = forward_create_op_upstream
-            .with(GovernorMiddleware::new(did_limiter))
-            .with(GovernorMiddleware::new(ip_limiter));
-
-        app = app.at("/:any", get(proxy).post(upstream_proxier));
-    } else {
-        app = app.at("/:any", get(proxy).post(nope));
-    }
-
-    let app = app
-        .with(AddData::new(state))
-        .with(Cors::new().allow_credentials(false))
-        .with(Compression::new())
-        .with(GovernorMiddleware::new(IpLimiters::new(Quota::per_minute(
-            3000.try_into().expect("ratelimit middleware to build"),
-        ))))
-        .with(CatchPanic::new())
-        .with(Tracing);
+    let app = app(state, limits);
 
     match listen {
         ListenConf::Acme {
@@ -481,6 +865,78 @@ pub async fn serve(
     Ok("server (uh oh?)")
 }
 
+/// The mirror's routes and middleware
+fn app(state: State, limits: RateLimitConf) -> impl Endpoint + 'static {
+    let mut app = Route::new()
+        .at("/", get(hello))
+        .at("/favicon.ico", get(favicon))
+        .at("/_health", get(health))
+        .at("/_metrics", get(prometheus_metrics));
+
+    let experimental = &state.experimental;
+    if experimental.tlog.is_some() {
+        log::info!("enabling experimental tlog endpoints");
+        app = app
+            .at("/tlog/checkpoint", get(tlog_checkpoint))
+            .at("/tlog/proof/inclusion", get(tlog_inclusion))
+            .at("/tlog/proof/consistency", get(tlog_consistency));
+    }
+
+    if experimental.write_upstream {
+        log::info!("enabling experimental write forwarding to upstream");
+
+        let ip_limiter = IpLimiters::new(
+            limits.write_per_ip,
+            limits.client_ip.clone(),
+            limits.access.clone(),
+        );
+        let did_limiter = CreatePlcOpLimiter::new(limits.write_per_did);
+
+        let upstream_proxier = forward_create_op_upstream
+            .with(GovernorMiddleware::new(did_limiter))
+            .with(GovernorMiddleware::new(ip_limiter));
+
+        app = app.at(
+            PLC_DID_ROUTE,
+            get(proxy)
+                .head(proxy)
+                .post(upstream_proxier)
+                .options(allow("GET, HEAD, OPTIONS, POST")),
+        );
+    } else {
+        app = app.at(PLC_DID_ROUTE, proxied_read().post(nope));
+    }
+
+    for route in PLC_READ_ROUTES {
+        app = app.at(route, proxied_read());
+    }
+
+    app.catch_error(async |_: NotFoundError| {
+        not_here(StatusCode::NOT_FOUND, "Nothing here, sorry.")
+    })
+    .catch_error(async |_: MethodNotAllowedError| {
+        not_here(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "That method isn't allowed for this path.",
+        )
+    })
+    .with(AddData::new(state))
+    .with(Cors::new().allow_credentials(false))
+    .with(Compression::new())
+    .with(GovernorMiddleware::new(RouteLimiters::new(
+        limits.routes,
+        limits.client_ip.clone(),
+        limits.access.clone(),
+    )))
+    .with(GovernorMiddleware::new(IpLimiters::new(
+        limits.global,
+        limits.client_ip,
+        limits.access,
+    )))
+    .with(CatchPanic::new())
+    .with(Tracing)
+}
+
 async fn run<A, L>(app: A, listener: L) -> std::io::Result<()>
 where
     A: Endpoint + 'static,
@@ -521,3 +977,233 @@ You probably want to change your request to use HTTPS instead of HTTP.
     .run(app)
     .await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use poem::listener::Acceptor;
+
+    /// serve an endpoint on a local port
+    async fn spawn(ep: impl Endpoint + 'static) -> Url {
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        tokio::task::spawn(Server::new_with_acceptor(acceptor).run(ep));
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    /// a wrapped server that says what it got
+    #[handler]
+    fn echo(req: &Request) -> String {
+        format!("{} {}", req.method(), req.uri())
+    }
+
+    fn state(plc: Url) -> State {
+        State {
+            client: Client::new(),
+            client_ip: ClientIp::default(),
+            plc: plc.clone(),
+            upstream: plc,
+            sync_info: None,
+            experimental: ExperimentalConf {
+                acme_domain: None,
+                write_upstream: false,
+                read_fallback: None,
+                scatter: None,
+                tlog: None,
+            },
+            timing: TimingConf::default(),
+            wrapped_breaker: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_routes() {
+        let wrapped = spawn(Route::new().nest("/", echo)).await;
+        let mirror = spawn(app(state(wrapped), RateLimitConf::default())).await;
+        let client = Client::new();
+        let send = async |method: &str, path: &str| {
+            client
+                .request(method.parse().unwrap(), format!("{mirror}{path}"))
+                .send()
+                .await
+                .unwrap()
+        };
+
+        let res = send("GET", "did:plc:abc123/log/audit?x=1").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.text().await.unwrap(),
+            "GET /did:plc:abc123/log/audit?x=1"
+        );
+
+        let res = send("HEAD", "did:plc:abc123").await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send("OPTIONS", "export").await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD, OPTIONS");
+        // writes are off, but the did route still says it takes them
+        let res = send("POST", "did:plc:abc123").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // only did:plc dids, and only known sub-paths
+        for path in ["did:web:example.com", "did:plc:abc123/nope", "nope"] {
+            let res = send("GET", path).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+            assert!(res.text().await.unwrap().contains("Nothing here"));
+        }
+        let res = send("DELETE", "export").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(res.text().await.unwrap().contains("method isn't allowed"));
+    }
+
+    #[test]
+    fn test_proxy_target_keeps_base_path() {
+        let uri: Uri = "/did:plc:abc123/log?x=1".parse().unwrap();
+        for base in [
+            "https://plc.example.com/mirror/",
+            "https://plc.example.com/mirror",
+        ] {
+            assert_eq!(
+                proxy_target(&base.parse().unwrap(), &uri).as_str(),
+                "https://plc.example.com/mirror/did:plc:abc123/log?x=1"
+            );
+        }
+        let uri: Uri = "/export".parse().unwrap();
+        assert_eq!(
+            proxy_target(&"https://plc.example.com".parse().unwrap(), &uri).as_str(),
+            "https://plc.example.com/export"
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(FallbackConf {
+            failure_threshold: 3,
+            cooldown: Duration::from_millis(50),
+        });
+        // closed: failures below the threshold, or broken up by a success
+        breaker.failure();
+        breaker.failure();
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert!(breaker.allows());
+        assert!(!breaker.is_open());
+
+        // open
+        breaker.failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allows());
+
+        // half-open after the cooldown: one failure re-opens it
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allows());
+        assert!(breaker.is_open());
+        breaker.failure();
+        assert!(!breaker.allows());
+
+        // and one success closes it, with a fresh failure count
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allows());
+        breaker.success();
+        assert!(!breaker.is_open());
+        breaker.failure();
+        assert!(breaker.allows());
+        assert!(!breaker.is_open());
+    }
+
+    /// says which forwarding header it got
+    #[handler]
+    fn forwarded_for(req: &Request) -> String {
+        req.header("x-forwarded-for").unwrap_or("none").to_string()
+    }
+
+    #[handler]
+    fn broken() -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    #[handler]
+    async fn slow() -> &'static str {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        "too late"
+    }
+
+    fn fallback_state(wrapped: Url, upstream: Url) -> State {
+        let mut state = state(wrapped);
+        state.upstream = upstream;
+        state.timing.upstream_read = Duration::from_millis(200);
+        state.wrapped_breaker = Some(Arc::new(CircuitBreaker::new(FallbackConf::default())));
+        state
+    }
+
+    #[tokio::test]
+    async fn test_proxy_forwarding_headers() {
+        let client = Client::new();
+        let get = async |mirror: &Url| {
+            let res = client
+                .get(format!("{mirror}did:plc:abc123"))
+                .header("x-forwarded-for", "6.6.6.6")
+                .header("forwarded", "for=6.6.6.6")
+                .send()
+                .await
+                .unwrap();
+            let fallback = res.headers().contains_key("x-allegedly-fallback");
+            (fallback, res.text().await.unwrap())
+        };
+
+        // the wrapped server gets the peer, not what the client claimed
+        let wrapped = spawn(Route::new().nest("/", forwarded_for)).await;
+        let mirror = spawn(app(state(wrapped), RateLimitConf::default())).await;
+        assert_eq!(get(&mirror).await, (false, "127.0.0.1".to_string()));
+
+        // upstream gets nothing about the client
+        let wrapped = spawn(Route::new().nest("/", broken)).await;
+        let upstream = spawn(Route::new().nest("/", forwarded_for)).await;
+        let state = fallback_state(wrapped, upstream);
+        let mirror = spawn(app(state, RateLimitConf::default())).await;
+        assert_eq!(get(&mirror).await, (true, "none".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_fallback_timeout() {
+        let wrapped = spawn(Route::new().nest("/", broken)).await;
+        let upstream = spawn(Route::new().nest("/", slow)).await;
+        let mirror = spawn(app(
+            fallback_state(wrapped, upstream),
+            RateLimitConf::default(),
+        ))
+        .await;
+        let t0 = Instant::now();
+        let res = Client::new()
+            .get(format!("{mirror}did:plc:abc123"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert!(t0.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("connection", "keep-alive, x-private"),
+            ("keep-alive", "timeout=5"),
+            ("x-private", "secret"),
+            ("te", "trailers"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("content-type", "application/json"),
+        ] {
+            headers.insert(name, value.parse().unwrap());
+        }
+        strip_hop_by_hop(&mut headers);
+        let left: Vec<_> = headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(left, ["content-type"]);
+    }
+}
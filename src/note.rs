@@ -0,0 +1,412 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use thiserror::Error;
+
+/// signature type byte for ed25519 note keys
+const ALG_ED25519: u8 = 0x01;
+/// signature type byte for witness cosignatures, as in <https://c2sp.org/tlog-cosignature>
+const ALG_COSIGNATURE_V1: u8 = 0x04;
+
+#[derive(Debug, Error)]
+pub enum NoteError {
+    #[error("malformed note key")]
+    BadKey,
+    #[error("malformed signed note")]
+    BadNote,
+    #[error("key names can't be empty or contain whitespace or '+'")]
+    BadName,
+    #[error("failed to get randomness for a new key: {0}")]
+    Random(getrandom::Error),
+}
+
+fn check_name(name: &str) -> Result<(), NoteError> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '+') {
+        return Err(NoteError::BadName);
+    }
+    Ok(())
+}
+
+/// key ID: the first four bytes of SHA256(name || "\n" || alg || public key)
+fn key_id(name: &str, alg: u8, key: &VerifyingKey) -> [u8; 4] {
+    let hash = Sha256::new()
+        .chain_update(name)
+        .chain_update("\n")
+        .chain_update([alg])
+        .chain_update(key.as_bytes())
+        .finalize();
+    hash[..4].try_into().unwrap()
+}
+
+/// What a witness signs: the checkpoint text, prefixed with the time it was
+/// cosigned
+fn cosignature_message(text: &str, timestamp: u64) -> String {
+    format!("cosignature/v1\ntime {timestamp}\n{text}")
+}
+
+/// name, key ID, alg byte, and public key or seed
+type KeyParts<'a> = (&'a str, [u8; 4], u8, [u8; 32]);
+
+/// parse `<name>+<hex key id>+<base64 alg and key>` into its parts
+fn key_parts(s: &str) -> Result<KeyParts<'_>, NoteError> {
+    // base64 can contain '+', so it has to be the last part
+    let mut parts = s.splitn(3, '+');
+    let (Some(name), Some(id), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(NoteError::BadKey);
+    };
+    check_name(name).map_err(|_| NoteError::BadKey)?;
+    let id = u32::from_str_radix(id, 16)
+        .map_err(|_| NoteError::BadKey)?
+        .to_be_bytes();
+    let key = BASE64_STANDARD.decode(key).map_err(|_| NoteError::BadKey)?;
+    let Some((&alg, key)) = key.split_first() else {
+        return Err(NoteError::BadKey);
+    };
+    if alg != ALG_ED25519 && alg != ALG_COSIGNATURE_V1 {
+        return Err(NoteError::BadKey);
+    }
+    let key = key.try_into().map_err(|_| NoteError::BadKey)?;
+    Ok((name, id, alg, key))
+}
+
+fn key_string(name: &str, id: [u8; 4], alg: u8, key: &[u8; 32]) -> String {
+    let mut bytes = vec![alg];
+    bytes.extend_from_slice(key);
+    format!(
+        "{name}+{:08x}+{}",
+        u32::from_be_bytes(id),
+        BASE64_STANDARD.encode(bytes)
+    )
+}
+
+/// An ed25519 key for signing notes, as in <https://c2sp.org/signed-note>
+pub struct NoteSigner {
+    name: String,
+    id: [u8; 4],
+    key: SigningKey,
+}
+
+impl NoteSigner {
+    pub fn generate(name: &str) -> Result<Self, NoteError> {
+        check_name(name)?;
+        let mut seed = [0; 32];
+        getrandom::fill(&mut seed).map_err(NoteError::Random)?;
+        let key = SigningKey::from_bytes(&seed);
+        Ok(Self {
+            name: name.to_string(),
+            id: key_id(name, ALG_ED25519, &key.verifying_key()),
+            key,
+        })
+    }
+
+    /// Parse a private key string: `PRIVATE+KEY+<name>+<hex id>+<base64 key>`
+    pub fn from_skey(skey: &str) -> Result<Self, NoteError> {
+        let rest = skey
+            .trim()
+            .strip_prefix("PRIVATE+KEY+")
+            .ok_or(NoteError::BadKey)?;
+        let (name, id, ALG_ED25519, seed) = key_parts(rest)? else {
+            return Err(NoteError::BadKey);
+        };
+        let key = SigningKey::from_bytes(&seed);
+        if key_id(name, ALG_ED25519, &key.verifying_key()) != id {
+            return Err(NoteError::BadKey);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            id,
+            key,
+        })
+    }
+
+    pub fn skey(&self) -> String {
+        format!(
+            "PRIVATE+KEY+{}",
+            key_string(&self.name, self.id, ALG_ED25519, self.key.as_bytes())
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn verifier(&self) -> NoteVerifier {
+        NoteVerifier {
+            name: self.name.clone(),
+            id: self.id,
+            alg: ALG_ED25519,
+            key: self.key.verifying_key(),
+        }
+    }
+
+    /// The verifier for this key's witness cosignatures
+    ///
+    /// Same key, but a different key ID and vkey type byte.
+    pub fn cosigner(&self) -> NoteVerifier {
+        let key = self.key.verifying_key();
+        NoteVerifier {
+            name: self.name.clone(),
+            id: key_id(&self.name, ALG_COSIGNATURE_V1, &key),
+            alg: ALG_COSIGNATURE_V1,
+            key,
+        }
+    }
+
+    pub fn sign(&self, text: &str) -> NoteSignature {
+        NoteSignature {
+            name: self.name.clone(),
+            key_id: self.id,
+            sig: self.key.sign(text.as_bytes()).to_bytes().to_vec(),
+        }
+    }
+
+    /// A `cosignature/v1` over a checkpoint, made at `timestamp` (unix seconds)
+    ///
+    /// The signature bytes are the big-endian timestamp, then the ed25519
+    /// signature.
+    pub fn cosign(&self, text: &str, timestamp: u64) -> NoteSignature {
+        let message = cosignature_message(text, timestamp);
+        let mut sig = timestamp.to_be_bytes().to_vec();
+        sig.extend_from_slice(&self.key.sign(message.as_bytes()).to_bytes());
+        NoteSignature {
+            name: self.name.clone(),
+            key_id: self.cosigner().id,
+            sig,
+        }
+    }
+}
+
+impl fmt::Debug for NoteSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // no private key in logs
+        f.debug_struct("NoteSigner")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A public key for checking note signatures
+#[derive(Debug, Clone)]
+pub struct NoteVerifier {
+    name: String,
+    id: [u8; 4],
+    /// plain ed25519 or cosignature/v1
+    alg: u8,
+    key: VerifyingKey,
+}
+
+impl NoteVerifier {
+    /// Parse a verifier key string: `<name>+<hex id>+<base64 key>`
+    ///
+    /// Both plain ed25519 keys and witness cosignature keys work.
+    pub fn from_vkey(vkey: &str) -> Result<Self, NoteError> {
+        let (name, id, alg, key) = key_parts(vkey.trim())?;
+        let key = VerifyingKey::from_bytes(&key).map_err(|_| NoteError::BadKey)?;
+        if key_id(name, alg, &key) != id {
+            return Err(NoteError::BadKey);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            id,
+            alg,
+            key,
+        })
+    }
+
+    pub fn vkey(&self) -> String {
+        key_string(&self.name, self.id, self.alg, self.key.as_bytes())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn verify(&self, text: &str, sig: &NoteSignature) -> bool {
+        if sig.name != self.name || sig.key_id != self.id {
+            return false;
+        }
+        if self.alg == ALG_COSIGNATURE_V1 {
+            return self.cosigned_at(text, sig).is_some();
+        }
+        let Ok(signature) = Signature::from_slice(&sig.sig) else {
+            return false;
+        };
+        self.key.verify(text.as_bytes(), &signature).is_ok()
+    }
+
+    /// When a valid cosignature by this key says it was made (unix seconds)
+    pub fn cosigned_at(&self, text: &str, sig: &NoteSignature) -> Option<u64> {
+        if self.alg != ALG_COSIGNATURE_V1 || sig.name != self.name || sig.key_id != self.id {
+            return None;
+        }
+        let (timestamp, signature) = sig.sig.split_first_chunk::<8>()?;
+        let timestamp = u64::from_be_bytes(*timestamp);
+        let signature = Signature::from_slice(signature).ok()?;
+        let message = cosignature_message(text, timestamp);
+        self.key
+            .verify(message.as_bytes(), &signature)
+            .ok()
+            .map(|()| timestamp)
+    }
+}
+
+/// One signature line of a note
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteSignature {
+    pub name: String,
+    pub key_id: [u8; 4],
+    pub sig: Vec<u8>,
+}
+
+/// Some text plus signatures over it
+///
+/// Serialized as the text, a blank line, then one `— <name> <base64>` line per
+/// signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    /// always ends in a newline
+    pub text: String,
+    pub signatures: Vec<NoteSignature>,
+}
+
+impl Note {
+    pub fn sign(text: String, signer: &NoteSigner) -> Self {
+        let signatures = vec![signer.sign(&text)];
+        Self { text, signatures }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, NoteError> {
+        let split = s.rfind("\n\n").ok_or(NoteError::BadNote)?;
+        let (text, sigs) = (&s[..split + 1], &s[split + 2..]);
+        let mut signatures = vec![];
+        for line in sigs.lines() {
+            let (name, sig) = line
+                .strip_prefix("— ")
+                .and_then(|l| l.split_once(' '))
+                .ok_or(NoteError::BadNote)?;
+            let sig = BASE64_STANDARD
+                .decode(sig)
+                .map_err(|_| NoteError::BadNote)?;
+            if sig.len() < 5 {
+                return Err(NoteError::BadNote);
+            }
+            signatures.push(NoteSignature {
+                name: name.to_string(),
+                key_id: sig[..4].try_into().unwrap(),
+                sig: sig[4..].to_vec(),
+            });
+        }
+        if signatures.is_empty() {
+            return Err(NoteError::BadNote);
+        }
+        Ok(Self {
+            text: text.to_string(),
+            signatures,
+        })
+    }
+
+    pub fn add_signature(&mut self, signer: &NoteSigner) {
+        self.signatures.retain(|s| s.name != signer.name);
+        self.signatures.push(signer.sign(&self.text));
+    }
+
+    /// Add a witness cosignature, replacing any older one by the same key
+    pub fn add_cosignature(&mut self, signer: &NoteSigner, timestamp: u64) {
+        self.signatures.retain(|s| s.name != signer.name);
+        self.signatures.push(signer.cosign(&self.text, timestamp));
+    }
+
+    pub fn verified_by(&self, verifier: &NoteVerifier) -> bool {
+        self.signatures
+            .iter()
+            .any(|sig| verifier.verify(&self.text, sig))
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.text)?;
+        for NoteSignature { name, key_id, sig } in &self.signatures {
+            let mut bytes = key_id.to_vec();
+            bytes.extend_from_slice(sig);
+            writeln!(f, "— {name} {}", BASE64_STANDARD.encode(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys_roundtrip() {
+        let signer = NoteSigner::generate("example.com/log").unwrap();
+        let again = NoteSigner::from_skey(&signer.skey()).unwrap();
+        assert_eq!(again.skey(), signer.skey());
+        let verifier = NoteVerifier::from_vkey(&signer.verifier().vkey()).unwrap();
+        assert_eq!(verifier.vkey(), signer.verifier().vkey());
+        assert!(NoteSigner::generate("has space").is_err());
+        assert!(NoteVerifier::from_vkey("example.com/log+00000000+AAAA").is_err());
+    }
+
+    #[test]
+    fn test_known_vkey() {
+        // from the c2sp signed-note spec
+        let vkey = "PeterNeumann+c74f20a3+ARpc2QcUPDhMQegwxbzhKqiBfsVkmqq/LDE4izWy10TW";
+        assert_eq!(NoteVerifier::from_vkey(vkey).unwrap().vkey(), vkey);
+    }
+
+    #[test]
+    fn test_sign_parse_verify() {
+        let log = NoteSigner::generate("log").unwrap();
+        let witness = NoteSigner::generate("witness").unwrap();
+        let mut note = Note::sign("log\n3\nAAAA\n".into(), &log);
+        note.add_signature(&witness);
+
+        let parsed = Note::parse(&note.to_string()).unwrap();
+        assert_eq!(parsed, note);
+        assert!(parsed.verified_by(&log.verifier()));
+        assert!(parsed.verified_by(&witness.verifier()));
+
+        let other = NoteSigner::generate("log").unwrap();
+        assert!(!parsed.verified_by(&other.verifier()));
+
+        let tampered = note.to_string().replace("\n3\n", "\n4\n");
+        assert!(!Note::parse(&tampered).unwrap().verified_by(&log.verifier()));
+    }
+
+    #[test]
+    fn test_cosignature() {
+        let log = NoteSigner::generate("log").unwrap();
+        let witness = NoteSigner::generate("witness").unwrap();
+        let text = "log\n3\nAAAA\n";
+        let mut note = Note::sign(text.into(), &log);
+        note.add_cosignature(&witness, 1679315147);
+
+        let cosigner = NoteVerifier::from_vkey(&witness.cosigner().vkey()).unwrap();
+        assert_eq!(cosigner.vkey(), witness.cosigner().vkey());
+        assert_ne!(cosigner.vkey(), witness.verifier().vkey());
+        let parsed = Note::parse(&note.to_string()).unwrap();
+        assert!(parsed.verified_by(&log.verifier()));
+        assert!(parsed.verified_by(&cosigner));
+        // it's not a plain note signature
+        assert!(!parsed.verified_by(&witness.verifier()));
+
+        // timestamp, then a signature over the timestamped message
+        let sig = &parsed.signatures[1];
+        assert_eq!(cosigner.cosigned_at(text, sig), Some(1679315147));
+        assert_eq!(sig.sig[..8], 1679315147u64.to_be_bytes());
+        let signature = Signature::from_slice(&sig.sig[8..]).unwrap();
+        let message = "cosignature/v1\ntime 1679315147\nlog\n3\nAAAA\n";
+        assert!(
+            witness
+                .key
+                .verifying_key()
+                .verify(message.as_bytes(), &signature)
+                .is_ok()
+        );
+        assert_eq!(cosigner.cosigned_at("log\n4\nAAAA\n", sig), None);
+    }
+}
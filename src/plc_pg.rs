@@ -1,9 +1,12 @@
-use crate::{Dt, ExportPage, PageBoundaryState};
+use crate::{CaughtUp, Dt, ExportPage, Op, channel_stream};
+use futures::{Stream, TryStreamExt};
 use native_tls::{Certificate, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
+use std::convert::Infallible;
 use std::path::PathBuf;
 use std::pin::pin;
 use std::time::Instant;
+use thiserror::Error;
 use tokio::{
     sync::{mpsc, oneshot},
     task::{JoinHandle, spawn},
@@ -16,7 +19,33 @@ use tokio_postgres::{
     types::{Json, Type},
 };
 
-fn get_tls(cert: PathBuf) -> anyhow::Result<MakeTlsConnector> {
+/// The did-method-plc migrations this crate knows how to work with
+const EXPECTED_MIGRATIONS: &[&str] = &[
+    "_20221020T204908820Z",
+    "_20230223T215019669Z",
+    "_20230406T174552885Z",
+    "_20231128T203323431Z",
+];
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error(transparent)]
+    Pg(#[from] PgError),
+    #[error("failed to read postgres cert: {0}")]
+    Cert(#[from] std::io::Error),
+    #[error("failed to set up postgres tls: {0}")]
+    Tls(#[from] native_tls::Error),
+    #[error("postgres connection task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("unexpected did-method-plc migrations {found:?}, expected {EXPECTED_MIGRATIONS:?}")]
+    SchemaMismatch { found: Vec<String> },
+    #[error("postgres: {table} table has {rows} rows, and `reset` was not requested")]
+    NonEmptyTarget { table: &'static str, rows: i64 },
+    #[error("failed to get pages to write: {0}")]
+    Source(Box<dyn std::error::Error + Send + Sync>),
+}
+
+fn get_tls(cert: PathBuf) -> Result<MakeTlsConnector, DbError> {
     let cert = std::fs::read(cert)?;
     let cert = Certificate::from_pem(&cert)?;
     let connector = TlsConnector::builder().add_root_certificate(cert).build()?;
@@ -46,7 +75,7 @@ pub struct Db {
 }
 
 impl Db {
-    pub async fn new(pg_uri: &str, cert: Option<PathBuf>) -> Result<Self, anyhow::Error> {
+    pub async fn new(pg_uri: &str, cert: Option<PathBuf>) -> Result<Self, DbError> {
         // we're going to interact with did-method-plc's database, so make sure
         // it's what we expect: check for db migrations.
         log::trace!("checking migrations...");
@@ -65,15 +94,9 @@ impl Db {
             .iter()
             .map(|row| row.get(0))
             .collect();
-        assert_eq!(
-            &migrations,
-            &[
-                "_20221020T204908820Z",
-                "_20230223T215019669Z",
-                "_20230406T174552885Z",
-                "_20231128T203323431Z",
-            ]
-        );
+        if migrations != EXPECTED_MIGRATIONS {
+            return Err(DbError::SchemaMismatch { found: migrations });
+        }
         drop(client);
         // make sure the connection worker thing doesn't linger
         conn_task.await??;
@@ -109,15 +132,173 @@ impl Db {
         drop(task);
         Ok(dt)
     }
+
+    /// Stream ops in `createdAt` order, after `after` (exclusive) and before
+    /// `until` (exclusive)
+    ///
+    /// ties are ordered by did then cid, so the order is deterministic.
+    pub async fn ops_between(
+        &self,
+        after: Option<Dt>,
+        until: Option<Dt>,
+    ) -> Result<impl Stream<Item = Result<Op, PgError>> + use<>, PgError> {
+        let (client, task) = self.connect().await?;
+        let rows = client
+            .query_raw(
+                r#"SELECT did, cid, "createdAt", nullified, operation
+                     FROM operations
+                    WHERE ($1::timestamptz IS NULL OR "createdAt" > $1)
+                      AND ($2::timestamptz IS NULL OR "createdAt" < $2)
+                    ORDER BY "createdAt", did, cid"#,
+                [&after, &until],
+            )
+            .await?;
+        Ok(rows.map_ok(move |row| {
+            // keep the connection alive as long as the stream
+            let _ = (&client, &task);
+            let Json(operation) = row.get(4);
+            Op {
+                did: row.get(0),
+                cid: row.get(1),
+                created_at: row.get(2),
+                nullified: row.get(3),
+                operation,
+            }
+        }))
+    }
+
+    /// The latest ingest seq, or 0 if nothing's been ingested yet
+    pub async fn ingest_seq(&self) -> Result<i64, PgError> {
+        let (client, task) = self.connect().await?;
+        init_ingest(&client).await?;
+        let seq = client
+            .query_one("SELECT coalesce(max(seq), 0) FROM allegedly_ingest", &[])
+            .await?
+            .get(0);
+        drop(task);
+        Ok(seq)
+    }
+
+    /// Stream ops ingested by [`stream_to_pg`] after ingest seq `seq`, in
+    /// ingest order, with the seq each was ingested at
+    pub async fn ops_ingested_after(
+        &self,
+        seq: i64,
+    ) -> Result<impl Stream<Item = Result<(i64, Op), PgError>> + use<>, PgError> {
+        let (client, task) = self.connect().await?;
+        init_ingest(&client).await?;
+        let rows = client
+            .query_raw(
+                r#"SELECT i.seq, o.did, o.cid, o."createdAt", o.nullified, o.operation
+                     FROM allegedly_ingest i
+                     JOIN operations o ON o.did = i.did AND o.cid = i.cid
+                    WHERE i.seq > $1
+                    ORDER BY i.seq"#,
+                [&seq],
+            )
+            .await?;
+        Ok(rows.map_ok(move |row| {
+            // keep the connection alive as long as the stream
+            let _ = (&client, &task);
+            let Json(operation) = row.get(5);
+            let op = Op {
+                did: row.get(1),
+                cid: row.get(2),
+                created_at: row.get(3),
+                nullified: row.get(4),
+                operation,
+            };
+            (row.get(0), op)
+        }))
+    }
+
+    /// All of a DID's ops, including nullified ones, in `createdAt` order
+    pub async fn ops_for_did(&self, did: &str) -> Result<Vec<Op>, PgError> {
+        let (client, task) = self.connect().await?;
+        let ops = client
+            .query(
+                r#"SELECT did, cid, "createdAt", nullified, operation
+                     FROM operations
+                    WHERE did = $1
+                    ORDER BY "createdAt", cid"#,
+                &[&did],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let Json(operation) = row.get(4);
+                Op {
+                    did: row.get(0),
+                    cid: row.get(1),
+                    created_at: row.get(2),
+                    nullified: row.get(3),
+                    operation,
+                }
+            })
+            .collect();
+        drop(task);
+        Ok(ops)
+    }
+}
+
+/// Our own record of ingest order, alongside did-method-plc's tables
+///
+/// Ops don't always arrive in `createdAt` order, so consumers that need to
+/// pick up everything new (like [`crate::tlog_sync`]) follow `seq` instead.
+/// There's one writer, [`stream_to_pg`], committing in order, so a reader
+/// never sees a later seq before an earlier one.
+async fn init_ingest(client: &Client) -> Result<(), PgError> {
+    client
+        .execute(
+            r#"CREATE TABLE IF NOT EXISTS allegedly_ingest (
+                   seq bigserial PRIMARY KEY,
+                   did text NOT NULL,
+                   cid text NOT NULL
+               )"#,
+            &[],
+        )
+        .await?;
+    Ok(())
 }
 
+/// Insert pages from a channel, like [`stream_to_pg`]
+///
+/// With `caught_up` (from a [`crate::caught_up`] stage before this one), the
+/// end of the pages is logged as the point where the db has caught up, or
+/// warned about if they ended without catching up.
 pub async fn pages_to_pg(
     db: Db,
-    mut pages: mpsc::Receiver<ExportPage>,
-) -> anyhow::Result<&'static str> {
+    pages: mpsc::Receiver<ExportPage>,
+    caught_up: Option<oneshot::Receiver<CaughtUp>>,
+) -> Result<&'static str, DbError> {
+    stream_to_pg(db, channel_stream::<Infallible>(pages)).await?;
+    let Some(caught_up) = caught_up else {
+        return Ok("pages_to_pg");
+    };
+    match caught_up.await {
+        Ok(CaughtUp { last_at, reason }) => {
+            log::info!("postgres caught up with upstream ({reason:?}), latest op at {last_at:?}");
+            Ok("pages_to_pg (caught up)")
+        }
+        Err(_) => {
+            log::warn!("postgres pages ended before catching up with upstream");
+            Ok("pages_to_pg (not caught up)")
+        }
+    }
+}
+
+/// Insert a stream of pages into the operations table, one transaction per page
+///
+/// Ops that are already present are skipped. New ones are also given the next
+/// ingest seq (see [`Db::ops_ingested_after`]).
+pub async fn stream_to_pg<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    db: Db,
+    pages: impl Stream<Item = Result<ExportPage, E>>,
+) -> Result<&'static str, DbError> {
     log::info!("starting pages_to_pg writer...");
 
     let (mut client, task) = db.connect().await?;
+    init_ingest(&client).await?;
 
     let ops_stmt = client
         .prepare(
@@ -126,6 +307,9 @@ pub async fn pages_to_pg(
                    ON CONFLICT do nothing"#,
         )
         .await?;
+    let ingest_stmt = client
+        .prepare(r#"INSERT INTO allegedly_ingest (did, cid) VALUES ($1, $2)"#)
+        .await?;
     let did_stmt = client
         .prepare(r#"INSERT INTO dids (did) VALUES ($1) ON CONFLICT do nothing"#)
         .await?;
@@ -134,11 +318,16 @@ pub async fn pages_to_pg(
     let mut ops_inserted = 0;
     let mut dids_inserted = 0;
 
-    while let Some(page) = pages.recv().await {
+    let mut pages = pin!(pages);
+    while let Some(page) = pages
+        .try_next()
+        .await
+        .map_err(|e| DbError::Source(e.into()))?
+    {
         log::trace!("writing page with {} ops", page.ops.len());
         let tx = client.transaction().await?;
         for op in page.ops {
-            ops_inserted += tx
+            let inserted = tx
                 .execute(
                     &ops_stmt,
                     &[
@@ -150,6 +339,10 @@ pub async fn pages_to_pg(
                     ],
                 )
                 .await?;
+            if inserted > 0 {
+                tx.execute(&ingest_stmt, &[&op.did, &op.cid]).await?;
+            }
+            ops_inserted += inserted;
             dids_inserted += tx.execute(&did_stmt, &[&op.did]).await?;
         }
         tx.commit().await?;
@@ -173,7 +366,7 @@ pub async fn pages_to_pg(
 ///
 /// fails: if the backfill data violates the primary key constraint (unique did*cid)
 ///
-/// panics: if the operations or dids tables are not empty, unless reset is true
+/// fails: if the operations or dids tables are not empty, unless reset is true
 ///
 /// recommended postgres setting: `max_wal_size=4GB` (or more)
 pub async fn backfill_to_pg(
@@ -181,7 +374,7 @@ pub async fn backfill_to_pg(
     reset: bool,
     mut pages: mpsc::Receiver<ExportPage>,
     notify_last_at: Option<oneshot::Sender<Option<Dt>>>,
-) -> anyhow::Result<&'static str> {
+) -> Result<&'static str, DbError> {
     let (mut client, task) = db.connect().await?;
 
     let t0 = Instant::now();
@@ -197,12 +390,12 @@ pub async fn backfill_to_pg(
                 log::warn!("postgres reset: deleted {n} from {table}");
             }
         } else {
-            let n: i64 = tx
+            let rows: i64 = tx
                 .query_one(&format!("SELECT count(*) FROM {table}"), &[])
                 .await?
                 .get(0);
-            if n > 0 {
-                panic!("postgres: {table} table was not empty and `reset` not requested");
+            if rows > 0 {
+                return Err(DbError::NonEmptyTarget { table, rows });
             }
         }
     }
@@ -250,10 +443,8 @@ pub async fn backfill_to_pg(
                 ])
                 .await?;
         }
-        if notify_last_at.is_some()
-            && let Some(s) = PageBoundaryState::new(&page)
-        {
-            last_at = last_at.filter(|&l| l >= s.last_at).or(Some(s.last_at));
+        if let Some(op) = page.ops.last() {
+            last_at = last_at.max(Some(op.created_at));
         }
     }
     log::debug!("finished receiving bulk pages");
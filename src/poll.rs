@@ -1,8 +1,17 @@
-use crate::{CLIENT, Dt, ExportPage, Op, OpKey};
-use reqwest::Url;
+use crate::{
+    DeadLetter, Dt, ExportPage, HttpClient, Op, OpKey, RejectError, metrics, reject,
+    stream_to_channel,
+};
+use futures::Stream;
+use reqwest::{
+    StatusCode, Url,
+    header::{HeaderMap, RETRY_AFTER},
+};
+use reqwest_middleware::RequestBuilder;
+use std::collections::{HashSet, VecDeque};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task::JoinHandle};
 
 #[derive(Debug, Error)]
 pub enum GetPageError {
@@ -12,11 +21,41 @@ pub enum GetPageError {
     ReqwestMiddleware(#[from] reqwest_middleware::Error),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    #[error("upstream responded with {0}")]
+    Status(StatusCode),
+    #[error("page was cut off mid-line after {} complete ops", page.ops.len())]
+    Truncated {
+        page: ExportPage,
+        /// from the response's headers, which arrived fine
+        limits: RateLimitHeaders,
+    },
+    #[error("failed to join page parsing task: {0}")]
+    ParseTask(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Rejected(#[from] RejectError),
+    #[error("upstream rate limited us (retry after {retry_after:?})")]
+    RateLimited {
+        retry_after: Option<Duration>,
+        limits: RateLimitHeaders,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum PollError {
+    #[error(transparent)]
+    GetPage(#[from] GetPageError),
+    #[error("a catch-up slice stopped before finishing")]
+    SliceStopped,
+    #[error("a catch-up slice is stuck at {at}: a full page of ops at one timestamp")]
+    SliceStuck { at: Dt },
+    #[error("the pages being followed ended before catching up")]
+    SourceClosed,
 }
 
 /// ops are primary-keyed by (did, cid)
 /// plc orders by `created_at` but does not guarantee distinct times per op
 /// we assume that the order will at least be deterministic: this may be unsound
+/// (so `PageBoundaryState` dedups by key, and tolerates time going backwards)
 #[derive(Debug, PartialEq)]
 pub struct LastOp {
     pub created_at: Dt,   // any op greater is definitely not duplicated
@@ -51,136 +90,523 @@ impl From<Dt> for LastOp {
     }
 }
 
+/// How many recently-seen op keys to remember for deduplication
+///
+/// A few pages' worth, so duplicates spread over several timestamps near a page
+/// boundary are still caught, without remembering every op forever.
+const DEDUP_WINDOW: usize = 4000;
+
 /// State for removing duplicates ops between PLC export page boundaries
+///
+/// Duplicate ops from /export normally only occur at the previous page's last
+/// timestamp, but that's not relied on: any op whose key is in a bounded window
+/// of recently-seen keys is dropped. Ops that arrive out of time order are
+/// kept, and counted and logged as anomalies.
 #[derive(Debug, PartialEq)]
 pub struct PageBoundaryState {
-    /// The previous page's last timestamp
-    ///
-    /// Duplicate ops from /export only occur for the same exact timestamp
+    /// The latest timestamp seen so far
     pub last_at: Dt,
-    /// The previous page's ops at its last timestamp
-    keys_at: Vec<OpKey>, // expected to ~always be length one
+    /// Recently-seen keys, oldest first
+    recent: VecDeque<OpKey>,
+    recent_set: HashSet<OpKey>,
 }
 
 impl PageBoundaryState {
     /// Initialize the boundary state with a PLC page
     pub fn new(page: &ExportPage) -> Option<Self> {
-        // grab the very last op
-        let (last_at, last_key) = page.ops.last().map(|op| (op.created_at, op.into()))?;
-
-        // set initial state
+        let first = page.ops.first()?;
         let mut me = Self {
-            last_at,
-            keys_at: vec![last_key],
+            last_at: first.created_at,
+            recent: VecDeque::new(),
+            recent_set: HashSet::new(),
         };
-
-        // and make sure all keys at this time are captured from the back
-        me.capture_nth_last_at(page, last_at, 1);
-
+        for op in &page.ops {
+            me.observe(op);
+        }
         Some(me)
     }
     /// Apply the deduplication and update state
     ///
-    /// The beginning of the page will be modified to remove duplicates from the
-    /// previous page.
-    ///
-    /// The end of the page is inspected to update the deduplicator state for
-    /// the next page.
-    fn apply_to_next(&mut self, page: &mut ExportPage) {
-        // walk ops forward, kicking previously-seen ops until created_at advances
-        let to_remove: Vec<usize> = page
-            .ops
-            .iter()
-            .enumerate()
-            .take_while(|(_, op)| op.created_at == self.last_at)
-            .filter(|(_, op)| self.keys_at.contains(&(*op).into()))
-            .map(|(i, _)| i)
-            .collect();
+    /// Ops seen recently are removed from the page, and the rest are remembered
+    /// for the next page.
+    pub(crate) fn apply_to_next(&mut self, page: &mut ExportPage) {
+        let before = page.ops.len();
+        page.ops.retain(|op| self.observe(op));
+        let removed = before - page.ops.len();
+        if removed > 0 {
+            log::trace!("removed {removed} duplicate ops");
+            metrics::POLL_DUPLICATES.add(removed as u64);
+        }
+    }
 
-        // actually remove them. last to first so indices don't shift
-        for dup_idx in to_remove.into_iter().rev() {
-            page.ops.remove(dup_idx);
+    /// Remember an op, returning whether it's new
+    fn observe(&mut self, op: &Op) -> bool {
+        let key = OpKey::from(op);
+        if self.recent_set.contains(&key) {
+            return false;
         }
+        if op.created_at < self.last_at {
+            metrics::POLL_OUT_OF_ORDER.inc();
+            log::warn!(
+                "op {} for {} at {} arrived after an op at {}; passing it along anyway",
+                op.cid,
+                op.did,
+                op.created_at,
+                self.last_at,
+            );
+        }
+        self.last_at = self.last_at.max(op.created_at);
+        self.recent.push_back(key.clone());
+        self.recent_set.insert(key);
+        if self.recent.len() > DEDUP_WINDOW
+            && let Some(oldest) = self.recent.pop_front()
+        {
+            self.recent_set.remove(&oldest);
+        }
+        true
+    }
+}
 
-        // grab the very last op
-        let Some((last_at, last_key)) = page.ops.last().map(|op| (op.created_at, op.into())) else {
-            // there are no ops left? oop. bail.
-            // last_at and existing keys remain in tact
-            return;
+/// Ops older than this mean we're still catching up with upstream's head
+pub(crate) const MAX_LAG: Duration = Duration::from_secs(60 * 60);
+
+/// Whether an op this old means there are probably more waiting upstream
+pub(crate) fn is_behind(last_at: Dt, max_lag: Duration) -> bool {
+    (chrono::Utc::now() - last_at)
+        .to_std()
+        .is_ok_and(|lag| lag > max_lag)
+}
+
+/// Never poll faster than this, however much budget upstream says we have
+const MIN_DELAY: Duration = Duration::from_millis(50);
+
+/// Longest wait after a 429 that didn't say how long to wait
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Rate-limit hints from an upstream response's headers
+///
+/// Understands `Retry-After`, the IETF `RateLimit` header (both the
+/// `limit=, remaining=, reset=` and the `r=;t=` drafts), the split
+/// `RateLimit-Limit`/`-Remaining`/`-Reset` headers, and their `X-RateLimit-*`
+/// variants.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitHeaders {
+    pub limit: Option<u64>,
+    /// requests left in the current window
+    pub remaining: Option<u64>,
+    /// time until the current window resets
+    pub reset: Option<Duration>,
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitHeaders {
+    pub fn from_headers(headers: &HeaderMap, now: Dt) -> Self {
+        let get = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let first_int = |v: &str| {
+            // "100" or "100, 100;w=300" (a quota policy follows the value)
+            v.split([',', ';']).next()?.trim().parse::<u64>().ok()
         };
 
-        // reset state (as long as time actually moved forward on this page)
-        if last_at > self.last_at {
-            self.last_at = last_at;
-            self.keys_at = vec![last_key];
-        } else {
-            // weird cases: either time didn't move (fine...) or went backwards (not fine)
-            assert_eq!(last_at, self.last_at, "time moved backwards on a page");
-            self.keys_at.push(last_key);
+        let mut me = Self {
+            retry_after: get(RETRY_AFTER.as_str()).and_then(|v| parse_retry_after(v, now)),
+            ..Default::default()
+        };
+
+        if let Some(combined) = get("ratelimit") {
+            for item in combined.split([',', ';']) {
+                let Some((k, v)) = item.split_once('=') else {
+                    continue;
+                };
+                let v = v.trim();
+                match k.trim().to_ascii_lowercase().as_str() {
+                    "limit" => me.limit = v.parse().ok(),
+                    "remaining" | "r" => me.remaining = v.parse().ok(),
+                    "reset" | "t" => me.reset = parse_reset(v, now),
+                    _ => {}
+                }
+            }
+        }
+        for prefix in ["ratelimit-", "x-ratelimit-"] {
+            let header = |suffix: &str| get(&format!("{prefix}{suffix}"));
+            me.limit = me.limit.or_else(|| header("limit").and_then(first_int));
+            me.remaining = me
+                .remaining
+                .or_else(|| header("remaining").and_then(first_int));
+            me.reset = me
+                .reset
+                .or_else(|| header("reset").and_then(|v| parse_reset(v, now)));
+        }
+        me
+    }
+
+    /// Spread the requests left in this window evenly over the rest of it
+    pub fn spacing(&self) -> Option<Duration> {
+        match (self.remaining?, self.reset?) {
+            (0, reset) => Some(reset),
+            (remaining, reset) => Some(reset / remaining.try_into().unwrap_or(u32::MAX)),
         }
-        // and make sure all keys at this time are captured from the back
-        self.capture_nth_last_at(page, last_at, 1);
     }
+}
 
-    /// walk backwards from 2nd last and collect keys until created_at changes
-    fn capture_nth_last_at(&mut self, page: &ExportPage, last_at: Dt, skips: usize) {
-        page.ops
-            .iter()
-            .rev()
-            .skip(skips)
-            .take_while(|op| op.created_at == last_at)
-            .for_each(|op| {
-                self.keys_at.push(op.into());
-            });
+/// `Retry-After` is either delay-seconds or an http date
+fn parse_retry_after(v: &str, now: Dt) -> Option<Duration> {
+    if let Ok(secs) = v.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
     }
+    let at = chrono::DateTime::parse_from_rfc2822(v.trim()).ok()?;
+    Some((at.to_utc() - now).to_std().unwrap_or_default())
+}
+
+/// Reset is usually seconds from now, but some servers send a unix timestamp
+fn parse_reset(v: &str, now: Dt) -> Option<Duration> {
+    let secs = v.trim().parse::<f64>().ok().filter(|s| *s >= 0.)?;
+    if secs > 1_000_000_000. {
+        let at = Dt::from_timestamp(secs as i64, 0)?;
+        return Some((at - now).to_std().unwrap_or_default());
+    }
+    Some(Duration::from_secs_f64(secs))
+}
+
+/// How long to wait after a successful poll, before polling again
+///
+/// When we're behind, go as fast as upstream's remaining budget allows.
+/// Otherwise we're caught up, so poll every `throttle`, or slower if the
+/// budget is running low. Without rate-limit headers, stick to `throttle`.
+pub(crate) fn next_delay(throttle: Duration, behind: bool, limits: &RateLimitHeaders) -> Duration {
+    match (behind, limits.spacing()) {
+        (true, Some(spacing)) => spacing.max(MIN_DELAY),
+        (false, Some(spacing)) => spacing.max(throttle),
+        (_, None) => throttle,
+    }
+}
+
+/// How long to wait after being rate-limited
+///
+/// Upstream's say-so if it gave one, otherwise double the previous delay.
+/// Either way it's kept within [`MIN_DELAY`] and [`MAX_BACKOFF`], so a
+/// `Retry-After: 0` can't turn into hammering and a huge one can't stall us.
+pub(crate) fn rate_limited_delay(prev: Duration, limits: &RateLimitHeaders) -> Duration {
+    limits
+        .retry_after
+        .or(limits.reset)
+        .unwrap_or_else(|| (prev * 2).max(Duration::from_secs(1)))
+        .clamp(MIN_DELAY, MAX_BACKOFF)
+}
+
+/// How to deserialize the ops in an export page
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PageParsing {
+    /// Parse each line as soon as it arrives
+    #[default]
+    Inline,
+    /// Parse batches of this many lines on blocking threads while the rest of
+    /// the page downloads. Worth it for pages much bigger than 1000 ops.
+    Parallel { batch: usize },
 }
 
 /// Get one PLC export page
 ///
-/// Extracts the final op so it can be used to fetch the following page
-pub async fn get_page(url: Url) -> Result<(ExportPage, Option<LastOp>), GetPageError> {
+/// Extracts the final op so it can be used to fetch the following page.
+///
+/// Ops are parsed as the body streams in. A final line that was cut off
+/// mid-op is a [`GetPageError::Truncated`] error, which holds the complete
+/// ops before it.
+pub async fn get_page(
+    client: &HttpClient,
+    url: Url,
+) -> Result<(ExportPage, Option<LastOp>), GetPageError> {
+    get_page_with(client, url, PageParsing::Inline).await
+}
+
+/// [`get_page`], choosing how ops are parsed
+pub async fn get_page_with(
+    client: &HttpClient,
+    url: Url,
+    parsing: PageParsing,
+) -> Result<(ExportPage, Option<LastOp>), GetPageError> {
     log::trace!("Getting page: {url}");
+    let source = url.to_string();
+    let (page, last_op, _) = fetch_page(client.get(url), &source, parsing).await?;
+    Ok((page, last_op))
+}
+
+pub(crate) async fn fetch_page(
+    req: RequestBuilder,
+    source: &str,
+    parsing: PageParsing,
+) -> Result<(ExportPage, Option<LastOp>, RateLimitHeaders), GetPageError> {
+    let mut res = req.send().await?;
+    let limits = RateLimitHeaders::from_headers(res.headers(), chrono::Utc::now());
+    let status = res.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(GetPageError::RateLimited {
+            retry_after: limits.retry_after,
+            limits,
+        });
+    }
+    if !status.is_success() {
+        return Err(GetPageError::Status(status));
+    }
+
+    let mut parser = PageParser::new(source, parsing);
+    while let Some(chunk) = res.chunk().await? {
+        parser.push(&chunk);
+    }
+    let (ops, truncated) = parser.finish().await?;
+    let page = ExportPage { ops };
+    if truncated {
+        return Err(GetPageError::Truncated { page, limits });
+    }
+
+    let last_op = page.ops.last().map(Into::into);
+
+    Ok((page, last_op, limits))
+}
+
+/// Parses ndjson ops from body chunks as they arrive
+struct PageParser {
+    /// where the page came from, for dead letters
+    source: String,
+    parsing: PageParsing,
+    /// bytes after the last newline seen so far
+    partial: Vec<u8>,
+    ops: Vec<Op>,
+    /// lines that weren't ops, recorded once the page is done
+    rejected: Vec<DeadLetter>,
+    /// complete lines waiting for a parallel batch to fill up
+    batch: Vec<u8>,
+    batch_lines: usize,
+    tasks: Vec<JoinHandle<(Vec<Op>, Vec<DeadLetter>)>>,
+}
+
+impl PageParser {
+    fn new(source: &str, parsing: PageParsing) -> Self {
+        Self {
+            source: source.to_string(),
+            parsing,
+            partial: vec![],
+            ops: vec![],
+            rejected: vec![],
+            batch: vec![],
+            batch_lines: 0,
+            tasks: vec![],
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.partial.extend_from_slice(chunk);
+        let Some(end) = self.partial.iter().rposition(|b| *b == b'\n') else {
+            return;
+        };
+        let rest = self.partial.split_off(end + 1);
+        let lines = std::mem::replace(&mut self.partial, rest);
+        match self.parsing {
+            PageParsing::Inline => {
+                let (ops, rejected) = parse_lines(&lines, &self.source);
+                self.ops.extend(ops);
+                self.rejected.extend(rejected);
+            }
+            PageParsing::Parallel { batch } => {
+                self.batch_lines += lines.iter().filter(|b| **b == b'\n').count();
+                self.batch.extend_from_slice(&lines);
+                if self.batch_lines >= batch {
+                    self.spawn_batch();
+                }
+            }
+        }
+    }
+
+    fn spawn_batch(&mut self) {
+        let lines = std::mem::take(&mut self.batch);
+        self.batch_lines = 0;
+        if !lines.is_empty() {
+            let source = self.source.clone();
+            self.tasks.push(tokio::task::spawn_blocking(move || {
+                parse_lines(&lines, &source)
+            }));
+        }
+    }
+
+    /// All the ops, and whether the final line was cut off
+    async fn finish(mut self) -> Result<(Vec<Op>, bool), GetPageError> {
+        self.spawn_batch();
+        for task in std::mem::take(&mut self.tasks) {
+            let (ops, rejected) = task.await?;
+            self.ops.extend(ops);
+            self.rejected.extend(rejected);
+        }
+        for letter in std::mem::take(&mut self.rejected) {
+            reject(letter).await?;
+        }
+        // the body doesn't have to end with a newline, so the final line might
+        // be complete. if it doesn't parse though, it was cut off.
+        let truncated = match self.partial.trim_ascii() {
+            [] => false,
+            line => match serde_json::from_slice::<Op>(line) {
+                Ok(op) => {
+                    self.ops.push(op);
+                    false
+                }
+                Err(e) => {
+                    log::debug!("final line of page didn't parse, assuming truncation: {e}");
+                    true
+                }
+            },
+        };
+        Ok((self.ops, truncated))
+    }
+}
 
-    let ops: Vec<Op> = CLIENT
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?
-        .trim()
-        .split('\n')
-        .filter_map(|s| {
-            serde_json::from_str::<Op>(s)
-                .inspect_err(|e| {
-                    if !s.is_empty() {
-                        log::warn!("failed to parse op: {e} ({s})")
+/// Parse complete lines, keeping any that aren't ops for the dead letters
+fn parse_lines(lines: &[u8], source: &str) -> (Vec<Op>, Vec<DeadLetter>) {
+    let mut ops = vec![];
+    let mut rejected = vec![];
+    for line in lines.split(|b| *b == b'\n').map(<[u8]>::trim_ascii) {
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice::<Op>(line) {
+            Ok(op) => ops.push(op),
+            Err(e) => rejected.push(DeadLetter::new(source, line, e)),
+        }
+    }
+    (ops, rejected)
+}
+
+/// Poll an upstream PLC server for new ops, as a stream of pages
+///
+/// Never ends on its own: once caught up, it keeps polling every `throttle`
+/// for new ops. Empty pages are skipped, and duplicates across page boundaries
+/// are removed.
+///
+/// The interval adapts to upstream's rate-limit headers: while pages come back
+/// full it polls as fast as the remaining budget allows, and once caught up it
+/// backs off to `throttle` (or slower, if the budget is low). A 429 is waited
+/// out (honoring `Retry-After`) and retried, rather than ending the stream.
+///
+/// `parsing` picks how each page's ops are deserialized: see [`PageParsing`].
+///
+/// ```no_run
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// use allegedly::{HttpClient, PageParsing, poll_stream};
+/// use futures::TryStreamExt;
+///
+/// let after = Some(chrono::Utc::now());
+/// let upstream = "https://plc.wtf/export".parse().unwrap();
+/// let throttle = std::time::Duration::from_millis(300);
+///
+/// let client = HttpClient::default();
+/// let parsing = PageParsing::Inline;
+/// let mut pages = std::pin::pin!(poll_stream(client, after, upstream, throttle, parsing));
+/// while let Some(page) = pages.try_next().await? {
+///     for op in page.ops.iter().filter(|op| op.did == "did:plc:hdhoaan3xa3jiuq4fg4mefid") {
+///         println!("Update found! cid={} -> operation: {}", op.cid, op.operation.get());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn poll_stream(
+    client: HttpClient,
+    after: Option<Dt>,
+    base: Url,
+    throttle: Duration,
+    parsing: PageParsing,
+) -> impl Stream<Item = Result<ExportPage, PollError>> {
+    struct State {
+        next_at: Option<tokio::time::Instant>,
+        delay: Duration,
+        prev_last: Option<LastOp>,
+        boundary_state: Option<PageBoundaryState>,
+    }
+    log::info!("starting upstream poller at {base} after {after:?}");
+    let state = State {
+        next_at: None,
+        delay: throttle,
+        prev_last: after.map(Into::into),
+        boundary_state: None,
+    };
+    futures::stream::try_unfold(state, move |mut state| {
+        let base = base.clone();
+        let client = client.clone();
+        async move {
+            loop {
+                if let Some(at) = state.next_at {
+                    tokio::time::sleep_until(at).await;
+                }
+                let started = tokio::time::Instant::now();
+
+                let mut url = base.clone();
+                if let Some(ref pl) = state.prev_last {
+                    url.query_pairs_mut()
+                        .append_pair("after", &pl.created_at.to_rfc3339());
+                };
+
+                log::trace!("Getting page: {url}");
+                let source = url.to_string();
+                let req = client.get_paced(url);
+                let fetched = fetch_page(req, &source, parsing).await;
+                let (mut page, next_last, limits) = match fetched {
+                    Ok(fetched) => fetched,
+                    Err(GetPageError::Truncated { page, limits }) => {
+                        // keep what we got: the next poll picks up after it
+                        log::warn!(
+                            "page was cut off after {} ops, fetching the rest",
+                            page.ops.len()
+                        );
+                        let last = page.ops.last().map(Into::into);
+                        (page, last, limits)
+                    }
+                    Err(GetPageError::RateLimited { limits, .. }) => {
+                        state.delay = rate_limited_delay(state.delay, &limits);
+                        log::warn!("rate limited by upstream, waiting {:?}", state.delay);
+                        state.next_at = Some(tokio::time::Instant::now() + state.delay);
+                        continue;
                     }
-                })
-                .ok()
-        })
-        .collect();
+                    Err(e) => return Err(e.into()),
+                };
 
-    let last_op = ops.last().map(Into::into);
+                let behind = page
+                    .ops
+                    .last()
+                    .is_some_and(|op| is_behind(op.created_at, MAX_LAG));
+                state.delay = next_delay(throttle, behind, &limits);
+                state.next_at = Some(started + state.delay);
+                log::trace!("next poll in {:?} (behind: {behind})", state.delay);
+
+                if let Some(ref mut boundary) = state.boundary_state {
+                    boundary.apply_to_next(&mut page);
+                } else {
+                    state.boundary_state = PageBoundaryState::new(&page);
+                }
+                state.prev_last = next_last.or(state.prev_last);
 
-    Ok((ExportPage { ops }, last_op))
+                if !page.is_empty() {
+                    return Ok(Some((page, state)));
+                }
+            }
+        }
+    })
 }
 
 /// Poll an upstream PLC server for new ops
 ///
-/// Pages of operations are written to the `dest` channel.
+/// Pages of operations are written to the `dest` channel. See [`poll_stream`]
+/// for a version that doesn't need a channel.
 ///
 /// ```no_run
 /// # #[tokio::main]
 /// # async fn main() {
-/// use allegedly::{ExportPage, Op, poll_upstream};
+/// use allegedly::{ExportPage, HttpClient, Op, PageParsing, poll_upstream};
 ///
 /// let after = Some(chrono::Utc::now());
 /// let upstream = "https://plc.wtf/export".parse().unwrap();
 /// let throttle = std::time::Duration::from_millis(300);
 ///
 /// let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-/// tokio::task::spawn(poll_upstream(after, upstream, throttle, tx));
+/// let client = HttpClient::default();
+/// tokio::task::spawn(poll_upstream(client, after, upstream, throttle, PageParsing::Inline, tx));
 ///
 /// while let Some(ExportPage { ops }) = rx.recv().await {
 ///     println!("received {} plc ops", ops.len());
@@ -196,43 +622,15 @@ pub async fn get_page(url: Url) -> Result<(ExportPage, Option<LastOp>), GetPageE
 /// # }
 /// ```
 pub async fn poll_upstream(
+    client: HttpClient,
     after: Option<Dt>,
     base: Url,
     throttle: Duration,
+    parsing: PageParsing,
     dest: mpsc::Sender<ExportPage>,
-) -> anyhow::Result<&'static str> {
-    log::info!("starting upstream poller at {base} after {after:?}");
-    let mut tick = tokio::time::interval(throttle);
-    let mut prev_last: Option<LastOp> = after.map(Into::into);
-    let mut boundary_state: Option<PageBoundaryState> = None;
-    loop {
-        tick.tick().await;
-
-        let mut url = base.clone();
-        if let Some(ref pl) = prev_last {
-            url.query_pairs_mut()
-                .append_pair("after", &pl.created_at.to_rfc3339());
-        };
-
-        let (mut page, next_last) = get_page(url).await?;
-        if let Some(ref mut state) = boundary_state {
-            state.apply_to_next(&mut page);
-        } else {
-            boundary_state = PageBoundaryState::new(&page);
-        }
-        if !page.is_empty() {
-            match dest.try_send(page) {
-                Ok(()) => {}
-                Err(mpsc::error::TrySendError::Full(page)) => {
-                    log::warn!("export: destination channel full, awaiting...");
-                    dest.send(page).await?;
-                }
-                e => e?,
-            };
-        }
-
-        prev_last = next_last.or(prev_last);
-    }
+) -> Result<&'static str, PollError> {
+    stream_to_channel(poll_stream(client, after, base, throttle, parsing), dest).await?;
+    Ok("poll_upstream (ended?)")
 }
 
 #[cfg(test)]
@@ -271,6 +669,17 @@ mod test {
         PageBoundaryState::new(&page).expect("to have a base page boundary state")
     }
 
+    fn key(did: &str, cid: &str) -> OpKey {
+        OpKey {
+            did: did.to_string(),
+            cid: cid.to_string(),
+        }
+    }
+
+    fn recent(state: &PageBoundaryState) -> Vec<OpKey> {
+        state.recent.iter().cloned().collect()
+    }
+
     #[test]
     fn test_boundary_new_empty() {
         let page = ExportPage { ops: vec![] };
@@ -285,13 +694,7 @@ mod test {
         };
         let state = PageBoundaryState::new(&page).unwrap();
         assert_eq!(state.last_at, Dt::from_timestamp(FIVES_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![OpKey {
-                cid: "cid".to_string(),
-                did: "did".to_string(),
-            }]
-        );
+        assert_eq!(recent(&state), vec![key("did", "cid")]);
     }
 
     #[test]
@@ -308,6 +711,7 @@ mod test {
         };
         let mut state = base_state();
         state.apply_to_next(&mut page);
+        assert!(page.ops.is_empty());
         assert_eq!(state, base_state());
     }
 
@@ -320,20 +724,9 @@ mod test {
 
         let mut state = base_state();
         state.apply_to_next(&mut page);
+        assert_eq!(page.ops.len(), 1);
         assert_eq!(state.last_at, Dt::from_timestamp(FIVES_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![
-                OpKey {
-                    cid: "cid".to_string(),
-                    did: "did".to_string(),
-                },
-                OpKey {
-                    cid: "cid2".to_string(),
-                    did: "did".to_string(),
-                },
-            ]
-        );
+        assert_eq!(recent(&state), vec![key("did", "cid"), key("did", "cid2")]);
     }
 
     #[test]
@@ -342,25 +735,13 @@ mod test {
         let mut op = valid_op();
         op.cid = "cid2".to_string();
         let mut page = ExportPage {
-            ops: vec![valid_op(), op],
+            ops: vec![valid_op(), op.clone()],
         };
 
         let mut state = base_state();
         state.apply_to_next(&mut page);
-        assert_eq!(state.last_at, Dt::from_timestamp(FIVES_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![
-                OpKey {
-                    cid: "cid".to_string(),
-                    did: "did".to_string(),
-                },
-                OpKey {
-                    cid: "cid2".to_string(),
-                    did: "did".to_string(),
-                },
-            ]
-        );
+        assert_eq!(page.ops, vec![op]);
+        assert_eq!(recent(&state), vec![key("did", "cid"), key("did", "cid2")]);
     }
 
     #[test]
@@ -369,25 +750,13 @@ mod test {
         let mut op = valid_op();
         op.cid = "cid2".to_string();
         let mut page = ExportPage {
-            ops: vec![op, valid_op()],
+            ops: vec![op.clone(), valid_op()],
         };
 
         let mut state = base_state();
         state.apply_to_next(&mut page);
-        assert_eq!(state.last_at, Dt::from_timestamp(FIVES_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![
-                OpKey {
-                    cid: "cid".to_string(),
-                    did: "did".to_string(),
-                },
-                OpKey {
-                    cid: "cid2".to_string(),
-                    did: "did".to_string(),
-                },
-            ]
-        );
+        assert_eq!(page.ops, vec![op]);
+        assert_eq!(recent(&state), vec![key("did", "cid"), key("did", "cid2")]);
     }
 
     #[test]
@@ -399,11 +768,8 @@ mod test {
         state.apply_to_next(&mut page);
         assert_eq!(state.last_at, Dt::from_timestamp(NEXT_TS, 0).unwrap());
         assert_eq!(
-            state.keys_at,
-            vec![OpKey {
-                cid: "cidnext".to_string(),
-                did: "didnext".to_string(),
-            },]
+            recent(&state),
+            vec![key("did", "cid"), key("didnext", "cidnext")]
         );
     }
 
@@ -415,15 +781,7 @@ mod test {
         let mut state = base_state();
         state.apply_to_next(&mut page);
         assert_eq!(state.last_at, Dt::from_timestamp(NEXT_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![OpKey {
-                cid: "cidnext".to_string(),
-                did: "didnext".to_string(),
-            },]
-        );
-        assert_eq!(page.ops.len(), 1);
-        assert_eq!(page.ops[0], next_op());
+        assert_eq!(page.ops, vec![next_op()]);
     }
 
     #[test]
@@ -442,16 +800,7 @@ mod test {
         let mut state = base_state();
         state.apply_to_next(&mut page);
         assert_eq!(state.last_at, Dt::from_timestamp(NEXT_TS, 0).unwrap());
-        assert_eq!(
-            state.keys_at,
-            vec![OpKey {
-                cid: "cidnext".to_string(),
-                did: "didnext".to_string(),
-            },]
-        );
-        assert_eq!(page.ops.len(), 2);
-        assert_eq!(page.ops[0], op);
-        assert_eq!(page.ops[1], next_op());
+        assert_eq!(page.ops, vec![op, next_op()]);
     }
 
     #[test]
@@ -470,15 +819,228 @@ mod test {
         let mut state = base_state();
         state.apply_to_next(&mut page);
         assert_eq!(state.last_at, Dt::from_timestamp(NEXT_TS, 0).unwrap());
+        assert_eq!(page.ops, vec![op, next_op()]);
+    }
+
+    #[test]
+    fn test_dup_across_several_pages() {
+        let mut state = base_state();
+        state.apply_to_next(&mut ExportPage {
+            ops: vec![next_op()],
+        });
+        // the first op shows up again, a timestamp behind
+        let mut page = ExportPage {
+            ops: vec![valid_op(), next_op()],
+        };
+        state.apply_to_next(&mut page);
+        assert!(page.ops.is_empty());
+    }
+
+    #[test]
+    fn test_dedup_window_is_bounded() {
+        let mut state = base_state();
+        let ops = (0..DEDUP_WINDOW + 10)
+            .map(|i| {
+                let mut op = next_op();
+                op.cid = format!("cid{i}");
+                op
+            })
+            .collect();
+        state.apply_to_next(&mut ExportPage { ops });
+        assert_eq!(state.recent.len(), DEDUP_WINDOW);
+        assert_eq!(state.recent_set.len(), DEDUP_WINDOW);
+        assert!(!state.recent_set.contains(&key("did", "cid")));
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.parse().unwrap(), v.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let now = Dt::from_timestamp(FIVES_TS, 0).unwrap();
+
+        let split = headers(&[
+            ("ratelimit-limit", "500"),
+            ("ratelimit-remaining", "100, 500;w=300"),
+            ("ratelimit-reset", "20"),
+        ]);
+        let limits = RateLimitHeaders::from_headers(&split, now);
+        assert_eq!(limits.limit, Some(500));
+        assert_eq!(limits.remaining, Some(100));
+        assert_eq!(limits.reset, Some(Duration::from_secs(20)));
+        assert_eq!(limits.spacing(), Some(Duration::from_millis(200)));
+
+        let x_epoch = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", &(FIVES_TS + 30).to_string()),
+        ]);
+        let limits = RateLimitHeaders::from_headers(&x_epoch, now);
+        assert_eq!(limits.spacing(), Some(Duration::from_secs(30)));
+
+        let combined = headers(&[("ratelimit", "\"default\";r=10;t=5")]);
+        let limits = RateLimitHeaders::from_headers(&combined, now);
+        assert_eq!(limits.remaining, Some(10));
+        assert_eq!(limits.reset, Some(Duration::from_secs(5)));
+
+        let date = headers(&[("retry-after", "Fri, 15 May 2015 00:00:07 GMT")]);
+        let limits = RateLimitHeaders::from_headers(&date, now);
+        assert_eq!(limits.retry_after, Some(Duration::from_secs(7)));
+
+        assert_eq!(
+            RateLimitHeaders::from_headers(&HeaderMap::new(), now),
+            RateLimitHeaders::default()
+        );
+    }
+
+    #[test]
+    fn test_is_behind() {
+        let now = chrono::Utc::now();
+        assert!(is_behind(now - chrono::Duration::hours(2), MAX_LAG));
+        assert!(!is_behind(now - chrono::Duration::minutes(1), MAX_LAG));
+        // upstream's clock can be ahead of ours
+        assert!(!is_behind(now + chrono::Duration::minutes(1), MAX_LAG));
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let throttle = Duration::from_millis(600);
+        let none = RateLimitHeaders::default();
+        let plenty = RateLimitHeaders {
+            remaining: Some(400),
+            reset: Some(Duration::from_secs(40)),
+            ..Default::default()
+        };
+        let scarce = RateLimitHeaders {
+            remaining: Some(2),
+            reset: Some(Duration::from_secs(40)),
+            ..Default::default()
+        };
+
+        // no hints: fixed throttle
+        assert_eq!(next_delay(throttle, true, &none), throttle);
+        assert_eq!(next_delay(throttle, false, &none), throttle);
+        // catching up: use the budget
+        assert_eq!(
+            next_delay(throttle, true, &plenty),
+            Duration::from_millis(100)
+        );
+        assert_eq!(next_delay(throttle, true, &scarce), Duration::from_secs(20));
+        // caught up: no faster than throttle
+        assert_eq!(next_delay(throttle, false, &plenty), throttle);
+        assert_eq!(
+            next_delay(throttle, false, &scarce),
+            Duration::from_secs(20)
+        );
+
+        // 429s: upstream's word, or doubling
+        let told = RateLimitHeaders {
+            retry_after: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        assert_eq!(rate_limited_delay(throttle, &told), Duration::from_secs(3));
         assert_eq!(
-            state.keys_at,
-            vec![OpKey {
-                cid: "cidnext".to_string(),
-                did: "didnext".to_string(),
-            },]
+            rate_limited_delay(Duration::from_secs(2), &none),
+            Duration::from_secs(4)
         );
-        assert_eq!(page.ops.len(), 2);
-        assert_eq!(page.ops[0], op);
-        assert_eq!(page.ops[1], next_op());
+        assert_eq!(rate_limited_delay(MAX_BACKOFF, &none), MAX_BACKOFF);
+        let told = |secs| RateLimitHeaders {
+            retry_after: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        assert_eq!(rate_limited_delay(throttle, &told(0)), MIN_DELAY);
+        assert_eq!(rate_limited_delay(throttle, &told(86_400)), MAX_BACKOFF);
+    }
+
+    fn op_line(i: usize) -> String {
+        format!(
+            r#"{{"did":"did:plc:{i}","cid":"cid{i}","createdAt":"2015-05-15T00:00:00Z","nullified":false,"operation":{{}}}}"#
+        )
+    }
+
+    async fn parse_chunked(body: &str, chunk: usize, parsing: PageParsing) -> (Vec<String>, bool) {
+        let mut parser = PageParser::new("test", parsing);
+        for bytes in body.as_bytes().chunks(chunk) {
+            parser.push(bytes);
+        }
+        let (ops, truncated) = parser.finish().await.unwrap();
+        (ops.into_iter().map(|op| op.cid).collect(), truncated)
+    }
+
+    #[tokio::test]
+    async fn test_page_parser() {
+        let lines: Vec<_> = (0..25).map(op_line).collect();
+        let expected: Vec<_> = (0..25).map(|i| format!("cid{i}")).collect();
+        let body = lines.join("\n");
+
+        for parsing in [PageParsing::Inline, PageParsing::Parallel { batch: 4 }] {
+            // chunk boundaries land mid-line
+            assert_eq!(
+                parse_chunked(&body, 7, parsing).await,
+                (expected.clone(), false)
+            );
+            // trailing newline is fine too
+            assert_eq!(
+                parse_chunked(&format!("{body}\n"), 100, parsing).await,
+                (expected.clone(), false)
+            );
+            // final line cut off
+            let cut = &body[..body.len() - 10];
+            assert_eq!(
+                parse_chunked(cut, 64, parsing).await,
+                (expected[..24].to_vec(), true)
+            );
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_truncated_page_keeps_limits() {
+        use poem::listener::{Acceptor, Listener, TcpListener};
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        let app = poem::endpoint::make(|_| async {
+            let body = format!("{}\n{}", op_line(0), &op_line(1)[..20]);
+            poem::Response::builder()
+                .header("RateLimit-Remaining", "3")
+                .header("RateLimit-Reset", "60")
+                .body(body)
+        });
+        let server = tokio::task::spawn(poem::Server::new_with_acceptor(acceptor).run(app));
+
+        let url: Url = format!("http://{addr}/export").parse().unwrap();
+        let res = get_page(&HttpClient::default(), url).await;
+        server.abort();
+        match res {
+            Err(GetPageError::Truncated { page, limits }) => {
+                assert_eq!(page.ops.len(), 1);
+                assert_eq!(limits.remaining, Some(3));
+                assert_eq!(limits.reset, Some(Duration::from_secs(60)));
+            }
+            other => panic!("expected a truncated page, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_time_backwards_is_kept() {
+        let mut op = valid_op();
+        op.cid = "cidold".to_string();
+        op.created_at = Dt::from_timestamp(FIVES_TS - 1, 0).unwrap();
+
+        let anomalies = metrics::POLL_OUT_OF_ORDER.get();
+        let mut state = base_state();
+        let mut page = ExportPage {
+            ops: vec![op.clone()],
+        };
+        state.apply_to_next(&mut page);
+        assert_eq!(page.ops, vec![op]);
+        assert_eq!(state.last_at, Dt::from_timestamp(FIVES_TS, 0).unwrap());
+        assert!(metrics::POLL_OUT_OF_ORDER.get() > anomalies);
     }
 }
@@ -3,13 +3,19 @@ use crate::logo;
 use governor::{
     NotUntil, Quota, RateLimiter,
     clock::{Clock, DefaultClock},
+    middleware::{StateInformationMiddleware, StateSnapshot},
     state::keyed::DefaultKeyedStateStore,
 };
-use poem::{Endpoint, Middleware, Request, Response, Result, http::StatusCode};
+use ipnet::IpNet;
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    http::{HeaderMap, StatusCode},
+};
 use std::{
     convert::TryInto,
     hash::Hash,
     net::{IpAddr, Ipv6Addr},
+    num::NonZeroU32,
     sync::{Arc, LazyLock},
     time::Duration,
 };
@@ -21,30 +27,115 @@ const IP6_64_MASK: Ipv6Addr = Ipv6Addr::from_bits(0xFFFF_FFFF_FFFF_FFFF_0000_000
 type IP6_56 = [u8; 7];
 type IP6_48 = [u8; 6];
 
+type KeyedLimiter<K> =
+    RateLimiter<K, DefaultKeyedStateStore<K>, DefaultClock, StateInformationMiddleware>;
+
+fn keyed<K: Hash + Eq + Clone>(quota: Quota) -> KeyedLimiter<K> {
+    RateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>()
+}
+
+/// Where a key stands with its quota after an allowed request
+///
+/// Reported to clients with `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitState {
+    pub limit: u32,
+    pub remaining: u32,
+    /// how long until the full burst is available again
+    pub reset: Duration,
+}
+
+impl From<StateSnapshot> for LimitState {
+    fn from(snapshot: StateSnapshot) -> Self {
+        let quota = snapshot.quota();
+        let limit = quota.burst_size().get();
+        let remaining = snapshot.remaining_burst_capacity();
+        Self {
+            limit,
+            remaining,
+            reset: quota.replenish_interval() * (limit - remaining.min(limit)),
+        }
+    }
+}
+
+impl LimitState {
+    /// keep whichever state is closest to being limited
+    fn tightest(self, other: Self) -> Self {
+        if other.remaining < self.remaining {
+            other
+        } else {
+            self
+        }
+    }
+}
+
 pub trait Limiter<K: Hash + std::fmt::Debug>: Send + Sync + 'static {
     fn extract_key(&self, req: &Request) -> Result<K>;
-    fn check_key(&self, ip: &K) -> Result<(), Duration>;
+    /// `Ok(None)` means the key is exempt from limiting
+    fn check_key(&self, key: &K) -> Result<Option<LimitState>, Duration>;
     fn housekeep(&self);
 }
 
-fn scale_quota(quota: Quota, factor: u32) -> Option<Quota> {
-    let period = quota.replenish_interval() / factor;
-    let burst = quota
-        .burst_size()
-        .checked_mul(factor.try_into().expect("factor to be non-zero"))
-        .expect("burst to be able to multiply");
-    Quota::with_period(period).map(|q| q.allow_burst(burst))
+/// Parse a quota like `3000/min`, `10/hour` or `5/s`
+pub fn parse_quota(s: &str) -> Result<Quota, String> {
+    let (n, per) = s
+        .split_once('/')
+        .ok_or_else(|| format!("expected <count>/<period> like 10/min, found {s:?}"))?;
+    let n: NonZeroU32 = n
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad quota count {n:?}: {e}"))?;
+    let quota = match per.trim() {
+        "s" | "sec" | "second" => Ok(Quota::per_second(n)),
+        "m" | "min" | "minute" => Ok(Quota::per_minute(n)),
+        "h" | "hour" => Ok(Quota::per_hour(n)),
+        "d" | "day" => Quota::with_period(Duration::from_secs(86_400) / n.get())
+            .map(|q| q.allow_burst(n))
+            .ok_or_else(|| format!("bad quota {s:?}")),
+        other => Err(format!(
+            "unknown quota period {other:?}, expected one of s, min, hour, day"
+        )),
+    }?;
+    // ipv6 /48s get 256x the quota, so that has to fit too
+    let scaled = quota.replenish_interval() / IP6_48_SCALE.get();
+    if scaled.is_zero() || quota.burst_size().checked_mul(IP6_48_SCALE).is_none() {
+        return Err(format!("quota {s:?} is too large"));
+    }
+    Ok(quota)
+}
+
+/// Parse a route-scoped quota like `/export=100/min`
+pub fn parse_route_quota(s: &str) -> Result<(String, Quota), String> {
+    let (route, quota) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <route>=<count>/<period>, found {s:?}"))?;
+    if !route.starts_with('/') {
+        return Err(format!("route must start with '/', found {route:?}"));
+    }
+    Ok((route.to_string(), parse_quota(quota)?))
+}
+
+const IP6_56_SCALE: NonZeroU32 = NonZeroU32::new(8).unwrap();
+const IP6_48_SCALE: NonZeroU32 = NonZeroU32::new(256).unwrap();
+
+/// A quota `factor` times as big, saturating rather than overflowing
+fn scale_quota(quota: Quota, factor: NonZeroU32) -> Quota {
+    let period = (quota.replenish_interval() / factor.get()).max(Duration::from_nanos(1));
+    let burst = quota.burst_size().saturating_mul(factor);
+    Quota::with_period(period)
+        .expect("period to be non-zero")
+        .allow_burst(burst)
 }
 
 #[derive(Debug)]
 pub struct CreatePlcOpLimiter {
-    limiter: RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>,
+    limiter: KeyedLimiter<String>,
 }
 
 impl CreatePlcOpLimiter {
     pub fn new(quota: Quota) -> Self {
         Self {
-            limiter: RateLimiter::keyed(quota),
+            limiter: keyed(quota),
         }
     }
 }
@@ -55,9 +146,10 @@ impl Limiter<String> for CreatePlcOpLimiter {
         let (did,) = req.path_params::<(String,)>()?;
         Ok(did)
     }
-    fn check_key(&self, did: &String) -> Result<(), Duration> {
+    fn check_key(&self, did: &String) -> Result<Option<LimitState>, Duration> {
         self.limiter
             .check_key(did)
+            .map(|s| Some(s.into()))
             .map_err(|e| e.wait_time_from(CLOCK.now()))
     }
     fn housekeep(&self) {
@@ -69,40 +161,193 @@ impl Limiter<String> for CreatePlcOpLimiter {
     }
 }
 
+/// Which forwarding header trusted proxies use to report the client's address
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: <client>, <proxy1>, <proxy2>`
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=<client>, for=<proxy1>`
+    Forwarded,
+}
+
+/// Figures out a request's real client IP
+///
+/// The socket peer is the client, unless the peer is a trusted proxy: then the
+/// forwarding header is walked from the right (nearest hop) and the first
+/// address that isn't another trusted proxy is the client.
+///
+/// Peers without an IP address (unix sockets) are local, so they're always
+/// trusted to forward.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIp {
+    trusted_proxies: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl ClientIp {
+    pub fn new(trusted_proxies: Vec<IpNet>, header: ForwardedHeader) -> Self {
+        Self {
+            trusted_proxies,
+            header,
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn extract(&self, req: &Request) -> Result<IpAddr> {
+        let peer = req
+            .remote_addr()
+            .as_socket_addr()
+            .map(|addr| addr.ip().to_canonical());
+
+        if let Some(ip) = peer
+            && !self.is_trusted(&ip)
+        {
+            return Ok(ip);
+        }
+
+        if let Some(ip) = self.forwarded_client(req.headers()) {
+            return Ok(ip);
+        }
+
+        peer.ok_or_else(|| {
+            log::warn!(
+                "no usable client address for request from {}",
+                req.remote_addr()
+            );
+            poem::Error::from_string(
+                "could not determine the client address for this request",
+                StatusCode::BAD_REQUEST,
+            )
+        })
+    }
+
+    /// walk the forwarding chain from the nearest hop back towards the client
+    fn forwarded_client(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let hops: Vec<Option<IpAddr>> = match self.header {
+            ForwardedHeader::XForwardedFor => headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .flat_map(|v| v.to_str().unwrap_or("").split(','))
+                .map(parse_hop)
+                .collect(),
+            ForwardedHeader::Forwarded => headers
+                .get_all("forwarded")
+                .iter()
+                .flat_map(|v| v.to_str().unwrap_or("").split(','))
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(k, _)| k.trim().eq_ignore_ascii_case("for"))
+                        .and_then(|(_, v)| parse_hop(v))
+                })
+                .collect(),
+        };
+
+        let mut client = None;
+        for hop in hops.into_iter().rev() {
+            // anything unparseable means we can't trust the chain any further
+            let ip = hop?;
+            client = Some(ip);
+            if !self.is_trusted(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// parse one forwarding hop: a bare ip, or `"[v6]:port"` / `v4:port` as seen in `Forwarded`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        let (v6, _port) = rest.split_once(']')?;
+        return v6.parse::<IpAddr>().ok().map(|ip| ip.to_canonical());
+    }
+    let (v4, _port) = hop.split_once(':')?;
+    v4.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+/// Parse a CIDR network, also accepting a bare IP as a single-address network
+pub fn parse_ip_net(s: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    s.parse::<IpNet>()
+        .or_else(|e| s.parse::<IpAddr>().map(IpNet::from).map_err(|_| e))
+}
+
+/// Networks that skip ip rate limits entirely, or are refused outright
+#[derive(Debug, Clone, Default)]
+pub struct IpAccess {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpAccess {
+    fn allows(&self, ip: &IpAddr) -> bool {
+        self.allow.iter().any(|net| net.contains(ip))
+    }
+    fn denies(&self, ip: &IpAddr) -> bool {
+        self.deny.iter().any(|net| net.contains(ip))
+    }
+}
+
 #[derive(Debug)]
 pub struct IpLimiters {
-    per_ip: RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>,
-    ip6_56: RateLimiter<IP6_56, DefaultKeyedStateStore<IP6_56>, DefaultClock>,
-    ip6_48: RateLimiter<IP6_48, DefaultKeyedStateStore<IP6_48>, DefaultClock>,
+    client_ip: ClientIp,
+    access: IpAccess,
+    per_ip: KeyedLimiter<IpAddr>,
+    ip6_56: KeyedLimiter<IP6_56>,
+    ip6_48: KeyedLimiter<IP6_48>,
 }
 
 impl IpLimiters {
-    pub fn new(quota: Quota) -> Self {
+    pub fn new(quota: Quota, client_ip: ClientIp, access: IpAccess) -> Self {
         Self {
-            per_ip: RateLimiter::keyed(quota),
-            ip6_56: RateLimiter::keyed(scale_quota(quota, 8).expect("to scale quota")),
-            ip6_48: RateLimiter::keyed(scale_quota(quota, 256).expect("to scale quota")),
+            client_ip,
+            access,
+            per_ip: keyed(quota),
+            ip6_56: keyed(scale_quota(quota, IP6_56_SCALE)),
+            ip6_48: keyed(scale_quota(quota, IP6_48_SCALE)),
         }
     }
 }
 
 impl Limiter<IpAddr> for IpLimiters {
     fn extract_key(&self, req: &Request) -> Result<IpAddr> {
-        Ok(req
-            .remote_addr()
-            .as_socket_addr()
-            .expect("failed to get request's remote addr") // TODO
-            .ip())
+        let ip = self.client_ip.extract(req)?;
+        if self.access.denies(&ip) {
+            log::debug!("refusing denylisted ip {ip}");
+            return Err(poem::Error::from_string(
+                "requests from this network are not accepted",
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Ok(ip)
     }
-    fn check_key(&self, ip: &IpAddr) -> Result<(), Duration> {
+    fn check_key(&self, ip: &IpAddr) -> Result<Option<LimitState>, Duration> {
+        if self.access.allows(ip) {
+            return Ok(None);
+        }
         let asdf = |n: NotUntil<_>| n.wait_time_from(CLOCK.now());
         match ip {
-            addr @ IpAddr::V4(_) => self.per_ip.check_key(addr).map_err(asdf),
+            addr @ IpAddr::V4(_) => self
+                .per_ip
+                .check_key(addr)
+                .map(|s| Some(s.into()))
+                .map_err(asdf),
             IpAddr::V6(a) => {
                 // always check all limiters
                 let check_ip = self
                     .per_ip
                     .check_key(&IpAddr::V6(a & IP6_64_MASK))
+                    .map(LimitState::from)
                     .map_err(asdf);
                 let check_56 = self
                     .ip6_56
@@ -111,6 +356,7 @@ impl Limiter<IpAddr> for IpLimiters {
                             .try_into()
                             .expect("to check ip6 /56 limiter"),
                     )
+                    .map(LimitState::from)
                     .map_err(asdf);
                 let check_48 = self
                     .ip6_48
@@ -119,8 +365,10 @@ impl Limiter<IpAddr> for IpLimiters {
                             .try_into()
                             .expect("to check ip6 /48 limiter"),
                     )
+                    .map(LimitState::from)
                     .map_err(asdf);
-                check_ip.and(check_56).and(check_48)
+                let (ip, s56, s48) = (check_ip?, check_56?, check_48?);
+                Ok(Some(ip.tightest(s56).tightest(s48)))
             }
         }
     }
@@ -137,6 +385,95 @@ impl Limiter<IpAddr> for IpLimiters {
     }
 }
 
+/// Extra per-ip quotas that only apply to some routes
+///
+/// Routes are matched by path segment prefix, and `*` matches any single
+/// segment: `/export` covers `/export` and `/export/stream`, `/*/log` covers
+/// `/{did}/log` and `/{did}/log/audit`. The first matching route wins.
+///
+/// Requests on other routes are exempt.
+#[derive(Debug)]
+pub struct RouteLimiters {
+    routes: Vec<(Vec<String>, IpLimiters)>,
+}
+
+impl RouteLimiters {
+    pub fn new(routes: Vec<(String, Quota)>, client_ip: ClientIp, access: IpAccess) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|(route, quota)| {
+                let segments = route
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                let limiters = IpLimiters::new(quota, client_ip.clone(), access.clone());
+                (segments, limiters)
+            })
+            .collect();
+        Self { routes }
+    }
+
+    fn find(&self, path: &str) -> Option<usize> {
+        let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        self.routes.iter().position(|(route, _)| {
+            route.len() <= path.len() && route.iter().zip(&path).all(|(r, p)| r == "*" || r == p)
+        })
+    }
+}
+
+impl Limiter<Option<(usize, IpAddr)>> for RouteLimiters {
+    fn extract_key(&self, req: &Request) -> Result<Option<(usize, IpAddr)>> {
+        let Some(i) = self.find(req.uri().path()) else {
+            return Ok(None);
+        };
+        // the route's limiters handle the allow and deny lists
+        let (_, limiters) = &self.routes[i];
+        let ip = limiters.extract_key(req)?;
+        Ok(Some((i, ip)))
+    }
+    fn check_key(&self, key: &Option<(usize, IpAddr)>) -> Result<Option<LimitState>, Duration> {
+        let Some((i, ip)) = key else {
+            return Ok(None);
+        };
+        self.routes[*i].1.check_key(ip)
+    }
+    fn housekeep(&self) {
+        for (_, limiters) in &self.routes {
+            limiters.housekeep();
+        }
+    }
+}
+
+/// Request-rate policy for `serve`
+#[derive(Debug, Clone)]
+pub struct RateLimitConf {
+    /// how to find the client ip to limit by
+    pub client_ip: ClientIp,
+    /// per-ip quota for all requests
+    pub global: Quota,
+    /// extra per-ip quotas for specific routes, see [`RouteLimiters`]
+    pub routes: Vec<(String, Quota)>,
+    /// per-ip quota for forwarded op submissions
+    pub write_per_ip: Quota,
+    /// per-did quota for forwarded op submissions
+    pub write_per_did: Quota,
+    pub access: IpAccess,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> Self {
+        Self {
+            client_ip: Default::default(),
+            global: Quota::per_minute(3000.try_into().unwrap()),
+            routes: vec![],
+            write_per_ip: Quota::per_hour(10.try_into().unwrap()),
+            write_per_did: Quota::per_hour(4.try_into().unwrap()),
+            access: Default::default(),
+        }
+    }
+}
+
 /// Once the rate limit has been reached, the middleware will respond with
 /// status code 429 (too many requests) and a `Retry-After` header with the amount
 /// of time that needs to pass before another request will be allowed.
@@ -198,15 +535,21 @@ where
     E: Endpoint,
     K: Hash + std::fmt::Debug + Send + Sync + 'static,
 {
-    type Output = E::Output;
+    type Output = Response;
 
     async fn call(&self, req: Request) -> Result<Self::Output> {
         let key = self.limiters.extract_key(&req)?;
 
         match self.limiters.check_key(&key) {
-            Ok(_) => {
+            Ok(None) => {
+                log::trace!("key exempt from limits: {key:?}");
+                self.ep.call(req).await.map(IntoResponse::into_response)
+            }
+            Ok(Some(state)) => {
                 log::debug!("allowing key {key:?}");
-                self.ep.call(req).await
+                let mut res = self.ep.call(req).await?.into_response();
+                set_ratelimit_headers(res.headers_mut(), state);
+                Ok(res)
             }
             Err(d) => {
                 let wait_time = d.as_secs();
@@ -217,6 +560,8 @@ where
                     .status(StatusCode::TOO_MANY_REQUESTS)
                     .header("x-ratelimit-after", wait_time)
                     .header("retry-after", wait_time)
+                    .header("ratelimit-remaining", 0)
+                    .header("ratelimit-reset", wait_time)
                     .body(booo());
                 Err(poem::Error::from_response(res))
             }
@@ -224,6 +569,19 @@ where
     }
 }
 
+/// add `RateLimit-*` headers, unless a tighter limit already set them
+fn set_ratelimit_headers(headers: &mut HeaderMap, state: LimitState) {
+    let existing_remaining = headers
+        .get("ratelimit-remaining")
+        .and_then(|v| v.to_str().ok()?.parse::<u32>().ok());
+    if existing_remaining.is_some_and(|r| r <= state.remaining) {
+        return;
+    }
+    headers.insert("ratelimit-limit", state.limit.into());
+    headers.insert("ratelimit-remaining", state.remaining.into());
+    headers.insert("ratelimit-reset", state.reset.as_secs().into());
+}
+
 fn booo() -> String {
     format!(
         r#"{}
@@ -235,3 +593,114 @@ Tip: check out the `x-ratelimit-after` response header.
         logo("mirror 429")
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client_ip(trusted: &[&str], header: ForwardedHeader) -> ClientIp {
+        let trusted = trusted.iter().map(|s| parse_ip_net(s).unwrap()).collect();
+        ClientIp::new(trusted, header)
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_huge_quotas() {
+        assert!(parse_quota("20000000/min").is_err());
+        assert!(parse_quota("4000000000/s").is_err());
+        assert!(parse_quota("3000/min").is_ok());
+        // anything that slips past parse_quota still scales without panicking
+        let huge = Quota::per_second(NonZeroU32::MAX);
+        let scaled = scale_quota(huge, IP6_48_SCALE);
+        assert_eq!(scaled.burst_size(), NonZeroU32::MAX);
+        assert!(!scaled.replenish_interval().is_zero());
+    }
+
+    #[test]
+    fn test_parse_ip_net_bare_ip() {
+        assert_eq!(
+            parse_ip_net("10.1.2.3").unwrap(),
+            "10.1.2.3/32".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            parse_ip_net("10.0.0.0/8").unwrap(),
+            "10.0.0.0/8".parse::<IpNet>().unwrap()
+        );
+        assert!(parse_ip_net("nope").is_err());
+    }
+
+    #[test]
+    fn test_xff_skips_trusted_hops() {
+        let c = client_ip(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let h = headers("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.5");
+        assert_eq!(c.forwarded_client(&h), Some(ip("2.2.2.2")));
+    }
+
+    #[test]
+    fn test_xff_all_trusted_takes_leftmost() {
+        let c = client_ip(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let h = headers("x-forwarded-for", "10.0.0.1, 10.0.0.2");
+        assert_eq!(c.forwarded_client(&h), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_xff_garbage_hop() {
+        let c = client_ip(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let h = headers("x-forwarded-for", "1.1.1.1, oops, 10.0.0.5");
+        assert_eq!(c.forwarded_client(&h), None);
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let c = client_ip(&["10.0.0.0/8"], ForwardedHeader::Forwarded);
+        let h = headers(
+            "forwarded",
+            r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.5:80;by=10.0.0.1"#,
+        );
+        assert_eq!(c.forwarded_client(&h), Some(ip("2001:db8:cafe::17")));
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(
+            parse_quota("3000/min"),
+            Ok(Quota::per_minute(3000.try_into().unwrap()))
+        );
+        assert_eq!(
+            parse_quota("4/hour"),
+            Ok(Quota::per_hour(4.try_into().unwrap()))
+        );
+        assert!(parse_quota("0/min").is_err());
+        assert!(parse_quota("10/fortnight").is_err());
+        assert!(parse_quota("10").is_err());
+    }
+
+    #[test]
+    fn test_route_limiters_find() {
+        let q = parse_quota("1/s").unwrap();
+        let routes = vec![("/export".to_string(), q), ("/*/log".to_string(), q)];
+        let r = RouteLimiters::new(routes, Default::default(), Default::default());
+        assert_eq!(r.find("/export"), Some(0));
+        assert_eq!(r.find("/export/stream"), Some(0));
+        assert_eq!(r.find("/exports"), None);
+        assert_eq!(r.find("/did:plc:abc/log/audit"), Some(1));
+        assert_eq!(r.find("/did:plc:abc"), None);
+        assert_eq!(r.find("/"), None);
+    }
+
+    #[test]
+    fn test_wrong_header_ignored() {
+        let c = client_ip(&["10.0.0.0/8"], ForwardedHeader::Forwarded);
+        let h = headers("x-forwarded-for", "1.1.1.1");
+        assert_eq!(c.forwarded_client(&h), None);
+    }
+}
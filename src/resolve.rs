@@ -0,0 +1,385 @@
+use crate::{Dt, Op, OpKey, OpSource, source_to_pages};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("no ops found for {0}")]
+    NotFound(String),
+    #[error("{0} has been tombstoned")]
+    Tombstoned(String),
+    #[error("failed to read op {cid}: {source}")]
+    BadOperation {
+        cid: String,
+        source: serde_json::Error,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    r#type: String,
+    endpoint: String,
+}
+
+/// The parts of a PLC operation needed for resolution
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Operation {
+    #[serde(rename = "plc_operation", rename_all = "camelCase")]
+    Plc {
+        rotation_keys: Vec<String>,
+        verification_methods: BTreeMap<String, String>,
+        also_known_as: Vec<String>,
+        services: BTreeMap<String, Service>,
+        prev: Option<String>,
+    },
+    /// the original genesis op format
+    #[serde(rename = "create", rename_all = "camelCase")]
+    LegacyCreate {
+        signing_key: String,
+        recovery_key: String,
+        handle: String,
+        service: String,
+    },
+    #[serde(rename = "plc_tombstone")]
+    Tombstone { prev: String },
+}
+
+impl Operation {
+    fn prev(&self) -> Option<&str> {
+        match self {
+            Self::Plc { prev, .. } => prev.as_deref(),
+            Self::LegacyCreate { .. } => None,
+            Self::Tombstone { prev } => Some(prev),
+        }
+    }
+}
+
+fn parse(op: &Op) -> Result<Operation, ResolveError> {
+    serde_json::from_str(op.operation.get()).map_err(|source| ResolveError::BadOperation {
+        cid: op.cid.clone(),
+        source,
+    })
+}
+
+/// A DID's ops as of some time, with nullification as it stood then
+#[derive(Debug)]
+pub struct DidHistory {
+    pub did: String,
+    /// every op up to the time, including nullified ones
+    pub audit_log: Vec<Op>,
+}
+
+impl DidHistory {
+    /// Replay a DID's ops up to `at` (inclusive), or all of them
+    ///
+    /// Nullification is recomputed by following `prev` links: each op extends
+    /// the chain from its `prev`, dropping anything after that. So an op
+    /// that was nullified after `at` shows as valid. The directory already
+    /// enforced its rules when accepting these ops, so they aren't re-checked.
+    ///
+    /// An op can come in more than once (say, from both a db and upstream's
+    /// export): only the first copy is kept.
+    pub fn replay(
+        did: &str,
+        ops: impl IntoIterator<Item = Op>,
+        at: Option<Dt>,
+    ) -> Result<Self, ResolveError> {
+        let mut seen = HashSet::new();
+        let mut ops: Vec<Op> = ops
+            .into_iter()
+            .filter(|op| op.did == did && at.is_none_or(|at| op.created_at <= at))
+            .filter(|op| seen.insert(OpKey::from(op)))
+            .collect();
+        ops.sort_by_key(|op| op.created_at);
+
+        let mut chain: Vec<usize> = vec![];
+        for (i, op) in ops.iter().enumerate() {
+            let parsed = parse(op)?;
+            match parsed
+                .prev()
+                .and_then(|prev| chain.iter().position(|&c| ops[c].cid == prev))
+            {
+                Some(p) => chain.truncate(p + 1),
+                None if parsed.prev().is_none() => chain.clear(),
+                None => log::warn!("op {} has an unknown prev, appending it anyway", op.cid),
+            }
+            chain.push(i);
+        }
+        for (i, op) in ops.iter_mut().enumerate() {
+            op.nullified = !chain.contains(&i);
+        }
+        Ok(Self {
+            did: did.to_string(),
+            audit_log: ops,
+        })
+    }
+
+    /// The latest op that's still valid
+    fn head(&self) -> Result<Operation, ResolveError> {
+        let op = self
+            .audit_log
+            .iter()
+            .rev()
+            .find(|op| !op.nullified)
+            .ok_or_else(|| ResolveError::NotFound(self.did.clone()))?;
+        parse(op)
+    }
+
+    /// The DID's current PLC data, like `GET /:did/data`
+    pub fn data(&self) -> Result<Value, ResolveError> {
+        let did = &self.did;
+        let (rotation_keys, verification_methods, also_known_as, services) = match self.head()? {
+            Operation::Plc {
+                rotation_keys,
+                verification_methods,
+                also_known_as,
+                services,
+                ..
+            } => (rotation_keys, verification_methods, also_known_as, services),
+            Operation::LegacyCreate {
+                signing_key,
+                recovery_key,
+                handle,
+                service,
+            } => (
+                vec![recovery_key, signing_key.clone()],
+                BTreeMap::from([("atproto".to_string(), signing_key)]),
+                vec![format!("at://{handle}")],
+                BTreeMap::from([(
+                    "atproto_pds".to_string(),
+                    Service {
+                        r#type: "AtprotoPersonalDataServer".to_string(),
+                        endpoint: service,
+                    },
+                )]),
+            ),
+            Operation::Tombstone { .. } => return Err(ResolveError::Tombstoned(did.clone())),
+        };
+        let services: BTreeMap<_, _> = services
+            .into_iter()
+            .map(|(id, Service { r#type, endpoint })| {
+                (id, json!({ "type": r#type, "endpoint": endpoint }))
+            })
+            .collect();
+        Ok(json!({
+            "did": did,
+            "verificationMethods": verification_methods,
+            "rotationKeys": rotation_keys,
+            "alsoKnownAs": also_known_as,
+            "services": services,
+        }))
+    }
+
+    /// The DID document, like `GET /:did`
+    pub fn document(&self) -> Result<Value, ResolveError> {
+        let did = &self.did;
+        let data = self.data()?;
+        let mut context = vec![
+            "https://www.w3.org/ns/did/v1",
+            "https://w3id.org/security/multikey/v1",
+        ];
+        let mut verification_method = vec![];
+        for (id, key) in data["verificationMethods"]
+            .as_object()
+            .into_iter()
+            .flatten()
+        {
+            let key = key.as_str().unwrap_or_default();
+            let multibase = key.strip_prefix("did:key:").unwrap_or(key);
+            // multicodec prefixes for secp256k1 and p256 public keys
+            let suite = if multibase.starts_with("zQ3s") {
+                Some("https://w3id.org/security/suites/secp256k1-2019/v1")
+            } else if multibase.starts_with("zDn") {
+                Some("https://w3id.org/security/suites/ecdsa-2019/v1")
+            } else {
+                None
+            };
+            if let Some(suite) = suite.filter(|s| !context.contains(s)) {
+                context.push(suite);
+            }
+            verification_method.push(json!({
+                "id": format!("{did}#{id}"),
+                "type": "Multikey",
+                "controller": did,
+                "publicKeyMultibase": multibase,
+            }));
+        }
+        let service: Vec<_> = data["services"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(id, s)| {
+                json!({
+                    "id": format!("#{id}"),
+                    "type": s["type"],
+                    "serviceEndpoint": s["endpoint"],
+                })
+            })
+            .collect();
+        Ok(json!({
+            "@context": context,
+            "id": did,
+            "alsoKnownAs": data["alsoKnownAs"],
+            "verificationMethod": verification_method,
+            "service": service,
+        }))
+    }
+}
+
+/// Find a DID's ops in a source, between `after` and `until`
+///
+/// Also returns the latest op time seen in the source (for any DID), which is
+/// where catching up from upstream should start. Databases are queried for
+/// the DID directly, everything else is scanned.
+pub async fn collect_did_ops(
+    source: OpSource,
+    did: &str,
+    after: Dt,
+    until: Dt,
+    throttle: Duration,
+) -> anyhow::Result<(Vec<Op>, Option<Dt>)> {
+    if let OpSource::Db(db) = source {
+        return Ok((db.ops_for_did(did).await?, db.get_latest().await?));
+    }
+    let (tx, mut rx) = mpsc::channel(4);
+    let reader = tokio::task::spawn(source_to_pages(source, after, until, throttle, tx));
+    let mut ops = vec![];
+    let mut latest = None;
+    while let Some(page) = rx.recv().await {
+        if let Some(last) = page.ops.last() {
+            latest = latest.max(Some(last.created_at));
+        }
+        ops.extend(page.ops.into_iter().filter(|op| op.did == did));
+    }
+    reader.await??;
+    Ok((ops, latest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DID: &str = "did:plc:test";
+
+    fn op(cid: &str, at: &str, nullified: bool, operation: Value) -> Op {
+        serde_json::from_value(json!({
+            "did": DID,
+            "cid": cid,
+            "createdAt": at,
+            "nullified": nullified,
+            "operation": operation,
+        }))
+        .unwrap()
+    }
+
+    fn plc_op(prev: Option<&str>, handle: &str) -> Value {
+        json!({
+            "type": "plc_operation",
+            "rotationKeys": ["did:key:zQ3shrotation"],
+            "verificationMethods": {"atproto": "did:key:zQ3shsigning"},
+            "alsoKnownAs": [format!("at://{handle}")],
+            "services": {"atproto_pds": {"type": "AtprotoPersonalDataServer", "endpoint": "https://pds.example.com"}},
+            "prev": prev,
+            "sig": "sig",
+        })
+    }
+
+    fn history() -> Vec<Op> {
+        vec![
+            op("a", "2024-01-01T00:00:00Z", false, plc_op(None, "one.test")),
+            // b was nullified by c, which forked from a
+            op(
+                "b",
+                "2024-01-02T00:00:00Z",
+                true,
+                plc_op(Some("a"), "two.test"),
+            ),
+            op(
+                "c",
+                "2024-01-03T00:00:00Z",
+                false,
+                plc_op(Some("a"), "three.test"),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_replay_current() {
+        let h = DidHistory::replay(DID, history(), None).unwrap();
+        let nullified: Vec<_> = h.audit_log.iter().map(|op| op.nullified).collect();
+        assert_eq!(nullified, vec![false, true, false]);
+        let doc = h.document().unwrap();
+        assert_eq!(doc["alsoKnownAs"], json!(["at://three.test"]));
+        assert_eq!(
+            doc["verificationMethod"][0]["publicKeyMultibase"],
+            "zQ3shsigning"
+        );
+        assert_eq!(
+            doc["service"][0]["serviceEndpoint"],
+            "https://pds.example.com"
+        );
+    }
+
+    #[test]
+    fn test_replay_duplicated_op() {
+        // like a catch-up from upstream repeating the db's latest op
+        let mut ops = history();
+        ops.push(ops[2].clone());
+        let h = DidHistory::replay(DID, ops, None).unwrap();
+        let nullified: Vec<_> = h.audit_log.iter().map(|op| op.nullified).collect();
+        assert_eq!(nullified, vec![false, true, false]);
+        assert_eq!(h.data().unwrap()["alsoKnownAs"], json!(["at://three.test"]));
+    }
+
+    #[test]
+    fn test_replay_at_time() {
+        let at = "2024-01-02T12:00:00Z".parse().unwrap();
+        let h = DidHistory::replay(DID, history(), Some(at)).unwrap();
+        assert_eq!(h.audit_log.len(), 2);
+        assert!(!h.audit_log[1].nullified, "b wasn't nullified yet");
+        assert_eq!(h.data().unwrap()["alsoKnownAs"], json!(["at://two.test"]));
+
+        let before = "2023-01-01T00:00:00Z".parse().unwrap();
+        let h = DidHistory::replay(DID, history(), Some(before)).unwrap();
+        assert!(matches!(h.document(), Err(ResolveError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_legacy_create_and_tombstone() {
+        let create = op(
+            "a",
+            "2022-11-17T00:00:00Z",
+            false,
+            json!({
+                "type": "create",
+                "signingKey": "did:key:zQ3shsigning",
+                "recoveryKey": "did:key:zQ3shrecovery",
+                "handle": "old.test",
+                "service": "https://pds.example.com",
+                "prev": null,
+                "sig": "sig",
+            }),
+        );
+        let h = DidHistory::replay(DID, vec![create.clone()], None).unwrap();
+        let data = h.data().unwrap();
+        assert_eq!(data["alsoKnownAs"], json!(["at://old.test"]));
+        assert_eq!(
+            data["rotationKeys"],
+            json!(["did:key:zQ3shrecovery", "did:key:zQ3shsigning"])
+        );
+
+        let tombstone = op(
+            "b",
+            "2022-11-18T00:00:00Z",
+            false,
+            json!({"type": "plc_tombstone", "prev": "a", "sig": "sig"}),
+        );
+        let h = DidHistory::replay(DID, vec![create, tombstone], None).unwrap();
+        assert!(matches!(h.document(), Err(ResolveError::Tombstoned(_))));
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{
+    CreatePlcOpLimiter, GovernorMiddleware, IpLimiters, RateLimitConf, UA, logo,
+    mirror::{forwarded_headers, proxy_target},
+};
+use futures::{StreamExt, stream::FuturesUnordered};
+use poem::{
+    Body, EndpointExt, IntoResponse, Request, Response, Route, Server, get, handler,
+    http::StatusCode,
+    listener::TcpListener,
+    middleware::{AddData, CatchPanic, Cors, Tracing},
+    post,
+    web::{Data, Json},
+};
+use reqwest::{Client, Url, header::HeaderMap};
+use serde::Serialize;
+use std::{net::SocketAddr, time::Duration};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScatterError {
+    #[error("no upstreams to scatter to")]
+    NoUpstreams,
+}
+
+/// When a scattered write counts as accepted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "snake_case")]
+pub enum ScatterRule {
+    /// any one upstream accepted it
+    #[default]
+    First,
+    /// every upstream accepted it
+    All,
+    /// a majority of upstreams accepted it
+    Quorum,
+}
+
+impl ScatterRule {
+    /// how many of `n` upstreams have to accept
+    fn needed(&self, n: usize) -> usize {
+        match self {
+            Self::First => 1.min(n),
+            Self::All => n,
+            Self::Quorum => n / 2 + 1,
+        }
+    }
+}
+
+/// The largest op submission we'll read, like the reference server's json
+/// body limit
+pub(crate) const MAX_OP_BYTES: usize = 100 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ScatterConf {
+    pub upstreams: Vec<Url>,
+    pub rule: ScatterRule,
+    /// per-upstream request timeout
+    pub timeout: Duration,
+}
+
+/// How one upstream handled a scattered write
+#[derive(Debug, Serialize)]
+pub struct UpstreamResult {
+    pub upstream: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// the upstream's response: json if it parses, otherwise a string
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl UpstreamResult {
+    fn accepted(&self) -> bool {
+        self.status.is_some_and(|s| (200..300).contains(&s))
+    }
+}
+
+/// The aggregate result of a scattered write
+#[derive(Debug, Serialize)]
+pub struct ScatterOutcome {
+    pub rule: ScatterRule,
+    pub accepted: bool,
+    /// upstreams that answered before the rule was decided
+    pub results: Vec<UpstreamResult>,
+    /// upstreams still working on it: their results are only logged
+    pub pending: Vec<String>,
+}
+
+async fn post_one(
+    client: Client,
+    target: Url,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> UpstreamResult {
+    let upstream = target.origin().ascii_serialization();
+    let res = client
+        .post(target)
+        .timeout(timeout)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            log::warn!("scatter: upstream write to {upstream} failed: {e}");
+            return UpstreamResult {
+                upstream,
+                status: None,
+                body: None,
+                error: Some(e.to_string()),
+            };
+        }
+    };
+    let status = res.status().as_u16();
+    let body = match res.text().await {
+        Ok(t) if t.is_empty() => None,
+        Ok(t) => Some(serde_json::from_str(&t).unwrap_or(serde_json::Value::String(t))),
+        Err(e) => Some(serde_json::Value::String(format!(
+            "(failed to read body: {e})"
+        ))),
+    };
+    log::debug!("scatter: {upstream} responded {status}");
+    UpstreamResult {
+        upstream,
+        status: Some(status),
+        body,
+        error: None,
+    }
+}
+
+/// POST the same body to several upstreams at once
+///
+/// Returns as soon as the rule is decided, one way or the other. Requests to
+/// upstreams that haven't answered yet keep going in the background.
+///
+/// With no targets, no rule could mean anything, so that's an error.
+pub async fn scatter_post(
+    client: &Client,
+    conf: &ScatterConf,
+    targets: Vec<Url>,
+    headers: HeaderMap,
+    body: Vec<u8>,
+) -> Result<ScatterOutcome, ScatterError> {
+    if targets.is_empty() {
+        return Err(ScatterError::NoUpstreams);
+    }
+    let n = targets.len();
+    let needed = conf.rule.needed(n);
+    let mut tasks: FuturesUnordered<_> = targets
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(i, target)| {
+            let task = tokio::task::spawn(post_one(
+                client.clone(),
+                target,
+                headers.clone(),
+                body.clone(),
+                conf.timeout,
+            ));
+            async move { (i, task.await) }
+        })
+        .collect();
+
+    let mut done = vec![false; n];
+    let mut results = vec![];
+    let (mut accepted, mut refused) = (0, 0);
+    while accepted < needed && n - refused >= needed {
+        let Some((i, joined)) = tasks.next().await else {
+            break;
+        };
+        let result = joined.unwrap_or_else(|e| UpstreamResult {
+            upstream: targets[i].origin().ascii_serialization(),
+            status: None,
+            body: None,
+            error: Some(format!("scatter task failed: {e}")),
+        });
+        if result.accepted() {
+            accepted += 1;
+        } else {
+            refused += 1;
+        }
+        done[i] = true;
+        results.push(result);
+    }
+
+    let pending = targets
+        .iter()
+        .zip(done)
+        .filter(|(_, done)| !done)
+        .map(|(t, _)| t.origin().ascii_serialization())
+        .collect();
+    Ok(ScatterOutcome {
+        rule: conf.rule,
+        accepted: accepted >= needed,
+        results,
+        pending,
+    })
+}
+
+impl IntoResponse for ScatterOutcome {
+    fn into_response(self) -> Response {
+        // if an upstream rejected the op itself, pass that along. otherwise
+        // it's on us (or them)
+        let status = if self.accepted {
+            StatusCode::OK
+        } else {
+            self.results
+                .iter()
+                .filter_map(|r| r.status)
+                .find(|s| (400..500).contains(s))
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .unwrap_or(StatusCode::BAD_GATEWAY)
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[handler]
+fn hello(Data(conf): Data<&ScatterConf>) -> String {
+    let upstreams: String = conf
+        .upstreams
+        .iter()
+        .map(|u| format!("    {u}\n"))
+        .collect();
+    format!(
+        r#"{}
+
+This is an Allegedly scatter server. It forwards PLC operations to several
+upstream PLC directories at once.
+
+
+Configured upstreams (rule: {:?}):
+
+{upstreams}
+
+Available APIs:
+
+    - POST /:did  Submit a PLC op. Responds with every upstream's result.
+
+
+    https://tangled.org/@microcosm.blue/Allegedly
+"#,
+        logo("scatter"),
+        conf.rule,
+    )
+}
+
+#[handler]
+async fn scatter_op(
+    Data(conf): Data<&ScatterConf>,
+    Data(client): Data<&Client>,
+    req: &Request,
+    body: Body,
+) -> poem::Result<ScatterOutcome> {
+    let targets = conf
+        .upstreams
+        .iter()
+        .map(|u| proxy_target(u, req.uri()))
+        .collect();
+    let body = body.into_bytes_limit(MAX_OP_BYTES).await?.to_vec();
+    scatter_post(client, conf, targets, forwarded_headers(req), body)
+        .await
+        .map_err(|e| poem::Error::from_string(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Run a standalone server that scatters PLC op submissions
+pub async fn serve_scatter(
+    conf: ScatterConf,
+    bind: SocketAddr,
+    limits: RateLimitConf,
+) -> anyhow::Result<&'static str> {
+    if conf.upstreams.is_empty() {
+        return Err(ScatterError::NoUpstreams.into());
+    }
+    log::info!(
+        "scattering writes to {} upstreams ({:?})",
+        conf.upstreams.len(),
+        conf.rule
+    );
+    let client = Client::builder().user_agent(UA).build()?;
+
+    let ip_limiter = IpLimiters::new(
+        limits.write_per_ip,
+        limits.client_ip.clone(),
+        limits.access.clone(),
+    );
+    let did_limiter = CreatePlcOpLimiter::new(limits.write_per_did);
+    let scatter = scatter_op
+        .with(GovernorMiddleware::new(did_limiter))
+        .with(GovernorMiddleware::new(ip_limiter));
+
+    let app = Route::new()
+        .at("/", get(hello))
+        .at("/:did<did:plc:[^/]+>", post(scatter))
+        .with(AddData::new(conf))
+        .with(AddData::new(client))
+        .with(Cors::new().allow_credentials(false))
+        .with(CatchPanic::new())
+        .with(Tracing);
+    Server::new(TcpListener::bind(bind)).run(app).await?;
+    Ok("serve_scatter (uh oh?)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use poem::Endpoint;
+
+    #[test]
+    fn test_rule_needed() {
+        assert_eq!(ScatterRule::First.needed(3), 1);
+        assert_eq!(ScatterRule::All.needed(3), 3);
+        assert_eq!(ScatterRule::Quorum.needed(3), 2);
+        assert_eq!(ScatterRule::Quorum.needed(4), 3);
+        assert_eq!(ScatterRule::Quorum.needed(1), 1);
+    }
+
+    /// A mock upstream that answers every request with `status` and `body`
+    /// after `delay`
+    async fn upstream(status: StatusCode, body: &'static str, delay: Duration) -> Url {
+        use poem::listener::{Acceptor, Listener};
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        let app = poem::endpoint::make(move |_| async move {
+            tokio::time::sleep(delay).await;
+            (status, body.to_string())
+        });
+        tokio::task::spawn(Server::new_with_acceptor(acceptor).run(app));
+        format!("http://{addr}/did:plc:abc123").parse().unwrap()
+    }
+
+    async fn ok() -> Url {
+        upstream(StatusCode::OK, "", Duration::ZERO).await
+    }
+
+    async fn slow() -> Url {
+        upstream(StatusCode::OK, "", Duration::from_secs(30)).await
+    }
+
+    async fn down() -> Url {
+        upstream(StatusCode::SERVICE_UNAVAILABLE, "", Duration::ZERO).await
+    }
+
+    async fn rejects() -> Url {
+        let body = r#"{"message":"Invalid signature on op"}"#;
+        upstream(StatusCode::BAD_REQUEST, body, Duration::ZERO).await
+    }
+
+    async fn scatter(rule: ScatterRule, targets: Vec<Url>) -> ScatterOutcome {
+        let conf = ScatterConf {
+            upstreams: targets.clone(),
+            rule,
+            timeout: Duration::from_secs(60),
+        };
+        let client = Client::new();
+        let post = scatter_post(&client, &conf, targets, HeaderMap::new(), vec![]);
+        tokio::time::timeout(Duration::from_secs(10), post)
+            .await
+            .expect("the rule to be decided without the slow upstream")
+            .unwrap()
+    }
+
+    fn origin(url: &Url) -> String {
+        url.origin().ascii_serialization()
+    }
+
+    #[tokio::test]
+    async fn test_no_upstreams() {
+        let conf = ScatterConf {
+            upstreams: vec![],
+            rule: ScatterRule::First,
+            timeout: Duration::from_secs(1),
+        };
+        let res = scatter_post(&Client::new(), &conf, vec![], HeaderMap::new(), vec![]).await;
+        assert!(matches!(res, Err(ScatterError::NoUpstreams)));
+        let limits = RateLimitConf::default();
+        let serving = serve_scatter(conf, "127.0.0.1:0".parse().unwrap(), limits).await;
+        assert!(serving.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_first() {
+        let (slow, ok) = (slow().await, ok().await);
+        let outcome = scatter(ScatterRule::First, vec![slow.clone(), ok]).await;
+        assert!(outcome.accepted);
+        assert_eq!(outcome.results.len(), 1);
+        assert_eq!(outcome.pending, vec![origin(&slow)]);
+        assert_eq!(outcome.into_response().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        let outcome = scatter(ScatterRule::All, vec![ok().await, ok().await]).await;
+        assert!(outcome.accepted);
+        assert!(outcome.pending.is_empty());
+
+        // one refusal decides it, without waiting on the slow one
+        let (slow, rejects) = (slow().await, rejects().await);
+        let outcome = scatter(ScatterRule::All, vec![slow.clone(), rejects]).await;
+        assert!(!outcome.accepted);
+        assert_eq!(outcome.pending, vec![origin(&slow)]);
+    }
+
+    #[tokio::test]
+    async fn test_quorum() {
+        let slow = slow().await;
+        let outcome = scatter(
+            ScatterRule::Quorum,
+            vec![ok().await, slow.clone(), ok().await],
+        )
+        .await;
+        assert!(outcome.accepted);
+        assert_eq!(outcome.pending, vec![origin(&slow)]);
+
+        let outcome = scatter(
+            ScatterRule::Quorum,
+            vec![ok().await, down().await, down().await],
+        )
+        .await;
+        assert!(!outcome.accepted);
+        assert!(outcome.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_status_passthrough() {
+        // an upstream's 4xx is about the op, so it's passed along with its body
+        let outcome = scatter(ScatterRule::First, vec![down().await, rejects().await]).await;
+        let res = outcome.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = res.into_body().into_json().await.unwrap();
+        let bodies: Vec<_> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["body"].clone())
+            .collect();
+        assert!(bodies.contains(&serde_json::json!({"message": "Invalid signature on op"})));
+
+        // any other failure is on the upstreams
+        let outcome = scatter(ScatterRule::First, vec![down().await]).await;
+        assert_eq!(outcome.into_response().status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_op_size_limit() {
+        let conf = ScatterConf {
+            upstreams: vec![ok().await],
+            rule: ScatterRule::First,
+            timeout: Duration::from_secs(1),
+        };
+        let app = Route::new()
+            .at("/:did", post(scatter_op))
+            .data(conf)
+            .data(Client::new());
+        let submit = async |len: usize| {
+            let req = Request::builder()
+                .method(poem::http::Method::POST)
+                .uri("/did:plc:abc123".parse().unwrap())
+                .body(vec![b' '; len]);
+            app.get_response(req).await.status()
+        };
+        assert_eq!(submit(MAX_OP_BYTES).await, StatusCode::OK);
+        assert_eq!(
+            submit(MAX_OP_BYTES + 1).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
@@ -0,0 +1,749 @@
+use crate::{Db, Dt, ExportPage, Note, NoteSigner, Op};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::{Stream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub type Hash = [u8; 32];
+
+/// RFC 6962 leaf hash
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([0x00])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+/// RFC 6962 interior node hash
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([0x01])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The leaf data committed to for an op: `{did} {cid} {createdAt}`
+///
+/// `createdAt` is formatted like PLC does, with milliseconds and a `Z`.
+pub fn op_leaf(op: &Op) -> String {
+    format!(
+        "{} {} {}",
+        op.did,
+        op.cid,
+        op.created_at
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    )
+}
+
+/// lookup key for finding an op's leaf by (did, cid)
+fn op_key(did: &str, cid: &str) -> [u8; 16] {
+    let full: Hash = Sha256::new()
+        .chain_update(did)
+        .chain_update(" ")
+        .chain_update(cid)
+        .finalize()
+        .into();
+    full[..16].try_into().unwrap()
+}
+
+/// largest power of two smaller than n (n > 1)
+fn split_point(n: u64) -> u64 {
+    1 << (63 - (n - 1).leading_zeros())
+}
+
+/// An append-only merkle tree of leaf hashes
+///
+/// Keeps every perfect subtree's hash: `levels[k][i]` covers leaves
+/// `i * 2^k .. (i + 1) * 2^k`. Roots and proofs for any tree size only need
+/// O(log n) hashing.
+#[derive(Debug, Default)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn len(&self) -> u64 {
+        self.levels.first().map(|l| l.len() as u64).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, leaf: Hash) {
+        let mut level = 0;
+        let mut hash = leaf;
+        loop {
+            if self.levels.len() == level {
+                self.levels.push(vec![]);
+            }
+            let nodes = &mut self.levels[level];
+            nodes.push(hash);
+            if nodes.len() % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+            level += 1;
+        }
+    }
+
+    /// RFC 6962 MTH over leaves `start..end`
+    ///
+    /// every perfect subtree reached by the RFC's splits is aligned, so it's
+    /// always in `levels`.
+    fn subtree(&self, start: u64, end: u64) -> Hash {
+        let n = end - start;
+        if n.is_power_of_two() {
+            let level = n.trailing_zeros() as usize;
+            return self.levels[level][(start >> level) as usize];
+        }
+        let k = split_point(n);
+        node_hash(
+            &self.subtree(start, start + k),
+            &self.subtree(start + k, end),
+        )
+    }
+
+    /// The root hash of the tree when it had `size` leaves
+    pub fn root(&self, size: u64) -> Option<Hash> {
+        match size {
+            0 => Some(Sha256::digest([]).into()),
+            s if s > self.len() => None,
+            s => Some(self.subtree(0, s)),
+        }
+    }
+
+    /// RFC 6962 audit path for leaf `index` in the tree of `size` leaves
+    pub fn inclusion_proof(&self, index: u64, size: u64) -> Option<Vec<Hash>> {
+        if index >= size || size > self.len() {
+            return None;
+        }
+        let mut proof = vec![];
+        self.path(index, 0, size, &mut proof);
+        Some(proof)
+    }
+
+    fn path(&self, m: u64, start: u64, end: u64, proof: &mut Vec<Hash>) {
+        let n = end - start;
+        if n == 1 {
+            return;
+        }
+        let k = split_point(n);
+        if m < k {
+            self.path(m, start, start + k, proof);
+            proof.push(self.subtree(start + k, end));
+        } else {
+            self.path(m - k, start + k, end, proof);
+            proof.push(self.subtree(start, start + k));
+        }
+    }
+
+    /// RFC 6962 consistency proof between tree sizes `old` and `new`
+    pub fn consistency_proof(&self, old: u64, new: u64) -> Option<Vec<Hash>> {
+        if old > new || new > self.len() {
+            return None;
+        }
+        let mut proof = vec![];
+        if old > 0 && old < new {
+            self.subproof(old, 0, new, true, &mut proof);
+        }
+        Some(proof)
+    }
+
+    fn subproof(&self, m: u64, start: u64, end: u64, whole: bool, proof: &mut Vec<Hash>) {
+        let n = end - start;
+        if m == n {
+            if !whole {
+                proof.push(self.subtree(start, end));
+            }
+            return;
+        }
+        let k = split_point(n);
+        if m <= k {
+            self.subproof(m, start, start + k, whole, proof);
+            proof.push(self.subtree(start + k, end));
+        } else {
+            self.subproof(m - k, start + k, end, false, proof);
+            proof.push(self.subtree(start, start + k));
+        }
+    }
+}
+
+/// Check an inclusion proof (RFC 9162 section 2.1.3.2)
+pub fn verify_inclusion(index: u64, size: u64, leaf: &Hash, proof: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut f, mut s) = (index, size - 1);
+    let mut r = *leaf;
+    for p in proof {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            r = node_hash(p, &r);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && &r == root
+}
+
+/// Check a consistency proof (RFC 9162 section 2.1.4.2)
+pub fn verify_consistency(
+    old: u64,
+    new: u64,
+    old_root: &Hash,
+    new_root: &Hash,
+    proof: &[Hash],
+) -> bool {
+    if old > new {
+        return false;
+    }
+    if old == new {
+        return proof.is_empty() && old_root == new_root;
+    }
+    if old == 0 {
+        return proof.is_empty();
+    }
+    let mut proof = proof.to_vec();
+    if old.is_power_of_two() {
+        proof.insert(0, *old_root);
+    }
+    let Some((first, rest)) = proof.split_first() else {
+        return false;
+    };
+    let (mut f, mut s) = (old - 1, new - 1);
+    while f & 1 == 1 {
+        f >>= 1;
+        s >>= 1;
+    }
+    let (mut fr, mut sr) = (*first, *first);
+    for c in rest {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && &fr == old_root && &sr == new_root
+}
+
+/// A tlog checkpoint, as in <https://c2sp.org/tlog-checkpoint>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub origin: String,
+    pub size: u64,
+    pub root: Hash,
+}
+
+impl Checkpoint {
+    /// The checkpoint body: origin, size and base64 root hash, one per line
+    pub fn body(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n",
+            self.origin,
+            self.size,
+            BASE64_STANDARD.encode(self.root)
+        )
+    }
+
+    /// Read a checkpoint body, ignoring any extension lines after the root
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let origin = lines.next().filter(|o| !o.is_empty())?.to_string();
+        let size = lines.next()?.parse().ok()?;
+        let root = BASE64_STANDARD.decode(lines.next()?).ok()?;
+        let root = root.try_into().ok()?;
+        Some(Self { origin, size, root })
+    }
+}
+
+/// Format proof hashes for a response: base64, one per line
+pub fn proof_text(proof: &[Hash]) -> String {
+    proof
+        .iter()
+        .map(|h| format!("{}\n", BASE64_STANDARD.encode(h)))
+        .collect()
+}
+
+/// bytes per persisted leaf: lookup key, createdAt micros, ingest seq, leaf hash
+const RECORD_LEN: usize = 16 + 8 + 8 + 32;
+
+/// A transparency log over PLC ops
+///
+/// Leaves are appended in the order ops show up, and each op is only ever
+/// logged once. When syncing from a database that's its ingest order (see
+/// [`tlog_sync`]), so an op with an older `createdAt` that's ingested late
+/// still gets logged. The tree lives in memory (roughly 100 bytes per op),
+/// and leaves are persisted to an append-only file so the log survives
+/// restarts.
+#[derive(Debug)]
+pub struct Tlog {
+    origin: String,
+    tree: MerkleTree,
+    index: HashMap<[u8; 16], u64>,
+    last_at: Option<Dt>,
+    /// the db ingest seq that's been logged through
+    synced: Option<i64>,
+    file: Option<BufWriter<File>>,
+    signer: Option<NoteSigner>,
+    /// the latest signed checkpoint, when we have a signer
+    published: Option<(Checkpoint, Note)>,
+}
+
+pub type SharedTlog = Arc<RwLock<Tlog>>;
+
+impl Tlog {
+    /// Open a log, loading any leaves already persisted at `path`
+    pub fn open(origin: String, path: Option<PathBuf>) -> std::io::Result<Self> {
+        let mut me = Self {
+            origin,
+            tree: Default::default(),
+            index: Default::default(),
+            last_at: None,
+            synced: None,
+            file: None,
+            signer: None,
+            published: None,
+        };
+        let Some(path) = path else {
+            return Ok(me);
+        };
+        if path.exists() {
+            // a crash mid-append can leave a torn record at the end: drop it,
+            // so new records don't land out of step
+            let len = std::fs::metadata(&path)?.len();
+            let whole = len - len % RECORD_LEN as u64;
+            if whole != len {
+                log::warn!(
+                    "tlog {path:?} ends in a partial record, truncating from {len} to {whole} bytes"
+                );
+                OpenOptions::new().write(true).open(&path)?.set_len(whole)?;
+            }
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut record = [0u8; RECORD_LEN];
+            loop {
+                match reader.read_exact(&mut record) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                let key: [u8; 16] = record[..16].try_into().unwrap();
+                let micros = i64::from_be_bytes(record[16..24].try_into().unwrap());
+                let seq = i64::from_be_bytes(record[24..32].try_into().unwrap());
+                let leaf: Hash = record[32..].try_into().unwrap();
+                me.index.insert(key, me.tree.len());
+                me.tree.push(leaf);
+                me.last_at = Dt::from_timestamp_micros(micros).max(me.last_at);
+                me.synced = Some(seq).max(me.synced);
+            }
+            log::info!("loaded {} tlog leaves from {path:?}", me.tree.len());
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        me.file = Some(BufWriter::new(file));
+        Ok(me)
+    }
+
+    /// Sign checkpoints with this key
+    ///
+    /// Once signing, proofs are served against the latest signed checkpoint
+    /// (see [`Tlog::publish`]) instead of the live tree.
+    pub fn with_signer(mut self, signer: NoteSigner) -> Self {
+        self.signer = Some(signer);
+        self.publish();
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        self.tree.len()
+    }
+
+    /// Time of the latest op in the log
+    pub fn last_at(&self) -> Option<Dt> {
+        self.last_at
+    }
+
+    /// The db ingest seq logged through so far, if anything's been logged
+    pub fn synced(&self) -> Option<i64> {
+        self.synced
+    }
+
+    /// Add ops that aren't logged yet, returning how many were new
+    ///
+    /// Ops within the batch are logged in `(createdAt, did, cid)` order.
+    pub fn append(&mut self, ops: &[Op]) -> std::io::Result<usize> {
+        let mut ops: Vec<&Op> = ops.iter().collect();
+        ops.sort_by(|a, b| (a.created_at, &a.did, &a.cid).cmp(&(b.created_at, &b.did, &b.cid)));
+        let seq = self.synced.unwrap_or(0);
+        self.push_ops(ops.into_iter().map(|op| (seq, op)))
+    }
+
+    /// Add ops in db ingest order, each with the ingest seq it was read at
+    pub fn append_ingested(&mut self, ops: &[(i64, Op)]) -> std::io::Result<usize> {
+        self.push_ops(ops.iter().map(|(seq, op)| (*seq, op)))
+    }
+
+    fn push_ops<'a>(&mut self, ops: impl Iterator<Item = (i64, &'a Op)>) -> std::io::Result<usize> {
+        let mut added = 0;
+        for (seq, op) in ops {
+            // already-logged ops still move the sync along
+            self.synced = Some(seq).max(self.synced);
+            let key = op_key(&op.did, &op.cid);
+            if self.index.contains_key(&key) {
+                continue;
+            }
+            let leaf = leaf_hash(op_leaf(op).as_bytes());
+            if let Some(ref mut file) = self.file {
+                file.write_all(&key)?;
+                file.write_all(&op.created_at.timestamp_micros().to_be_bytes())?;
+                file.write_all(&seq.to_be_bytes())?;
+                file.write_all(&leaf)?;
+            }
+            self.index.insert(key, self.tree.len());
+            self.tree.push(leaf);
+            self.last_at = Some(op.created_at).max(self.last_at);
+            added += 1;
+        }
+        if let Some(ref mut file) = self.file {
+            file.flush()?;
+        }
+        Ok(added)
+    }
+
+    /// The root hash when the log had `size` leaves, if it's been that big
+    pub fn root(&self, size: u64) -> Option<Hash> {
+        self.tree.root(size)
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        let size = self.tree.len();
+        Checkpoint {
+            origin: self.origin.clone(),
+            size,
+            root: self.tree.root(size).expect("root for current size"),
+        }
+    }
+
+    /// Sign the current checkpoint, if it's changed since the last one
+    pub fn publish(&mut self) {
+        let Some(ref signer) = self.signer else {
+            return;
+        };
+        if let Some((ref checkpoint, _)) = self.published
+            && checkpoint.size == self.tree.len()
+        {
+            return;
+        }
+        let checkpoint = self.checkpoint();
+        let note = Note::sign(checkpoint.body(), signer);
+        self.published = Some((checkpoint, note));
+    }
+
+    /// The checkpoint that proofs are served against, and its text
+    ///
+    /// That's the latest signed note if we're signing, otherwise the plain
+    /// body of the current checkpoint.
+    pub fn published(&self) -> (Checkpoint, String) {
+        match self.published {
+            Some((ref checkpoint, ref note)) => (checkpoint.clone(), note.to_string()),
+            None => {
+                let checkpoint = self.checkpoint();
+                let body = checkpoint.body();
+                (checkpoint, body)
+            }
+        }
+    }
+
+    /// Leaf index and audit path for an op, in the tree of `size` leaves
+    pub fn prove_inclusion(&self, did: &str, cid: &str, size: u64) -> Option<(u64, Vec<Hash>)> {
+        let index = *self.index.get(&op_key(did, cid))?;
+        let proof = self.tree.inclusion_proof(index, size)?;
+        Some((index, proof))
+    }
+
+    pub fn prove_consistency(&self, old: u64, new: u64) -> Option<Vec<Hash>> {
+        self.tree.consistency_proof(old, new)
+    }
+}
+
+/// Keep a tlog in sync with the ops in a mirror's database
+///
+/// Builds the log from the whole db history on first run, then keeps picking
+/// up newly ingested ops every `interval`, by the db's ingest seq (see
+/// [`crate::pages_to_pg`]). Going by ingest order rather than `createdAt`
+/// means an op that shows up late with an older timestamp isn't skipped. New
+/// checkpoints are signed as ops are added, if the log has a signer.
+pub async fn tlog_sync(
+    tlog: SharedTlog,
+    db: Db,
+    interval: Duration,
+) -> anyhow::Result<&'static str> {
+    loop {
+        let synced = tlog.read().unwrap().synced();
+        let added = match synced {
+            Some(seq) => {
+                let ops = db.ops_ingested_after(seq).await?;
+                sync_chunks(&tlog, ops).await?
+            }
+            None => {
+                // everything ingested through `seq` is in the history read
+                // after it, and anything after is picked up next time
+                let seq = db.ingest_seq().await?;
+                let ops = db.ops_between(None, None).await?.map_ok(|op| (seq, op));
+                sync_chunks(&tlog, ops).await?
+            }
+        };
+        if added > 0 {
+            log::debug!(
+                "tlog: added {added} ops, size now {}",
+                tlog.read().unwrap().size()
+            );
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn sync_chunks<E: Into<anyhow::Error>>(
+    tlog: &SharedTlog,
+    ops: impl Stream<Item = Result<(i64, Op), E>>,
+) -> anyhow::Result<usize> {
+    let mut ops = pin!(ops.try_chunks(10_000));
+    let mut added = 0;
+    while let Some(chunk) = ops.try_next().await.map_err(|e| e.1.into())? {
+        // file writes and hashing block, and so does the lock
+        let tlog = tlog.clone();
+        added += tokio::task::spawn_blocking(move || {
+            let mut tlog = tlog.write().unwrap();
+            let added = tlog.append_ingested(&chunk)?;
+            tlog.publish();
+            Ok::<_, std::io::Error>(added)
+        })
+        .await??;
+    }
+    Ok(added)
+}
+
+/// Log ops from a page stream, like from [`crate::poll_upstream`]
+///
+/// For building a log without a database, like a witness's own view.
+pub async fn pages_to_tlog(
+    mut rx: mpsc::Receiver<ExportPage>,
+    tlog: SharedTlog,
+) -> anyhow::Result<&'static str> {
+    while let Some(page) = rx.recv().await {
+        let tlog = tlog.clone();
+        let added =
+            tokio::task::spawn_blocking(move || tlog.write().unwrap().append(&page.ops)).await??;
+        log::trace!("tlog: added {added} ops from page");
+    }
+    Ok("pages_to_tlog")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn leaves(n: u64) -> Vec<Hash> {
+        (0..n).map(|i| leaf_hash(&i.to_be_bytes())).collect()
+    }
+
+    fn tree(n: u64) -> MerkleTree {
+        let mut t = MerkleTree::default();
+        for leaf in leaves(n) {
+            t.push(leaf);
+        }
+        t
+    }
+
+    /// MTH straight from the RFC
+    fn naive_root(leaves: &[Hash]) -> Hash {
+        match leaves.len() {
+            0 => Sha256::digest([]).into(),
+            1 => leaves[0],
+            n => {
+                let k = split_point(n as u64) as usize;
+                node_hash(&naive_root(&leaves[..k]), &naive_root(&leaves[k..]))
+            }
+        }
+    }
+
+    #[test]
+    fn test_roots_match_rfc() {
+        let t = tree(70);
+        let all = leaves(70);
+        for size in 0..=70 {
+            assert_eq!(t.root(size), Some(naive_root(&all[..size as usize])));
+        }
+        assert_eq!(t.root(71), None);
+    }
+
+    #[test]
+    fn test_inclusion_proofs_verify() {
+        let t = tree(33);
+        let all = leaves(33);
+        for size in 1..=33 {
+            let root = t.root(size).unwrap();
+            for index in 0..size {
+                let proof = t.inclusion_proof(index, size).unwrap();
+                assert!(verify_inclusion(
+                    index,
+                    size,
+                    &all[index as usize],
+                    &proof,
+                    &root
+                ));
+                assert!(!verify_inclusion(index, size, &all[0], &proof, &root) || index == 0);
+            }
+        }
+        assert!(t.inclusion_proof(33, 33).is_none());
+    }
+
+    #[test]
+    fn test_consistency_proofs_verify() {
+        let t = tree(33);
+        for new in 0..=33 {
+            let new_root = t.root(new).unwrap();
+            for old in 0..=new {
+                let old_root = t.root(old).unwrap();
+                let proof = t.consistency_proof(old, new).unwrap();
+                assert!(verify_consistency(old, new, &old_root, &new_root, &proof));
+                if old > 0 && old < new {
+                    let wrong = leaf_hash(b"nope");
+                    assert!(!verify_consistency(old, new, &wrong, &new_root, &proof));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tlog_append_dedups_and_orders() {
+        let op = |did: &str, at: &str| -> Op {
+            serde_json::from_value(serde_json::json!({
+                "did": did,
+                "cid": "cid",
+                "createdAt": at,
+                "nullified": false,
+                "operation": {},
+            }))
+            .unwrap()
+        };
+        let a = op("did:a", "2025-01-01T00:00:01Z");
+        let b = op("did:b", "2025-01-01T00:00:00Z");
+
+        let mut tlog = Tlog::open("test".into(), None).unwrap();
+        assert_eq!(tlog.append(&[a.clone(), b.clone()]).unwrap(), 2);
+        assert_eq!(tlog.append(&[a]).unwrap(), 0);
+        assert_eq!(tlog.size(), 2);
+
+        // b is earlier so it's logged first
+        let (index, proof) = tlog.prove_inclusion("did:b", "cid", 2).unwrap();
+        assert_eq!(index, 0);
+        let leaf = leaf_hash(op_leaf(&b).as_bytes());
+        let root = tlog.checkpoint().root;
+        assert!(verify_inclusion(index, 2, &leaf, &proof, &root));
+        assert_eq!(op_leaf(&b), "did:b cid 2025-01-01T00:00:00.000Z");
+
+        let checkpoint = tlog.checkpoint();
+        assert_eq!(Checkpoint::parse(&checkpoint.body()), Some(checkpoint));
+    }
+
+    #[test]
+    fn test_tlog_open_drops_torn_record() {
+        let op = |did: &str| -> Op {
+            serde_json::from_value(serde_json::json!({
+                "did": did,
+                "cid": "cid",
+                "createdAt": "2025-01-01T00:00:00Z",
+                "nullified": false,
+                "operation": {},
+            }))
+            .unwrap()
+        };
+        let path = std::env::temp_dir().join(format!("tlog-torn-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut tlog = Tlog::open("test".into(), Some(path.clone())).unwrap();
+        tlog.append(&[op("did:a"), op("did:b")]).unwrap();
+        let root = tlog.checkpoint().root;
+        drop(tlog);
+
+        // half of a third record, as if we crashed writing it
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[7; RECORD_LEN / 2]).unwrap();
+        drop(file);
+
+        let mut tlog = Tlog::open("test".into(), Some(path.clone())).unwrap();
+        assert_eq!(tlog.size(), 2);
+        assert_eq!(tlog.checkpoint().root, root);
+        tlog.append(&[op("did:c")]).unwrap();
+        let root = tlog.checkpoint().root;
+        drop(tlog);
+
+        let tlog = Tlog::open("test".into(), Some(path.clone())).unwrap();
+        assert_eq!(tlog.size(), 3);
+        assert_eq!(tlog.checkpoint().root, root);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            3 * RECORD_LEN as u64
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tlog_ingest_order() {
+        let op = |did: &str, at: &str| -> Op {
+            serde_json::from_value(serde_json::json!({
+                "did": did,
+                "cid": "cid",
+                "createdAt": at,
+                "nullified": false,
+                "operation": {},
+            }))
+            .unwrap()
+        };
+        let path = std::env::temp_dir().join(format!("tlog-ingest-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut tlog = Tlog::open("test".into(), Some(path.clone())).unwrap();
+        assert_eq!(tlog.synced(), None);
+        let a = op("did:a", "2025-01-01T00:00:01Z");
+        tlog.append_ingested(&[(4, a.clone())]).unwrap();
+        // ingested later but older: logged after, not skipped
+        let b = op("did:b", "2025-01-01T00:00:00Z");
+        assert_eq!(tlog.append_ingested(&[(4, a), (5, b)]).unwrap(), 1);
+        assert_eq!(tlog.prove_inclusion("did:b", "cid", 2).unwrap().0, 1);
+        drop(tlog);
+
+        let tlog = Tlog::open("test".into(), Some(path.clone())).unwrap();
+        assert_eq!(tlog.synced(), Some(5));
+        assert_eq!(tlog.size(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+//! POST matching ops to webhooks
+//!
+//! Ops are batched, and each batch goes to every configured url with the
+//! http client's retries. A cursor file remembers how far delivery got, so a
+//! restart picks up from there instead of missing or repeating much.
+//!
+//! A url that still fails after the retries is dropped, and the others carry
+//! on. The cursor then stays where that url stopped, so a restart sends it
+//! everything it missed (and repeats those ops for the rest).
+
+use crate::{Dt, ExportPage, HttpClient, Op, OpFilter, metrics};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// The header with the body's HMAC-SHA256, as `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "x-allegedly-signature";
+
+/// How often to save a cursor that only moved past ops that didn't match
+const CURSOR_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("failed to deliver to webhook {url}: {source}")]
+    Request {
+        url: Url,
+        source: reqwest_middleware::Error,
+    },
+    #[error("webhook {url} responded {status}")]
+    Status {
+        url: Url,
+        status: reqwest::StatusCode,
+    },
+    #[error("failed to read or write webhook cursor {path:?}: {source}")]
+    Cursor {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("bad webhook cursor in {path:?}: {source}")]
+    BadCursor {
+        path: PathBuf,
+        source: chrono::ParseError,
+    },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Where and how to deliver
+#[derive(Debug, Clone)]
+pub struct WebhookConf {
+    pub urls: Vec<Url>,
+    /// signs each body, for receivers to check
+    pub secret: Option<String>,
+    /// send once this many ops match
+    pub batch_size: usize,
+    /// or once the oldest op in a batch has waited this long
+    pub batch_wait: Duration,
+    /// remembers the latest op handled, once everything before it is delivered
+    pub cursor: Option<PathBuf>,
+}
+
+impl Default for WebhookConf {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: None,
+            batch_size: 100,
+            batch_wait: Duration::from_secs(5),
+            cursor: None,
+        }
+    }
+}
+
+/// What each webhook gets
+#[derive(Debug, Serialize)]
+struct Delivery<'a> {
+    /// resume with this as `after` to get the ops after this batch
+    cursor: Option<Dt>,
+    ops: &'a [Op],
+}
+
+/// The value for [`SIGNATURE_HEADER`]
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac to take any key length");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+/// The saved cursor, if there is one yet
+pub fn read_cursor(path: &Path) -> Result<Option<Dt>, WebhookError> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(WebhookError::Cursor {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    };
+    let at = text
+        .trim()
+        .parse()
+        .map_err(|source| WebhookError::BadCursor {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(Some(at))
+}
+
+/// Replace the cursor file, via a rename so it's never half-written
+async fn write_cursor(path: &Path, at: Dt) -> Result<(), WebhookError> {
+    let err = |source| WebhookError::Cursor {
+        path: path.to_path_buf(),
+        source,
+    };
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, at.to_rfc3339()).await.map_err(err)?;
+    tokio::fs::rename(&tmp, path).await.map_err(err)
+}
+
+/// Send a batch to each url, with each one's outcome in the same order
+async fn deliver(
+    client: &HttpClient,
+    secret: Option<&str>,
+    urls: &[Url],
+    ops: &[Op],
+    cursor: Option<Dt>,
+) -> Result<Vec<Result<(), WebhookError>>, WebhookError> {
+    let body = serde_json::to_vec(&Delivery { cursor, ops })?;
+    let signature = secret.map(|secret| sign(secret, &body));
+    let sends = urls.iter().map(|url| {
+        let mut req = client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(ref signature) = signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+        async move {
+            let res = req.send().await.map_err(|source| WebhookError::Request {
+                url: url.clone(),
+                source,
+            })?;
+            if !res.status().is_success() {
+                return Err(WebhookError::Status {
+                    url: url.clone(),
+                    status: res.status(),
+                });
+            }
+            Ok(())
+        }
+    });
+    Ok(futures::future::join_all(sends).await)
+}
+
+/// Send ops matching `filter` to every webhook in `conf`
+///
+/// Delivery is at least once: a url that still fails after the client's
+/// retries gets nothing more, and the cursor stays before the batch it
+/// missed. This only stops with an error once every url has failed.
+pub async fn pages_to_webhooks(
+    mut rx: mpsc::Receiver<ExportPage>,
+    client: HttpClient,
+    conf: WebhookConf,
+    filter: Option<OpFilter>,
+) -> Result<&'static str, WebhookError> {
+    let mut batch: Vec<Op> = vec![];
+    let mut batch_deadline = None;
+    let mut live = conf.urls.clone();
+    // the latest op handled so far, matching or not
+    let mut seen_at = None;
+    let mut saved_at = None;
+    let mut last_save = Instant::now();
+    let mut save_deadline = None;
+    loop {
+        let wake = batch_deadline.into_iter().chain(save_deadline).min();
+        let page = match wake {
+            Some(deadline) => tokio::select! {
+                page = rx.recv() => page,
+                () = tokio::time::sleep_until(deadline) => {
+                    // flush or save below
+                    Some(ExportPage { ops: vec![] })
+                }
+            },
+            None => rx.recv().await,
+        };
+        let done = page.is_none();
+        if let Some(mut page) = page {
+            if let Some(op) = page.ops.last() {
+                seen_at = seen_at.max(Some(op.created_at));
+            }
+            if let Some(ref filter) = filter {
+                filter.apply(&mut page);
+            }
+            if batch.is_empty() && !page.is_empty() {
+                batch_deadline = Some(Instant::now() + conf.batch_wait);
+            }
+            batch.extend(page.ops);
+        }
+
+        let due = batch_deadline.is_some_and(|d| d <= Instant::now());
+        let flush = !batch.is_empty() && (done || due || batch.len() >= conf.batch_size);
+        if flush {
+            let mut chunks = batch.chunks(conf.batch_size.max(1)).peekable();
+            while let Some(chunk) = chunks.next() {
+                // only the last chunk covers everything seen, including
+                // ops filtered out after it
+                let cursor = match chunks.peek() {
+                    Some(_) => chunk.last().map(|op| op.created_at),
+                    None => seen_at,
+                };
+                let results =
+                    deliver(&client, conf.secret.as_deref(), &live, chunk, cursor).await?;
+                let mut failed = vec![];
+                for (url, res) in live.iter().zip(results) {
+                    if let Err(e) = res {
+                        metrics::WEBHOOK_FAILURES.inc();
+                        log::error!("giving up on this webhook, the cursor stays before it: {e}");
+                        failed.push((url.clone(), e));
+                    }
+                }
+                live.retain(|url| !failed.iter().any(|(f, _)| f == url));
+                if live.is_empty()
+                    && let Some((_, e)) = failed.pop()
+                {
+                    return Err(e);
+                }
+                metrics::WEBHOOK_OPS.add(chunk.len() as u64);
+            }
+            log::debug!("delivered {} ops to webhooks", batch.len());
+            batch.clear();
+            batch_deadline = None;
+        }
+        // everything up to `seen_at` is out the door, unless a url was dropped
+        save_deadline = None;
+        if batch.is_empty()
+            && seen_at != saved_at
+            && live.len() == conf.urls.len()
+            && let (Some(path), Some(at)) = (&conf.cursor, seen_at)
+        {
+            // save right after a delivery, otherwise only now and then
+            let next_save = last_save + CURSOR_EVERY;
+            if flush || done || next_save <= Instant::now() {
+                write_cursor(path, at).await?;
+                saved_at = seen_at;
+                last_save = Instant::now();
+            } else {
+                save_deadline = Some(next_save);
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    Ok("pages_to_webhooks")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cursor_roundtrip() {
+        let path = std::env::temp_dir().join(format!("webhook-cursor-{}", std::process::id()));
+        assert_eq!(read_cursor(&path).unwrap(), None);
+        let at: Dt = "2025-01-01T00:00:00.123Z".parse().unwrap();
+        write_cursor(&path, at).await.unwrap();
+        assert_eq!(read_cursor(&path).unwrap(), Some(at));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_chunk_cursors() {
+        use poem::{
+            EndpointExt, Route, Server,
+            listener::{Acceptor, Listener, TcpListener},
+            post,
+            web::{Data, Json},
+        };
+        use std::sync::{Arc, Mutex};
+
+        type Cursors = Arc<Mutex<Vec<Option<Dt>>>>;
+
+        #[poem::handler]
+        fn hook(Data(cursors): Data<&Cursors>, Json(body): Json<serde_json::Value>) {
+            let cursor = body["cursor"].as_str().map(|c| c.parse().unwrap());
+            cursors.lock().unwrap().push(cursor);
+        }
+
+        let cursors = Cursors::default();
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        let app = Route::new().at("/", post(hook)).data(cursors.clone());
+        let server = tokio::task::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+        let base: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+        let at = |s| base + chrono::Duration::seconds(s);
+        let ops = (0..4)
+            .map(|i| Op {
+                did: format!("did:plc:{}", if i == 3 { "other" } else { "hooked" }),
+                cid: format!("cid{i}"),
+                created_at: at(i),
+                nullified: false,
+                operation: serde_json::value::RawValue::from_string("{}".into()).unwrap(),
+            })
+            .collect();
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(ExportPage { ops }).await.unwrap();
+        drop(tx);
+
+        let conf = WebhookConf {
+            urls: vec![format!("http://{addr}/").parse().unwrap()],
+            batch_size: 2,
+            ..Default::default()
+        };
+        let filter = OpFilter {
+            dids: Some(["did:plc:hooked".to_string()].into()),
+            ..Default::default()
+        };
+        pages_to_webhooks(rx, HttpClient::default(), conf, Some(filter))
+            .await
+            .unwrap();
+        server.abort();
+
+        // the first chunk ends at its own last op; the last covers the
+        // filtered-out op after it too
+        assert_eq!(*cursors.lock().unwrap(), vec![Some(at(1)), Some(at(3))]);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_failing_url_is_dropped() {
+        use poem::{
+            EndpointExt, Route, Server,
+            http::StatusCode,
+            listener::{Acceptor, Listener, TcpListener},
+            post,
+            web::Data,
+        };
+        use std::sync::{Arc, Mutex};
+
+        #[poem::handler]
+        fn ok(Data(got): Data<&Arc<Mutex<usize>>>) {
+            *got.lock().unwrap() += 1;
+        }
+
+        #[poem::handler]
+        fn down() -> StatusCode {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+
+        let got = Arc::new(Mutex::new(0usize));
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        let app = Route::new()
+            .at("/ok", post(ok))
+            .at("/down", post(down))
+            .data(got.clone());
+        let server = tokio::task::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+        let client = crate::ClientConf {
+            max_retries: 0,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let cursor = std::env::temp_dir().join(format!("webhook-dropped-{}", std::process::id()));
+        let base: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+        let page = |i| ExportPage {
+            ops: vec![Op {
+                did: "did:plc:hooked".to_string(),
+                cid: format!("cid{i}"),
+                created_at: base + chrono::Duration::seconds(i),
+                nullified: false,
+                operation: serde_json::value::RawValue::from_string("{}".into()).unwrap(),
+            }],
+        };
+        let run = |urls: Vec<&str>| {
+            let conf = WebhookConf {
+                urls: urls
+                    .into_iter()
+                    .map(|path| format!("http://{addr}{path}").parse().unwrap())
+                    .collect(),
+                batch_size: 1,
+                cursor: Some(cursor.clone()),
+                ..Default::default()
+            };
+            let (tx, rx) = mpsc::channel(3);
+            for i in 0..3 {
+                tx.try_send(page(i)).unwrap();
+            }
+            drop(tx);
+            pages_to_webhooks(rx, client.clone(), conf, None)
+        };
+
+        // the working url gets everything, but the cursor isn't saved past
+        // what the dropped one missed
+        run(vec!["/ok", "/down"]).await.unwrap();
+        assert_eq!(*got.lock().unwrap(), 3);
+        assert_eq!(read_cursor(&cursor).unwrap(), None);
+
+        // with every url down, the sink fails
+        let res = run(vec!["/down"]).await;
+        assert!(matches!(res, Err(WebhookError::Status { .. })), "{res:?}");
+        server.abort();
+    }
+}
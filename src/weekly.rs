@@ -1,11 +1,14 @@
-use crate::{CLIENT, Dt, ExportPage, Op};
+use crate::{DeadLetter, Dt, ExportPage, HttpClient, Op, RejectError, channel_stream, reject};
 use async_compression::tokio::bufread::GzipDecoder;
 use async_compression::tokio::write::GzipEncoder;
 use core::pin::pin;
+use futures::{Stream, TryStreamExt};
 use reqwest::Url;
+use std::convert::Infallible;
 use std::future::Future;
-use std::ops::{Bound, RangeBounds};
+use std::ops::Bound;
 use std::path::PathBuf;
+use thiserror::Error;
 use tokio::{
     fs::File,
     io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
@@ -23,14 +26,13 @@ impl Week {
     pub const fn from_n(n: i64) -> Self {
         Self(n)
     }
-    pub fn range(r: impl RangeBounds<Week>) -> Vec<Self> {
-        let first = match r.start_bound() {
-            Bound::Included(week) => *week,
-            Bound::Excluded(week) => week.next(),
-            Bound::Unbounded => panic!("week range must have a defined start bound"),
-        };
-        let last = match r.end_bound() {
-            Bound::Included(week) => *week,
+    /// Weeks from `first` through `end`
+    ///
+    /// An unbounded end stops at the last week outside the nullification
+    /// window.
+    pub fn range(first: Week, end: Bound<Week>) -> Vec<Self> {
+        let last = match end {
+            Bound::Included(week) => week,
             Bound::Excluded(week) => week.prev(),
             Bound::Unbounded => Self(Self::nullification_cutoff()).prev(),
         };
@@ -84,17 +86,43 @@ impl From<Week> for Dt {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("failed to read bundle: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    ReqwestMiddleware(#[from] reqwest_middleware::Error),
+    #[error("bad bundle url: {0}")]
+    Url(String),
+    #[error(transparent)]
+    Rejected(#[from] RejectError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("nobody is receiving pages anymore")]
+    Closed,
+    #[error("bundle worker failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("failed to get pages to write: {0}")]
+    Source(Box<dyn std::error::Error + Send + Sync>),
+}
+
 pub trait BundleSource: Clone {
+    /// Open a week's gzipped bundle
+    ///
+    /// The reader can't borrow from the source, so that streams of pages can
+    /// outlive it.
     fn reader_for(
         &self,
         week: Week,
-    ) -> impl Future<Output = anyhow::Result<impl AsyncRead + Send>> + Send;
+    ) -> impl Future<Output = Result<impl AsyncRead + Send + use<Self>, BundleError>> + Send;
 }
 
 #[derive(Debug, Clone)]
 pub struct FolderSource(pub PathBuf);
 impl BundleSource for FolderSource {
-    async fn reader_for(&self, week: Week) -> anyhow::Result<impl AsyncRead> {
+    async fn reader_for(&self, week: Week) -> Result<impl AsyncRead + use<>, BundleError> {
         let FolderSource(dir) = self;
         let path = dir.join(format!("{}.jsonl.gz", week.0));
         log::debug!("opening folder source: {path:?}");
@@ -106,13 +134,14 @@ impl BundleSource for FolderSource {
 }
 
 #[derive(Debug, Clone)]
-pub struct HttpSource(pub Url);
+pub struct HttpSource(pub Url, pub HttpClient);
 impl BundleSource for HttpSource {
-    async fn reader_for(&self, week: Week) -> anyhow::Result<impl AsyncRead> {
-        use futures::TryStreamExt;
-        let HttpSource(base) = self;
-        let url = base.join(&format!("{}.jsonl.gz", week.0))?;
-        Ok(CLIENT
+    async fn reader_for(&self, week: Week) -> Result<impl AsyncRead + use<>, BundleError> {
+        let HttpSource(base, client) = self;
+        let url = base
+            .join(&format!("{}.jsonl.gz", week.0))
+            .map_err(|e| BundleError::Url(e.to_string()))?;
+        Ok(client
             .get(url)
             .send()
             .await?
@@ -125,10 +154,22 @@ impl BundleSource for HttpSource {
 }
 
 pub async fn pages_to_weeks(
-    mut rx: mpsc::Receiver<ExportPage>,
+    rx: mpsc::Receiver<ExportPage>,
     dir: PathBuf,
     clobber: bool,
-) -> anyhow::Result<()> {
+) -> Result<(), BundleError> {
+    stream_to_weeks(channel_stream::<Infallible>(rx), dir, clobber).await
+}
+
+/// Write a stream of pages to weekly bundles in `dir`
+///
+/// Ops are expected in order: a new bundle file is started whenever an op's
+/// week differs from the previous op's.
+pub async fn stream_to_weeks<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    pages: impl Stream<Item = Result<ExportPage, E>>,
+    dir: PathBuf,
+    clobber: bool,
+) -> Result<(), BundleError> {
     pub use std::time::Instant;
 
     // ...there is certainly a nicer way to write this
@@ -141,7 +182,12 @@ pub async fn pages_to_weeks(
     let mut week_ops = 0;
     let mut week_t0 = total_t0;
 
-    while let Some(page) = rx.recv().await {
+    let mut pages = pin!(pages);
+    while let Some(page) = pages
+        .try_next()
+        .await
+        .map_err(|e| BundleError::Source(e.into()))?
+    {
         for op in page.ops {
             let op_week = op.created_at.into();
             if current_week.map(|w| w != op_week).unwrap_or(true) {
@@ -195,32 +241,56 @@ pub async fn week_to_pages(
     source: impl BundleSource,
     week: Week,
     dest: mpsc::Sender<ExportPage>,
-) -> anyhow::Result<()> {
-    use futures::TryStreamExt;
-    let reader = source
-        .reader_for(week)
-        .await
-        .inspect_err(|e| log::error!("week_to_pages reader failed: {e}"))?;
-    let decoder = GzipDecoder::new(BufReader::new(reader));
-    let mut chunks = pin!(LinesStream::new(BufReader::new(decoder).lines()).try_chunks(10000));
+) -> Result<(), BundleError> {
+    let mut pages = pin!(week_stream(source, week));
+    while let Some(page) = pages.try_next().await? {
+        dest.send(page).await.map_err(|_| {
+            log::error!("failed to send page: receiver closed");
+            BundleError::Closed
+        })?;
+    }
+    Ok(())
+}
 
-    while let Some(chunk) = chunks
-        .try_next()
-        .await
-        .inspect_err(|e| log::error!("failed to get next chunk: {e}"))?
-    {
-        let ops: Vec<Op> = chunk
-            .into_iter()
-            .filter_map(|s| {
-                serde_json::from_str::<Op>(&s)
-                    .inspect_err(|e| log::warn!("failed to parse op: {e} ({s})"))
-                    .ok()
-            })
-            .collect();
-        let page = ExportPage { ops };
-        dest.send(page)
+/// Read one week's bundle as a stream of pages
+pub fn week_stream(
+    source: impl BundleSource,
+    week: Week,
+) -> impl Stream<Item = Result<ExportPage, BundleError>> {
+    let reader = async move {
+        source
+            .reader_for(week)
             .await
-            .inspect_err(|e| log::error!("failed to send page: {e}"))?;
+            .inspect_err(|e| log::error!("week_stream reader failed: {e}"))
+    };
+    futures::stream::once(reader)
+        .map_ok(move |reader| {
+            let decoder = GzipDecoder::new(BufReader::new(reader));
+            LinesStream::new(BufReader::new(decoder).lines())
+                .try_chunks(10000)
+                .map_err(|e| {
+                    log::error!("failed to get next chunk: {}", e.1);
+                    BundleError::Io(e.1)
+                })
+                .and_then(move |lines| parse_bundle_lines(week, lines))
+        })
+        .try_flatten()
+}
+
+/// Parse a chunk of a bundle's lines, sending any that aren't ops to the dead
+/// letters
+async fn parse_bundle_lines(week: Week, lines: Vec<String>) -> Result<ExportPage, BundleError> {
+    let source = format!("bundle week {}", Dt::from(week));
+    let mut ops = Vec::with_capacity(lines.len());
+    let mut rejected = vec![];
+    for line in lines {
+        match serde_json::from_str::<Op>(&line) {
+            Ok(op) => ops.push(op),
+            Err(e) => rejected.push(DeadLetter::new(&source, line.as_bytes(), e)),
+        }
     }
-    Ok(())
+    for letter in rejected {
+        reject(letter).await?;
+    }
+    Ok(ExportPage { ops })
 }
@@ -0,0 +1,328 @@
+use crate::{
+    Checkpoint, Hash, Note, NoteSigner, NoteVerifier, SharedTlog, UA, logo, metrics,
+    verify_consistency,
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use poem::{
+    EndpointExt, Error, Route, Server, get, handler,
+    http::StatusCode,
+    listener::TcpListener,
+    middleware::{AddData, CatchPanic, Tracing},
+    web::Data,
+};
+use reqwest::{Client, Url};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// The latest checkpoint we've cosigned
+pub type Witnessed = Arc<RwLock<Option<Note>>>;
+
+#[derive(Debug)]
+pub struct WitnessConf {
+    /// base url of the allegedly mirror serving the log
+    pub log: Url,
+    /// the log's checkpoint signing key
+    pub log_key: NoteVerifier,
+    /// our cosigning key
+    pub signer: NoteSigner,
+    /// our own view of the log, built from upstream's ops
+    pub tlog: SharedTlog,
+    /// where to keep the latest cosigned checkpoint across restarts
+    pub state: Option<PathBuf>,
+    /// how often to check for a new checkpoint
+    pub interval: Duration,
+}
+
+/// Load the last cosigned checkpoint, if there is one
+pub async fn load_witnessed(path: &Option<PathBuf>) -> anyhow::Result<Witnessed> {
+    let note = match path {
+        Some(path) if tokio::fs::try_exists(path).await? => {
+            let text = tokio::fs::read_to_string(path).await?;
+            Some(Note::parse(&text)?)
+        }
+        _ => None,
+    };
+    Ok(Arc::new(RwLock::new(note)))
+}
+
+async fn fetch_note(client: &Client, url: Url) -> anyhow::Result<String> {
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
+}
+
+/// Check the log's new checkpoint against the last one we witnessed
+///
+/// Anything wrong here means the log is either broken or lying, so we refuse
+/// to cosign.
+async fn check_consistent(
+    client: &Client,
+    log: &Url,
+    old: &Checkpoint,
+    new: &Checkpoint,
+) -> anyhow::Result<()> {
+    if new.origin != old.origin {
+        anyhow::bail!(
+            "log origin changed from {:?} to {:?}",
+            old.origin,
+            new.origin
+        );
+    }
+    if new.size < old.size {
+        anyhow::bail!("log shrank from {} to {} leaves", old.size, new.size);
+    }
+    if new.size == old.size {
+        if new.root != old.root {
+            anyhow::bail!("log root changed at size {}: split view?", new.size);
+        }
+        return Ok(());
+    }
+    let mut url = log.join("/tlog/proof/consistency")?;
+    url.query_pairs_mut()
+        .append_pair("old", &old.size.to_string())
+        .append_pair("new", &new.size.to_string());
+    let proof = fetch_note(client, url)
+        .await?
+        .lines()
+        .map(|line| {
+            let hash = BASE64_STANDARD.decode(line)?;
+            Hash::try_from(hash).map_err(|_| anyhow::anyhow!("proof hash with wrong length"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !verify_consistency(old.size, new.size, &old.root, &new.root, &proof) {
+        anyhow::bail!(
+            "log at size {} is not consistent with size {}",
+            new.size,
+            old.size
+        );
+    }
+    Ok(())
+}
+
+/// Fetch, verify, and maybe cosign the log's latest checkpoint
+///
+/// The checkpoint's root has to match our own view of the log at that size,
+/// so we only cosign what we've seen for ourselves. Returns `Ok(None)` if
+/// there was nothing new to cosign, or our view hasn't caught up to it yet.
+async fn witness_once(
+    client: &Client,
+    conf: &WitnessConf,
+    previous: Option<&Note>,
+) -> anyhow::Result<Option<Note>> {
+    let text = fetch_note(client, conf.log.join("/tlog/checkpoint")?).await?;
+    let mut note = Note::parse(&text)?;
+    if !note.verified_by(&conf.log_key) {
+        anyhow::bail!("checkpoint isn't signed by {}", conf.log_key.name());
+    }
+    let checkpoint = Checkpoint::parse(&note.text)
+        .ok_or_else(|| anyhow::anyhow!("signed note isn't a checkpoint"))?;
+    if note.text != checkpoint.body() {
+        anyhow::bail!("checkpoint has extension lines, which we don't cosign");
+    }
+
+    let (ours, our_size) = {
+        let tlog = conf.tlog.read().unwrap();
+        (tlog.root(checkpoint.size), tlog.size())
+    };
+    match ours {
+        None => {
+            log::debug!(
+                "log is at size {} but our own view is only at {our_size}, waiting",
+                checkpoint.size
+            );
+            return Ok(None);
+        }
+        Some(root) if root != checkpoint.root => anyhow::bail!(
+            "log root at size {} differs from our own view: split view?",
+            checkpoint.size
+        ),
+        Some(_) => {}
+    }
+
+    if let Some(previous) = previous {
+        let old = Checkpoint::parse(&previous.text)
+            .ok_or_else(|| anyhow::anyhow!("our saved checkpoint is corrupt"))?;
+        check_consistent(client, &conf.log, &old, &checkpoint).await?;
+        if old.size == checkpoint.size {
+            return Ok(None);
+        }
+    } else {
+        log::info!(
+            "witnessing {:?} for the first time at size {}",
+            checkpoint.origin,
+            checkpoint.size
+        );
+    }
+
+    // keep only the log's signature and ours
+    note.signatures
+        .retain(|sig| conf.log_key.verify(&note.text, sig));
+    note.add_cosignature(&conf.signer, chrono::Utc::now().timestamp() as u64);
+    Ok(Some(note))
+}
+
+/// Poll a mirror's tlog checkpoints and cosign the consistent ones
+pub async fn witness(conf: WitnessConf, witnessed: Witnessed) -> anyhow::Result<&'static str> {
+    let client = Client::builder()
+        .user_agent(UA)
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    log::info!(
+        "witnessing {} with key {}",
+        conf.log,
+        conf.signer.cosigner().vkey()
+    );
+    loop {
+        let previous = witnessed.read().unwrap().clone();
+        match witness_once(&client, &conf, previous.as_ref()).await {
+            Ok(Some(note)) => {
+                if let Some(ref path) = conf.state {
+                    tokio::fs::write(path, note.to_string()).await?;
+                }
+                log::debug!("cosigned checkpoint:\n{}", note.text);
+                metrics::WITNESS_COSIGNED.inc();
+                *witnessed.write().unwrap() = Some(note);
+            }
+            Ok(None) => log::trace!("no new checkpoint"),
+            Err(e) => {
+                metrics::WITNESS_REFUSED.inc();
+                log::warn!("not cosigning: {e}");
+            }
+        }
+        tokio::time::sleep(conf.interval).await;
+    }
+}
+
+#[handler]
+fn hello() -> String {
+    format!(
+        r#"{}
+
+This is an Allegedly witness. It follows a PLC mirror's transparency log, checks
+each new checkpoint against its own view of PLC history and the last checkpoint
+it cosigned, and cosigns it.
+
+    - GET  /checkpoint  The latest cosigned checkpoint
+    - GET  /_metrics    Prometheus metrics
+
+    https://tangled.org/@microcosm.blue/Allegedly
+"#,
+        logo("witness")
+    )
+}
+
+#[handler]
+fn cosigned(Data(witnessed): Data<&Witnessed>) -> poem::Result<String> {
+    witnessed
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|note| note.to_string())
+        .ok_or_else(|| Error::from_string("nothing witnessed yet", StatusCode::NOT_FOUND))
+}
+
+#[handler]
+fn prometheus_metrics() -> String {
+    metrics::render()
+}
+
+/// Serve the latest cosigned checkpoint
+pub async fn serve_witness(bind: SocketAddr, witnessed: Witnessed) -> anyhow::Result<&'static str> {
+    let app = Route::new()
+        .at("/", get(hello))
+        .at("/checkpoint", get(cosigned))
+        .at("/_metrics", get(prometheus_metrics))
+        .with(AddData::new(witnessed))
+        .with(CatchPanic::new())
+        .with(Tracing);
+    Server::new(TcpListener::bind(bind)).run(app).await?;
+    Ok("serve_witness (unexpected)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Op, Tlog};
+    use poem::listener::{Acceptor, Listener};
+
+    fn op(did: &str) -> Op {
+        serde_json::from_value(serde_json::json!({
+            "did": did,
+            "cid": "cid",
+            "createdAt": "2025-01-01T00:00:00Z",
+            "nullified": false,
+            "operation": {},
+        }))
+        .unwrap()
+    }
+
+    #[handler]
+    fn checkpoint(Data(note): Data<&String>) -> String {
+        note.clone()
+    }
+
+    /// a mirror serving a signed checkpoint over these ops
+    async fn serve_log(signer: &NoteSigner, ops: &[Op]) -> Url {
+        let mut tlog = Tlog::open("log".into(), None).unwrap();
+        tlog.append(ops).unwrap();
+        let note = Note::sign(tlog.checkpoint().body(), signer).to_string();
+        let app = Route::new()
+            .at("/tlog/checkpoint", get(checkpoint))
+            .data(note);
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        tokio::task::spawn(Server::new_with_acceptor(acceptor).run(app));
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    fn witness_conf(log: Url, log_key: &NoteSigner, ops: &[Op]) -> WitnessConf {
+        let mut tlog = Tlog::open("ours".into(), None).unwrap();
+        tlog.append(ops).unwrap();
+        WitnessConf {
+            log,
+            log_key: log_key.verifier(),
+            signer: NoteSigner::generate("witness").unwrap(),
+            tlog: Arc::new(RwLock::new(tlog)),
+            state: None,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_witness_checks_own_view() {
+        let client = Client::new();
+        let log_key = NoteSigner::generate("log").unwrap();
+        let log = serve_log(&log_key, &[op("did:a"), op("did:b")]).await;
+
+        // same history: cosigned
+        let conf = witness_conf(
+            log.clone(),
+            &log_key,
+            &[op("did:a"), op("did:b"), op("did:c")],
+        );
+        let note = witness_once(&client, &conf, None).await.unwrap().unwrap();
+        assert!(note.verified_by(&log_key.verifier()));
+        assert!(note.verified_by(&conf.signer.cosigner()));
+        assert_eq!(note.signatures.len(), 2);
+
+        // we haven't seen that much yet
+        let conf = witness_conf(log.clone(), &log_key, &[op("did:a")]);
+        assert!(witness_once(&client, &conf, None).await.unwrap().is_none());
+
+        // a different history, even on first sight
+        let conf = witness_conf(log, &log_key, &[op("did:a"), op("did:x")]);
+        let err = witness_once(&client, &conf, None).await.unwrap_err();
+        assert!(err.to_string().contains("split view"), "{err}");
+    }
+}
@@ -0,0 +1,73 @@
+//! Dead letters are process-wide, so these get their own test binary
+
+use allegedly::{
+    BundleError, DeadLetterSink, Dt, FolderSource, GetPageError, HttpClient, Week, get_page,
+    set_dead_letters, week_to_pages,
+};
+use async_compression::tokio::write::GzipEncoder;
+use poem::{
+    Route, Server, get, handler,
+    listener::{Acceptor, Listener, TcpListener},
+};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+const GOOD: &str = r#"{"did":"did:plc:a","cid":"cid","createdAt":"2025-01-01T00:00:00Z","nullified":false,"operation":{}}"#;
+
+fn lines(bad: &str) -> String {
+    format!("{GOOD}\n{bad}\n{GOOD}\n")
+}
+
+#[handler]
+fn export() -> String {
+    lines("{\"from\":\"export\"")
+}
+
+#[tokio::test]
+async fn test_strict_dead_letters() {
+    let dir = std::env::temp_dir().join(format!("dead-letters-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let sink = dir.join("dead.jsonl");
+    set_dead_letters(Some(DeadLetterSink::File(sink.clone())), true)
+        .await
+        .unwrap();
+
+    // from an export page
+    let acceptor = TcpListener::bind("127.0.0.1:0")
+        .into_acceptor()
+        .await
+        .unwrap();
+    let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+    let server = tokio::task::spawn(
+        Server::new_with_acceptor(acceptor).run(Route::new().at("/export", get(export))),
+    );
+    let url = format!("http://{addr}/export").parse().unwrap();
+    let res = get_page(&HttpClient::default(), url).await;
+    assert!(matches!(res, Err(GetPageError::Rejected(_))), "{res:?}");
+    server.abort();
+
+    // from a bundle
+    let at: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+    let week = Week::from(at);
+    let path = dir.join(format!("{}.jsonl.gz", Dt::from(week).timestamp()));
+    let mut gz = GzipEncoder::new(tokio::fs::File::create(&path).await.unwrap());
+    gz.write_all(lines("{\"from\":\"bundle\"").as_bytes())
+        .await
+        .unwrap();
+    gz.shutdown().await.unwrap();
+    let (tx, _rx) = mpsc::channel(4);
+    let res = week_to_pages(FolderSource(dir.clone()), week, tx).await;
+    assert!(matches!(res, Err(BundleError::Rejected(_))), "{res:?}");
+
+    // both were recorded before erroring
+    let raws: Vec<String> = std::fs::read_to_string(&sink)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["raw"].to_string())
+        .collect();
+    assert_eq!(
+        raws,
+        vec![r#""{\"from\":\"export\"""#, r#""{\"from\":\"bundle\"""#]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,628 @@
+use allegedly::{
+    CatchUpTarget, CaughtUpReason, ClientConf, Dt, ExportPage, Fixture, FixtureFaults, HttpClient,
+    Op, OpFilter, OpSource, PageParsing, PollError, catch_up_stream, catch_up_upstream, caught_up,
+    filter_pages, poll_upstream, source_to_pages, spawn_fixture, take_ops,
+};
+use futures::TryStreamExt;
+use serde_json::json;
+use std::{collections::HashSet, time::Duration};
+use tokio::sync::{mpsc, oneshot};
+
+const OPS: usize = 2500;
+
+/// ops in threes with the same timestamp, so ties land on page boundaries
+fn ops() -> Vec<Op> {
+    let base: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+    (0..OPS)
+        .map(|i| {
+            let created_at = base + chrono::Duration::seconds(i as i64 / 3);
+            serde_json::from_value(json!({
+                "did": format!("did:plc:fixture{i:04}"),
+                "cid": format!("cid{i:04}"),
+                "createdAt": created_at,
+                "nullified": false,
+                "operation": {
+                    "type": "plc_operation",
+                    "rotationKeys": ["did:key:zQ3shrotation"],
+                    "verificationMethods": {"atproto": "did:key:zQ3shsigning"},
+                    "alsoKnownAs": [format!("at://fixture{i}.test")],
+                    "services": {"atproto_pds": {"type": "AtprotoPersonalDataServer", "endpoint": "https://pds.example.com"}},
+                    "prev": null,
+                    "sig": "sig",
+                },
+            }))
+            .unwrap()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_poll_faulty_fixture() {
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        rate_limit_every: 4,
+        retry_after: Duration::ZERO,
+        truncate_every: 2,
+        ..Default::default()
+    };
+    let (url, server) = spawn_fixture(Fixture::new(ops(), faults)).await.unwrap();
+
+    let (tx, mut rx) = mpsc::channel(4);
+    // retry quickly, so the injected 429s don't slow the test down
+    let client = ClientConf {
+        retry_bounds: (Duration::from_millis(10), Duration::from_millis(100)),
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+    let poller = tokio::task::spawn(poll_upstream(
+        client,
+        None,
+        url.join("export").unwrap(),
+        Duration::from_millis(1),
+        PageParsing::Inline,
+        tx,
+    ));
+
+    let mut seen = vec![];
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while seen.len() < OPS {
+            let page = rx.recv().await.expect("poller to keep going");
+            seen.extend(page.ops.into_iter().map(|op| (op.created_at, op.cid)));
+        }
+    })
+    .await
+    .expect("to receive every op");
+    poller.abort();
+    server.abort();
+
+    let unique: HashSet<_> = seen.iter().map(|(_, cid)| cid).collect();
+    assert_eq!(unique.len(), seen.len(), "no duplicate ops");
+    assert!(seen.is_sorted(), "ops in order");
+}
+
+#[tokio::test]
+async fn test_sliced_catch_up_faulty_fixture() {
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        rate_limit_every: 5,
+        retry_after: Duration::ZERO,
+        truncate_every: 3,
+        ..Default::default()
+    };
+    let all = ops();
+    let after = all[0].created_at - chrono::Duration::seconds(1);
+    let until = all[OPS - 1].created_at + chrono::Duration::seconds(1);
+    let (url, server) = spawn_fixture(Fixture::new(all, faults)).await.unwrap();
+
+    let pages = catch_up_stream(
+        HttpClient::default(),
+        url.join("export").unwrap(),
+        after,
+        until,
+        4,
+        Duration::from_millis(1),
+        PageParsing::Parallel { batch: 100 },
+    );
+    let pages: Vec<_> = tokio::time::timeout(Duration::from_secs(60), pages.try_collect())
+        .await
+        .expect("catch-up to finish")
+        .unwrap();
+    server.abort();
+
+    let sizes: Vec<_> = pages.iter().map(|p| p.ops.len()).collect();
+    assert_eq!(sizes, vec![1000, 1000, 500], "re-paged to full pages");
+    let seen: Vec<_> = pages
+        .into_iter()
+        .flat_map(|p| p.ops)
+        .map(|op| (op.created_at, op.cid))
+        .collect();
+    let unique: HashSet<_> = seen.iter().map(|(_, cid)| cid).collect();
+    assert_eq!(unique.len(), OPS, "every op, once");
+    assert!(seen.is_sorted(), "ops in order");
+}
+
+#[tokio::test]
+async fn test_sliced_catch_up_stops_at_past_until() {
+    let all = ops();
+    let after = all[0].created_at - chrono::Duration::seconds(1);
+    let until = all[1200].created_at;
+    let (url, server) = spawn_fixture(Fixture::new(all, FixtureFaults::default()))
+        .await
+        .unwrap();
+    let export = url.join("export").unwrap();
+
+    let client = HttpClient::default();
+    let (poll_tx, poll_rx) = mpsc::channel(4);
+    let (tx, mut rx) = mpsc::channel(4);
+    let target = CatchUpTarget {
+        after: Some(after),
+        until: Some(until),
+        ..CatchUpTarget::new(client.clone(), export.clone())
+    };
+    let catching_up = tokio::task::spawn(catch_up_upstream(
+        client,
+        after,
+        Some(until),
+        export,
+        4,
+        Duration::from_millis(1),
+        PageParsing::Inline,
+        poll_tx,
+    ));
+    let (notify, caught) = oneshot::channel();
+    let stopper = tokio::task::spawn(caught_up(poll_rx, tx, target, Some(notify)));
+
+    let mut seen = vec![];
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while let Some(page) = rx.recv().await {
+            seen.extend(page.ops);
+        }
+    })
+    .await
+    .expect("to stop at until");
+    server.abort();
+
+    // no polling on past `until`
+    assert_eq!(
+        catching_up.await.unwrap().unwrap(),
+        "catch_up_upstream (until reached)"
+    );
+    stopper.await.unwrap().unwrap();
+    assert_eq!(caught.await.unwrap().reason, CaughtUpReason::Until);
+    assert_eq!(seen.len(), 1200);
+    assert!(seen.iter().all(|op| op.created_at < until));
+}
+
+#[tokio::test]
+async fn test_stuck_slice_errors() {
+    // more ops at one timestamp than fit in a page, from an upstream that
+    // repeats ops at `after`: the slice can't get past them
+    let base: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+    let mut all = ops();
+    for op in &mut all[..1200] {
+        op.created_at = base;
+    }
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        ..Default::default()
+    };
+    let (url, server) = spawn_fixture(Fixture::new(all, faults)).await.unwrap();
+
+    let pages = catch_up_stream(
+        HttpClient::default(),
+        url.join("export").unwrap(),
+        base - chrono::Duration::seconds(1),
+        base + chrono::Duration::seconds(1),
+        1,
+        Duration::from_millis(1),
+        PageParsing::Inline,
+    );
+    let res: Result<Vec<_>, _> = tokio::time::timeout(Duration::from_secs(60), pages.try_collect())
+        .await
+        .expect("catch-up to give up");
+    server.abort();
+    assert!(
+        matches!(res, Err(PollError::SliceStuck { at }) if at == base),
+        "{res:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_stuck_export_diff_source_errors() {
+    // same as a stuck slice: the diff can't trust anything after this
+    let base: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+    let mut all = ops();
+    for op in &mut all[..1200] {
+        op.created_at = base;
+    }
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        ..Default::default()
+    };
+    let (url, server) = spawn_fixture(Fixture::new(all, faults)).await.unwrap();
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let draining = tokio::task::spawn(async move { while rx.recv().await.is_some() {} });
+    let source = OpSource::Export(url.join("export").unwrap(), HttpClient::default());
+    let res = tokio::time::timeout(
+        Duration::from_secs(60),
+        source_to_pages(
+            source,
+            base - chrono::Duration::seconds(1),
+            base + chrono::Duration::days(1),
+            Duration::from_millis(1),
+            tx,
+        ),
+    )
+    .await
+    .expect("the export to give up");
+    server.abort();
+    draining.await.unwrap();
+    assert!(res.is_err(), "{res:?}");
+}
+
+#[tokio::test]
+async fn test_caught_up_at_until_and_head() {
+    let all = ops();
+    let start = all[0].created_at;
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        ..Default::default()
+    };
+    let (url, server) = spawn_fixture(Fixture::new(all, faults)).await.unwrap();
+    let export = url.join("export").unwrap();
+
+    let until = start + chrono::Duration::seconds(100);
+    for (until, expected_ops, expected_reason) in [
+        (Some(until), 300, CaughtUpReason::Until),
+        (None, OPS, CaughtUpReason::Head),
+    ] {
+        let client = HttpClient::default();
+        let (poll_tx, poll_rx) = mpsc::channel(4);
+        let (tx, mut rx) = mpsc::channel(4);
+        let (notify, caught) = oneshot::channel();
+        let target = CatchUpTarget {
+            until,
+            idle: Duration::from_millis(200),
+            ..CatchUpTarget::new(client.clone(), export.clone())
+        };
+        let poller = tokio::task::spawn(poll_upstream(
+            client,
+            None,
+            export.clone(),
+            Duration::from_millis(1),
+            PageParsing::Inline,
+            poll_tx,
+        ));
+        let detector = tokio::task::spawn(caught_up(poll_rx, tx, target, Some(notify)));
+
+        let mut n = 0;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            while let Some(page) = rx.recv().await {
+                n += page.ops.len();
+            }
+        })
+        .await
+        .expect("to catch up");
+        let caught = caught.await.unwrap();
+        assert_eq!(n, expected_ops);
+        assert_eq!(caught.reason, expected_reason);
+        detector.await.unwrap().unwrap();
+        // the poller stops quietly once the detector is done with it
+        poller.await.unwrap().unwrap();
+    }
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_caught_up_when_already_current() {
+    // like a backfill whose bundles already reach upstream's head: the poller
+    // skips empty pages, so nothing ever arrives
+    let all = ops();
+    let last = all[OPS - 1].created_at;
+    let (url, server) = spawn_fixture(Fixture::new(all, FixtureFaults::default()))
+        .await
+        .unwrap();
+    let export = url.join("export").unwrap();
+
+    let client = HttpClient::default();
+    let (poll_tx, poll_rx) = mpsc::channel(4);
+    let (tx, mut rx) = mpsc::channel(4);
+    let (notify, caught) = oneshot::channel();
+    let target = CatchUpTarget {
+        after: Some(last),
+        idle: Duration::from_millis(200),
+        ..CatchUpTarget::new(client.clone(), export.clone())
+    };
+    let poller = tokio::task::spawn(poll_upstream(
+        client,
+        Some(last),
+        export,
+        Duration::from_millis(50),
+        PageParsing::Inline,
+        poll_tx,
+    ));
+    let detector = tokio::task::spawn(caught_up(poll_rx, tx, target, Some(notify)));
+
+    let page = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .expect("to catch up without any pages");
+    assert!(page.is_none());
+    let caught = caught.await.unwrap();
+    assert_eq!(caught.reason, CaughtUpReason::Head);
+    assert_eq!(caught.last_at, Some(last));
+    detector.await.unwrap().unwrap();
+    poller.await.unwrap().unwrap();
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_caught_up_stops_when_destination_done() {
+    // a poller with nothing new for a while, like `tail --count` at the head
+    let (poll_tx, poll_rx) = mpsc::channel(1);
+    let (tx, rx) = mpsc::channel(1);
+    let target = CatchUpTarget {
+        stop_at_head: false,
+        ..CatchUpTarget::new(
+            HttpClient::default(),
+            "http://127.0.0.1:9/export".parse().unwrap(),
+        )
+    };
+    let catching_up = tokio::task::spawn(caught_up(poll_rx, tx, target, None));
+    let (counted_tx, mut counted_rx) = mpsc::channel(1);
+    let counting = tokio::task::spawn(take_ops(rx, counted_tx, 2));
+
+    poll_tx
+        .send(ExportPage {
+            ops: ops()[..3].to_vec(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(counted_rx.recv().await.unwrap().ops.len(), 2);
+    counting.await.unwrap().unwrap();
+    let res = tokio::time::timeout(Duration::from_secs(5), catching_up)
+        .await
+        .expect("caught_up to stop once nobody is listening");
+    assert_eq!(res.unwrap().unwrap(), "caught_up (destination done)");
+    drop(poll_tx);
+}
+
+#[tokio::test]
+async fn test_count_counts_filtered_ops() {
+    let all = ops();
+    let after = all[0].created_at - chrono::Duration::seconds(1);
+    // one in a hundred ops matches
+    let dids: HashSet<String> = all.iter().step_by(100).map(|op| op.did.clone()).collect();
+    let (url, server) = spawn_fixture(Fixture::new(all, FixtureFaults::default()))
+        .await
+        .unwrap();
+    let export = url.join("export").unwrap();
+
+    let client = HttpClient::default();
+    let (poll_tx, poll_rx) = mpsc::channel(4);
+    let (tx, caught_up_rx) = mpsc::channel(4);
+    let (filter_tx, filter_rx) = mpsc::channel(4);
+    let (count_tx, mut rx) = mpsc::channel(4);
+    let target = CatchUpTarget {
+        after: Some(after),
+        ..CatchUpTarget::new(client.clone(), export.clone())
+    };
+    let filter = OpFilter {
+        dids: Some(dids.clone()),
+        ..Default::default()
+    };
+    tokio::task::spawn(poll_upstream(
+        client,
+        Some(after),
+        export,
+        Duration::from_millis(1),
+        PageParsing::Inline,
+        poll_tx,
+    ));
+    tokio::task::spawn(caught_up(poll_rx, tx, target, None));
+    tokio::task::spawn(filter_pages(caught_up_rx, filter_tx, filter));
+    let counter = tokio::task::spawn(take_ops(filter_rx, count_tx, 10));
+
+    let mut seen = Vec::new();
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while let Some(page) = rx.recv().await {
+            seen.extend(page.ops);
+        }
+    })
+    .await
+    .expect("to stop after the count");
+    counter.await.unwrap().unwrap();
+    server.abort();
+    assert_eq!(seen.len(), 10);
+    assert!(seen.iter().all(|op| dids.contains(&op.did)));
+}
+
+#[tokio::test]
+async fn test_bounded_tail_slice() {
+    let all = ops();
+    let start = all[0].created_at;
+    let faults = FixtureFaults {
+        duplicate_boundaries: true,
+        ..Default::default()
+    };
+    let (url, server) = spawn_fixture(Fixture::new(all, faults)).await.unwrap();
+    let export = url.join("export").unwrap();
+
+    let after = start + chrono::Duration::seconds(10);
+    let until = start + chrono::Duration::seconds(100);
+    // ops at seconds 11 through 99, three each
+    for (count, expected_ops) in [(None, 267), (Some(50), 50)] {
+        let client = HttpClient::default();
+        let (poll_tx, poll_rx) = mpsc::channel(4);
+        let (tx, caught_up_rx) = mpsc::channel(4);
+        let (count_tx, mut rx) = mpsc::channel(4);
+        let target = CatchUpTarget {
+            after: Some(after),
+            until: Some(until),
+            stop_at_head: false,
+            ..CatchUpTarget::new(client.clone(), export.clone())
+        };
+        let poller = tokio::task::spawn(poll_upstream(
+            client,
+            Some(after),
+            export.clone(),
+            Duration::from_millis(1),
+            PageParsing::Inline,
+            poll_tx,
+        ));
+        let detector = tokio::task::spawn(caught_up(poll_rx, tx, target, None));
+        let counter = tokio::task::spawn(take_ops(
+            caught_up_rx,
+            count_tx,
+            count.unwrap_or(usize::MAX),
+        ));
+
+        let mut seen = Vec::new();
+        tokio::time::timeout(Duration::from_secs(60), async {
+            while let Some(page) = rx.recv().await {
+                seen.extend(page.ops);
+            }
+        })
+        .await
+        .expect("to finish the slice");
+        assert_eq!(seen.len(), expected_ops);
+        let unique: HashSet<_> = seen.iter().map(|op| op.cid.clone()).collect();
+        assert_eq!(unique.len(), expected_ops);
+        assert!(
+            seen.iter()
+                .all(|op| op.created_at > after && op.created_at < until)
+        );
+        counter.await.unwrap().unwrap();
+        detector.await.unwrap().unwrap();
+        poller.await.unwrap().unwrap();
+    }
+    server.abort();
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_webhook_deliveries() {
+    use allegedly::{OpFilter, WebhookConf, pages_to_webhooks, read_cursor, sign};
+    use poem::{
+        EndpointExt, Route, Server, handler,
+        listener::{Acceptor, Listener, TcpListener},
+        post,
+        web::Data,
+    };
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    #[handler]
+    fn receive(req: &poem::Request, body: Vec<u8>, Data(received): Data<&Received>) {
+        let signature = req
+            .header(allegedly::SIGNATURE_HEADER)
+            .unwrap_or_default()
+            .to_string();
+        received.lock().unwrap().push((signature, body));
+    }
+
+    let all = ops();
+    let last_at = all[OPS - 1].created_at;
+    let (url, server) = spawn_fixture(Fixture::new(all, FixtureFaults::default()))
+        .await
+        .unwrap();
+
+    let received = Received::default();
+    let hook = TcpListener::bind("127.0.0.1:0")
+        .into_acceptor()
+        .await
+        .unwrap();
+    let addr = hook.local_addr()[0].as_socket_addr().cloned().unwrap();
+    let hook_server = tokio::task::spawn(
+        Server::new_with_acceptor(hook).run(
+            Route::new()
+                .at("/hook", post(receive))
+                .data(received.clone()),
+        ),
+    );
+
+    let cursor = std::env::temp_dir().join(format!("webhook-test-{}", std::process::id()));
+    let _ = std::fs::remove_file(&cursor);
+    let handles = ["fixture5.test", "fixture1500.test", "fixture2499.test"];
+    let filter = OpFilter {
+        handles: handles.iter().map(|h| h.to_string()).collect(),
+        ..Default::default()
+    };
+    let conf = WebhookConf {
+        urls: vec![format!("http://{addr}/hook").parse().unwrap()],
+        secret: Some("hush".to_string()),
+        batch_size: 2,
+        batch_wait: Duration::from_millis(100),
+        cursor: Some(cursor.clone()),
+    };
+    let client = HttpClient::default();
+    let (tx, rx) = mpsc::channel(4);
+    let poller = tokio::task::spawn(poll_upstream(
+        client.clone(),
+        None,
+        url.join("export").unwrap(),
+        Duration::from_millis(1),
+        PageParsing::Inline,
+        tx,
+    ));
+    let sink = tokio::task::spawn(pages_to_webhooks(rx, client, conf, Some(filter)));
+
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while read_cursor(&cursor).unwrap() != Some(last_at) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("to deliver everything and save the cursor");
+    poller.abort();
+    sink.abort();
+    hook_server.abort();
+    server.abort();
+    std::fs::remove_file(&cursor).unwrap();
+
+    let received = received.lock().unwrap();
+    let mut delivered = vec![];
+    for (signature, body) in received.iter() {
+        assert_eq!(signature, &sign("hush", body));
+        let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+        let ops = body["ops"].as_array().unwrap();
+        assert!(ops.len() <= 2);
+        delivered.extend(ops.iter().map(|op| op["did"].as_str().unwrap().to_string()));
+    }
+    assert_eq!(
+        delivered,
+        [
+            "did:plc:fixture0005",
+            "did:plc:fixture1500",
+            "did:plc:fixture2499"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_fixture_resolve_and_health() {
+    let (url, server) = spawn_fixture(Fixture::new(ops(), FixtureFaults::default()))
+        .await
+        .unwrap();
+
+    let client = HttpClient::default();
+    let doc: serde_json::Value = client
+        .get(format!("{url}did:plc:fixture0042").parse().unwrap())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(doc["alsoKnownAs"], json!(["at://fixture42.test"]));
+
+    let missing = client
+        .get(format!("{url}did:plc:nope").parse().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), 404);
+
+    let health = client
+        .get(url.join("_health").unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert!(health.status().is_success());
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_caught_up_source_closed() {
+    let (poll_tx, poll_rx) = mpsc::channel(1);
+    let (tx, _rx) = mpsc::channel(1);
+    let upstream = "http://127.0.0.1:1/export".parse().unwrap();
+    let target = CatchUpTarget::new(HttpClient::default(), upstream);
+    drop(poll_tx);
+    let res = caught_up(poll_rx, tx, target, None).await;
+    assert!(matches!(res, Err(PollError::SourceClosed)), "{res:?}");
+}
@@ -0,0 +1,122 @@
+//! Tests against a real postgres, if `ALLEGEDLY_TEST_PG` has a uri for one
+//!
+//! The database's tables are dropped and recreated, so point this at a
+//! scratch database, and run with `--test-threads=1` since they share it.
+
+use allegedly::{CaughtUp, CaughtUpReason, Db, Dt, ExportPage, Op, Tlog, pages_to_pg, tlog_sync};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// A fresh did-method-plc schema, or None to skip
+async fn scratch_db() -> Option<Db> {
+    let Ok(uri) = std::env::var("ALLEGEDLY_TEST_PG") else {
+        eprintln!("ALLEGEDLY_TEST_PG not set, skipping");
+        return None;
+    };
+    let (client, connection) = tokio_postgres::connect(&uri, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    let task = tokio::task::spawn(connection);
+    client
+        .batch_execute(
+            r#"DROP TABLE IF EXISTS kysely_migration, operations, dids, allegedly_ingest;
+               CREATE TABLE kysely_migration (name varchar PRIMARY KEY, timestamp varchar);
+               INSERT INTO kysely_migration (name) VALUES
+                   ('_20221020T204908820Z'), ('_20230223T215019669Z'),
+                   ('_20230406T174552885Z'), ('_20231128T203323431Z');
+               CREATE TABLE operations (
+                   did varchar NOT NULL,
+                   operation jsonb NOT NULL,
+                   cid varchar NOT NULL,
+                   nullified boolean NOT NULL,
+                   "createdAt" timestamptz NOT NULL,
+                   PRIMARY KEY (did, cid)
+               );
+               CREATE TABLE dids (did varchar PRIMARY KEY);"#,
+        )
+        .await
+        .unwrap();
+    drop(client);
+    task.await.unwrap().unwrap();
+    Some(Db::new(&uri, None).await.unwrap())
+}
+
+fn op(did: &str, at: &str) -> Op {
+    serde_json::from_value(serde_json::json!({
+        "did": did,
+        "cid": "cid",
+        "createdAt": at,
+        "nullified": false,
+        "operation": {},
+    }))
+    .unwrap()
+}
+
+async fn ingest(db: &Db, ops: Vec<Op>) {
+    let (tx, rx) = mpsc::channel(1);
+    tx.send(ExportPage { ops }).await.unwrap();
+    drop(tx);
+    pages_to_pg(db.clone(), rx, None).await.unwrap();
+}
+
+async fn wait_for_size(tlog: &Arc<RwLock<Tlog>>, size: u64) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while tlog.read().unwrap().size() < size {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("tlog to catch up");
+}
+
+#[tokio::test]
+async fn test_tlog_sync_picks_up_late_older_ops() {
+    let Some(db) = scratch_db().await else {
+        return;
+    };
+    ingest(&db, vec![op("did:plc:a", "2025-01-01T00:00:10Z")]).await;
+
+    let tlog = Arc::new(RwLock::new(Tlog::open("test".into(), None).unwrap()));
+    let sync = tokio::task::spawn(tlog_sync(
+        tlog.clone(),
+        db.clone(),
+        Duration::from_millis(10),
+    ));
+    wait_for_size(&tlog, 1).await;
+
+    // well before anything already logged
+    let late = op("did:plc:b", "2025-01-01T00:00:00Z");
+    ingest(&db, vec![late]).await;
+    wait_for_size(&tlog, 2).await;
+    sync.abort();
+
+    let tlog = tlog.read().unwrap();
+    assert!(tlog.prove_inclusion("did:plc:b", "cid", 2).is_some());
+    let last_at: Dt = "2025-01-01T00:00:10Z".parse().unwrap();
+    assert_eq!(tlog.last_at(), Some(last_at));
+}
+
+#[tokio::test]
+async fn test_pages_to_pg_hears_caught_up() {
+    let Some(db) = scratch_db().await else {
+        return;
+    };
+    let at: Dt = "2025-01-01T00:00:00Z".parse().unwrap();
+    let (tx, rx) = mpsc::channel(1);
+    let (notify, caught) = oneshot::channel();
+    let sink = tokio::task::spawn(pages_to_pg(db, rx, Some(caught)));
+    tx.send(ExportPage {
+        ops: vec![op("did:plc:a", "2025-01-01T00:00:00Z")],
+    })
+    .await
+    .unwrap();
+    notify
+        .send(CaughtUp {
+            last_at: Some(at),
+            reason: CaughtUpReason::Head,
+        })
+        .unwrap();
+    drop(tx);
+    assert_eq!(sink.await.unwrap().unwrap(), "pages_to_pg (caught up)");
+}